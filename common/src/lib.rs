@@ -1,10 +1,75 @@
-use std::time::Duration;
+use std::{collections::BTreeMap, fmt, str::FromStr, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
 /// The maximum allowed length of a title, in UTF-16 code units
 pub const MAX_TITLE_UTF16_CODEUNITS: usize = 300;
 
+/// The audio formats that articles can be stored in
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum AudioCodec {
+    /// MP3. Every browser and podcast app can play it
+    #[default]
+    Mp3,
+    /// Opus in an Ogg container. About half the size of MP3 for the same quality of speech
+    OggOpus,
+}
+
+impl AudioCodec {
+    /// All the supported codecs
+    pub const ALL: [AudioCodec; 2] = [AudioCodec::Mp3, AudioCodec::OggOpus];
+
+    /// The file extension of audio files with this codec
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioCodec::Mp3 => "mp3",
+            AudioCodec::OggOpus => "ogg",
+        }
+    }
+
+    /// The MIME type of audio files with this codec
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            AudioCodec::Mp3 => "audio/mpeg",
+            AudioCodec::OggOpus => "audio/ogg",
+        }
+    }
+
+    /// Returns the codec whose files have the given extension, if any
+    pub fn from_extension(ext: &str) -> Option<AudioCodec> {
+        AudioCodec::ALL.into_iter().find(|c| c.extension() == ext)
+    }
+
+    /// Returns the codec with the given MIME type, if any. This also accepts the nonstandard
+    /// `audio/mp3`, which older versions of the frontend used.
+    pub fn from_mime_type(mime: &str) -> Option<AudioCodec> {
+        if mime == "audio/mp3" {
+            return Some(AudioCodec::Mp3);
+        }
+        AudioCodec::ALL.into_iter().find(|c| c.mime_type() == mime)
+    }
+}
+
+impl fmt::Display for AudioCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AudioCodec::Mp3 => "mp3",
+            AudioCodec::OggOpus => "ogg-opus",
+        })
+    }
+}
+
+impl FromStr for AudioCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        AudioCodec::ALL
+            .into_iter()
+            .find(|c| c.to_string() == s)
+            .ok_or_else(|| format!("unknown audio codec '{s}'. Options are mp3, ogg-opus"))
+    }
+}
+
 /// Contains all the metadata about an article
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct ArticleMetadata {
@@ -18,25 +83,246 @@ pub struct ArticleMetadata {
     pub datetime_added: Option<u64>,
     /// The URL this article was sourced from, if any
     pub source_url: Option<String>,
+    /// The ISO 639-3 code of the language the article was read in, e.g., "nld"
+    #[serde(default)]
+    pub lang: Option<String>,
+    /// How confident the language detector was in `lang`, from 0 to 1. This is `None` if the
+    /// language was given in the submission, rather than detected
+    #[serde(default)]
+    pub lang_confidence: Option<f64>,
+    /// The format of the article's audio. The audio file is named `ID.EXT`, where `EXT` is the
+    /// codec's extension
+    #[serde(default)]
+    pub codec: AudioCodec,
+    /// The series the article is part of, if any, like the book it's a chapter of
+    #[serde(default)]
+    pub series: Option<ArticleSeries>,
+}
+
+/// Where an article falls in a series of articles, like a chapter in a book
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArticleSeries {
+    /// The title of the series
+    pub title: String,
+    /// The article's place in the series, starting from 1
+    pub index: u32,
+    /// The number of articles in the series
+    pub len: u32,
 }
 
 /// A library catalog is a list of article metadata
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LibraryCatalog(pub Vec<ArticleMetadata>);
 
+/// Marks the point in an article's audio where a sentence starts
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SentenceTimepoint {
+    /// The offset from the start of the audio, in seconds
+    pub time: f64,
+    /// The text of the sentence
+    pub text: String,
+}
+
+/// An alignment maps an article's text to its audio. It is the list of the article's sentences and
+/// when they start, in order.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ArticleAlignment(pub Vec<SentenceTimepoint>);
+
+/// Marks the point in an article's audio where a section starts
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SectionTimepoint {
+    /// The offset from the start of the audio, in seconds
+    pub time: f64,
+    /// The heading of the section
+    pub heading: String,
+}
+
+/// The sections of an article are its headings and when they start, in order. This is for jumping
+/// around in the article's audio.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ArticleSections(pub Vec<SectionTimepoint>);
+
+/// The progress of an article that the server is in the middle of synthesizing
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ArticleProgress {
+    pub id: String,
+    pub title: String,
+    /// The number of chunks of the article that have been synthesized
+    pub chunks_done: usize,
+    /// The number of chunks the article was broken into. This is 0 until the article is broken up
+    pub chunks_total: usize,
+}
+
+/// The quality of a reading voice
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VoiceQuality {
+    /// The standard quality
+    Standard,
+    /// Represents Neural2 or Wavenet if the Neural2 version of whatever the client wants is not
+    /// available
+    High,
+}
+
+/// Voices are only classified as high or low pitch
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum VoiceType {
+    #[serde(rename = "high")]
+    HighPitch,
+    #[serde(rename = "low")]
+    LowPitch,
+}
+
+impl VoiceType {
+    /// The other pitch
+    pub fn opposite(self) -> VoiceType {
+        match self {
+            VoiceType::HighPitch => VoiceType::LowPitch,
+            VoiceType::LowPitch => VoiceType::HighPitch,
+        }
+    }
+}
+
+/// The description of a reading voice that the server offers
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VoiceDescription {
+    /// The unique voice identifier. This is what goes in [`VoiceSelection::voice`]
+    pub id: String,
+    /// The ISO 639-3 code of the language this voice speaks, e.g., "por"
+    pub lang: String,
+    /// The English description of this voice. E.g., "Portuguese (Brazil)"
+    pub language: String,
+    /// The quality of this voice
+    pub quality: VoiceQuality,
+    /// The pitch of this voice
+    pub pitch: VoiceType,
+}
+
+/// A voice catalog is a list of the voices the server offers
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VoiceCatalog(pub Vec<VoiceDescription>);
+
+/// The pricing tier of a voice. Google Cloud bills each tier at a different rate per character
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VoiceTier {
+    Standard,
+    Wavenet,
+    Neural2,
+    /// A voice that's synthesized on the server itself, and costs nothing
+    Local,
+}
+
+/// The amount of text synthesized over some period of time
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UsageTotals {
+    /// The number of characters the TTS engine billed for, by voice tier. That's every character
+    /// sent, SSML markup included, except `<mark>` tags
+    pub billed_chars: BTreeMap<VoiceTier, u64>,
+    /// What those characters cost, according to the server's configured prices
+    pub estimated_cost_usd: f64,
+}
+
+/// The server's TTS usage, by day and by month. Days are of the form YYYY-MM-DD, and months are
+/// of the form YYYY-MM. Both are in UTC.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UsageReport {
+    pub days: BTreeMap<String, UsageTotals>,
+    pub months: BTreeMap<String, UsageTotals>,
+}
+
+/// How a lexicon entry changes the text it matches
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum LexiconRule {
+    /// Replace the matched text with `replacement`
+    Plain { replacement: String },
+    /// The pattern is a regular expression. Replace its matches with `replacement`, which can
+    /// refer to capture groups as `$1`, `$name`, etc.
+    Regex { replacement: String },
+    /// Keep the matched text, but read it as `alias`. This is SSML's `<sub>` element
+    Sub { alias: String },
+    /// Keep the matched text, but pronounce it as the phonetic string `ph`, written in `alphabet`
+    /// (e.g., "ipa" or "x-sampa"). This is SSML's `<phoneme>` element
+    Phoneme { alphabet: String, ph: String },
+}
+
+/// The articles a lexicon entry applies to. In JSON, this is `"global"`, `{"lang": "eng"}`, or
+/// `{"domain": "nytimes.com"}`
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LexiconScope {
+    /// Every article
+    #[default]
+    Global,
+    /// Articles in the given language, as an ISO 639-3 code, e.g., "eng"
+    Lang(String),
+    /// Articles from the given domain or any of its subdomains, e.g., "nytimes.com"
+    Domain(String),
+}
+
+/// An entry in the server's pronunciation lexicon. Entries are applied in order of their IDs.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LexiconEntry {
+    /// The ID of the entry. This is assigned by the server, and ignored when adding an entry
+    #[serde(default)]
+    pub id: u64,
+    /// The text to match. Unless the rule is a regex, this only matches whole words
+    pub pattern: String,
+    #[serde(flatten)]
+    pub rule: LexiconRule,
+    /// The articles the entry applies to. This defaults to every article
+    #[serde(default)]
+    pub scope: LexiconScope,
+}
+
+/// The client's choice of narrator for an article. Every field is optional. If `voice` is set,
+/// `quality` and `pitch` are ignored. Otherwise, the server picks a voice in the article's language
+/// with the given quality and pitch, defaulting to high quality and high pitch.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct VoiceSelection {
+    /// The ID of the voice to use, as listed in the [`VoiceCatalog`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voice: Option<String>,
+    /// The desired voice quality
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<VoiceQuality>,
+    /// The desired voice pitch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pitch: Option<VoiceType>,
+    /// Whether to read quotations and block quotes with a second voice, in the same language as
+    /// the narrator's and of the opposite pitch
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dual_voice: bool,
+}
+
 /// The request type for when the client sends the raw text of the article they want converted
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ArticleTextSubmission {
     pub title: String,
     pub body: String,
+    /// The article's author, if known. The server may read this after the title
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// The BCP-47 tag of the article's language, e.g., "nl" or "pt-BR". If this isn't set, the
+    /// server detects the language
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lang: Option<String>,
+    /// The narrator to use
+    #[serde(flatten)]
+    pub voice: VoiceSelection,
 }
 
 impl ArticleTextSubmission {
-    /// Converts this submission into its serialized string form
-    pub fn serialize(&self) -> String {
-        // TODO: Internationalize this to use the correct stop character for the given language
-        // Include the title at the top of the article.
-        format!("{}. {}", self.title, self.body)
+    /// Converts this submission into its serialized string form. The title is a heading at the
+    /// top of the article, so that it's read with a pause after it, and it's followed by the
+    /// `byline`, if any, on a line of its own
+    pub fn serialize(&self, byline: Option<&str>) -> String {
+        let title = self.title.replace(['\r', '\n'], " ");
+        match byline {
+            Some(byline) => format!("# {title}\n{byline}\n{}", self.body),
+            None => format!("# {title}\n{}", self.body),
+        }
     }
 }
 
@@ -44,6 +330,13 @@ impl ArticleTextSubmission {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ArticleUrlSubmission {
     pub url: String,
+    /// The BCP-47 tag of the article's language. If this isn't set, the server detects the
+    /// language
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lang: Option<String>,
+    /// The narrator to use
+    #[serde(flatten)]
+    pub voice: VoiceSelection,
 }
 
 /// The request type for when the client sends the raw HTML of the page along with the URL it came from
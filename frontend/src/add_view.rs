@@ -1,13 +1,20 @@
-use common::{ArticleTextSubmission, ArticleUrlSubmission, MAX_TITLE_UTF16_CODEUNITS};
+use common::{
+    ArticleTextSubmission, ArticleUrlSubmission, VoiceSelection, MAX_TITLE_UTF16_CODEUNITS,
+};
 
 use anyhow::{anyhow, bail, Error as AnyError};
 use gloo_net::http::Request;
-use wasm_bindgen::JsValue;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{File, FormData, HtmlInputElement};
 use yew::{html::Scope, prelude::*};
 
 const URL_FORM_ID: &str = "article-url-input";
 const TITLE_FORM_ID: &str = "article-title-input";
 const BODY_FORM_ID: &str = "article-body-input";
+const FILE_FORM_ID: &str = "article-file-input";
+
+/// The kinds of files the server can make articles from
+const ACCEPTED_FILE_TYPES: &str = ".pdf,.epub,.txt,.md,.markdown,.html,.htm";
 
 /// POSTs the given ArticleTextSubmission to the server for conversion
 async fn submit_article_text(submission: &ArticleTextSubmission) -> Result<(), AnyError> {
@@ -53,6 +60,34 @@ async fn submit_article_url(submission: &ArticleUrlSubmission) -> Result<(), Any
     Ok(())
 }
 
+/// POSTs the given file to the server for extraction and conversion. Returns the number of articles
+/// that were added, which is more than one for a book
+async fn submit_article_file(file: &File) -> Result<usize, AnyError> {
+    tracing::debug!("Adding file {}", file.name());
+    let endpoint = "/api/add-article-by-file";
+    let form = FormData::new().map_err(|e| anyhow!("Couldn't make form: {:?}", e))?;
+    form.append_with_blob_and_filename("file", file, &file.name())
+        .map_err(|e| anyhow!("Couldn't attach file: {:?}", e))?;
+    let resp = Request::post(endpoint)
+        .body(form)?
+        .send()
+        .await
+        .map_err(|e| anyhow!("Error POSTing to {endpoint}: {}", e))?;
+
+    if !resp.ok() {
+        bail!(
+            "Error adding file \"{}\". {}. {}",
+            file.name(),
+            resp.status_text(),
+            resp.text().await.unwrap_or("".to_string())
+        );
+    }
+
+    // The server responds with the IDs of the new articles, one per line
+    let ids = resp.text().await.unwrap_or_default();
+    Ok(ids.lines().count())
+}
+
 /// Retrives the value of the element with the given ID
 fn get_elem_value(id: &str) -> String {
     let doc = gloo_utils::document();
@@ -77,7 +112,14 @@ fn add_by_text_cb(link: Scope<Add>) {
     }
 
     // Construct the submission and update the progress
-    let submission = ArticleTextSubmission { title, body };
+    // Let the server detect the language and pick the voice
+    let submission = ArticleTextSubmission {
+        title,
+        body,
+        author: None,
+        lang: None,
+        voice: VoiceSelection::default(),
+    };
     link.send_message(AddMsg::AddProgress("Converting to speech...".to_string()));
 
     tracing::debug!("Submitting {:?}", submission);
@@ -107,7 +149,12 @@ fn add_by_url_cb(link: Scope<Add>) {
     }
 
     // Construct the submission and update the progress
-    let submission = ArticleUrlSubmission { url };
+    // Let the server detect the language and pick the voice
+    let submission = ArticleUrlSubmission {
+        url,
+        lang: None,
+        voice: VoiceSelection::default(),
+    };
     link.send_message(AddMsg::AddProgress(
         "Fetching and converting article...".to_string(),
     ));
@@ -126,6 +173,41 @@ fn add_by_url_cb(link: Scope<Add>) {
     });
 }
 
+/// POSTs the chosen file to the server for extraction and conversion
+fn add_by_file_cb(link: Scope<Add>) {
+    // Get the chosen file
+    let input: HtmlInputElement = gloo_utils::document()
+        .get_element_by_id(FILE_FORM_ID)
+        .unwrap()
+        .dyn_into()
+        .unwrap();
+    let file = match input.files().and_then(|files| files.get(0)) {
+        Some(f) => f,
+        None => {
+            gloo_utils::window()
+                .alert_with_message("Must choose a file")
+                .unwrap();
+            return;
+        }
+    };
+
+    link.send_message(AddMsg::AddProgress(format!(
+        "Converting {} to speech...",
+        file.name()
+    )));
+
+    // Make the submission
+    link.send_future(async move {
+        match submit_article_file(&file).await {
+            // On error, send the error
+            Err(e) => AddMsg::SetError(e),
+            // On success, say so, and say how many articles a book became
+            Ok(n) if n > 1 => AddMsg::AddProgress(format!("Success! Added {n} articles.")),
+            Ok(_) => AddMsg::AddProgress("Success!".to_string()),
+        }
+    });
+}
+
 #[derive(Default)]
 pub(crate) struct Add {
     err: Option<AnyError>,
@@ -160,8 +242,10 @@ impl Component for Add {
     fn view(&self, ctx: &Context<Self>) -> Html {
         let link = ctx.link().clone();
         let link2 = ctx.link().clone();
+        let link3 = ctx.link().clone();
         let add_text_callback = Callback::from(move |_| add_by_text_cb(link.clone()));
         let add_url_callback = Callback::from(move |_| add_by_url_cb(link2.clone()));
+        let add_file_callback = Callback::from(move |_| add_by_file_cb(link3.clone()));
 
         let err_str = self
             .err
@@ -173,8 +257,8 @@ impl Component for Add {
             <main>
                 <h1>{ "Add article" }</h1>
                 <p>{
-                    "You may add an article either by providing a URL, or by pasting the title
-                    and body text"
+                    "You may add an article by providing a URL, by uploading a file, or by pasting
+                    the title and body text"
                 }</p>
                 <fieldset>
                     <legend><h2>{ "Add article by URL" }</h2></legend>
@@ -184,6 +268,23 @@ impl Component for Add {
                     </div>
                     <button type="submit" onclick={add_url_callback}>{ "Submit" }</button>
                 </fieldset>
+                <fieldset>
+                    <legend><h2>{ "Add article by file" }</h2></legend>
+                    <p>{
+                        "PDF, EPUB, text, Markdown, and HTML files are supported. A book is added
+                        as one article per chapter."
+                    }</p>
+                    <div class="field">
+                        <label for={FILE_FORM_ID}>{ "Article file:" }</label>
+                        <input
+                            type="file"
+                            id={FILE_FORM_ID}
+                            accept={ACCEPTED_FILE_TYPES}
+                            required=true
+                        />
+                    </div>
+                    <button type="submit" onclick={add_file_callback}>{ "Submit" }</button>
+                </fieldset>
                 <fieldset>
                     <legend><h2>{ "Add article by text" }</h2></legend>
                     <div class="field">
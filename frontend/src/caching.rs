@@ -1,18 +1,20 @@
 use crate::{
     player_view::{ArticleState, PlayerState},
     queue_view::{ArticleId, CachedArticle, Queue, QueueEntry},
+    utils,
 };
 
 use std::{cell::RefCell, sync::Arc};
 
 use anyhow::{anyhow, bail, Error as AnyError};
+use common::AudioCodec;
 use gloo_utils::{format::JsValueSerdeExt, window};
 use ringbuffer::AllocRingBuffer;
 use wasm_bindgen::{closure::Closure, JsCast, JsValue};
 use wasm_bindgen_futures::{spawn_local, JsFuture};
 use web_sys::{
-    Blob, BlobPropertyBag, Event, IdbDatabase, IdbObjectStore, IdbObjectStoreParameters,
-    IdbRequest, IdbTransactionMode, RegistrationOptions,
+    Blob, Event, IdbDatabase, IdbObjectStore, IdbObjectStoreParameters, IdbRequest,
+    IdbTransactionMode, RegistrationOptions,
 };
 
 // TODO Fixme: This path is only valid in production mode
@@ -411,18 +413,8 @@ pub(crate) async fn save_article(article: &CachedArticle) -> Result<QueueEntry,
     )
     .unwrap();
 
-    // Set the blob
-    let blob = {
-        let bytes = js_sys::Uint8Array::from(article.audio_blob.as_slice());
-        // A blob is made from an array of arrays. So construct [bytes] and use that.
-        let parts = js_sys::Array::new();
-        parts.set(0, JsValue::from(bytes));
-        Blob::new_with_u8_array_sequence_and_options(
-            &parts,
-            BlobPropertyBag::new().type_("audio/mp3"),
-        )
-        .unwrap()
-    };
+    // Set the blob. Its MIME type records the codec
+    let blob = utils::bytes_to_audio_blob(&article.audio_blob, article.codec);
     js_sys::Reflect::set(&serialized_article, &JsValue::from_str("audio_blob"), &blob).unwrap();
 
     // Insert the article
@@ -446,11 +438,12 @@ pub(crate) async fn load_article(id: &ArticleId) -> Result<CachedArticle, AnyErr
     let title = js_sys::Reflect::get(&serialized_article, &JsValue::from_str("title"))
         .map_err(|e| wrap_jserror("couldn't get article title field", e))
         .map(|t| t.as_string().unwrap_or(id.clone()))?;
-    // Get the MP3 bytes
+    // Get the audio bytes. The codec is the blob's MIME type
     let js_blob: Blob = js_sys::Reflect::get(&serialized_article, &JsValue::from_str("audio_blob"))
         .unwrap()
         .dyn_into()
         .unwrap();
+    let codec = AudioCodec::from_mime_type(&js_blob.type_()).unwrap_or_default();
     let array_buf = JsFuture::from(js_blob.array_buffer()).await.unwrap();
     let audio_blob = js_sys::Uint8Array::new(&array_buf).to_vec();
 
@@ -458,6 +451,7 @@ pub(crate) async fn load_article(id: &ArticleId) -> Result<CachedArticle, AnyErr
         id: ArticleId(id.clone()),
         title,
         audio_blob,
+        codec,
     })
 }
 
@@ -4,7 +4,7 @@ use crate::{
     queue_view::{ArticleId, CachedArticle, Queue, QueueEntry, QueueMsg},
     WeakComponentLink,
 };
-use common::{ArticleMetadata, LibraryCatalog};
+use common::{ArticleMetadata, AudioCodec, LibraryCatalog};
 
 use std::collections::BTreeMap;
 
@@ -43,10 +43,11 @@ async fn fetch_catalog() -> Result<LibraryCatalog, AnyError> {
 async fn fetch_article(
     id: &ArticleId,
     title: &str,
+    codec: AudioCodec,
     lib_link: Scope<Library>,
 ) -> Result<CachedArticle, AnyError> {
     // Fetch the audio blobs
-    let filename = format!("{}.mp3", id.0);
+    let filename = format!("{}.{}", id.0, codec.extension());
     let encoded_title = urlencoding::encode(&filename);
     let resp = Request::get(&format!("/api/audio-blobs/{encoded_title}"))
         .send()
@@ -98,6 +99,7 @@ async fn fetch_article(
         title: title.to_string(),
         id: id.clone(),
         audio_blob,
+        codec,
     })
 }
 
@@ -115,6 +117,7 @@ fn render_lib_item(
 ) -> Html {
     let title = metadata.title.clone();
     let id = ArticleId(metadata.id.clone());
+    let codec = metadata.codec;
     let title_copy = title.clone();
 
     // Generate the ID for the button/progress indicator
@@ -127,6 +130,7 @@ fn render_lib_item(
         LibraryMsg::FetchArticle {
             id: id.clone(),
             title: title_copy.clone(),
+            codec,
         }
     });
 
@@ -245,7 +249,11 @@ pub(crate) enum LibraryMsg {
     /// Sets the Library's error display to the given error
     SetError(AnyError),
     /// Tells the library to do a fetch() for the specific article
-    FetchArticle { id: ArticleId, title: String },
+    FetchArticle {
+        id: ArticleId,
+        title: String,
+        codec: AudioCodec,
+    },
     /// Tells the library to fetch() the catalog
     FetchCatalog,
     /// Updates the download progress of the given article
@@ -294,7 +302,7 @@ impl Component for Library {
                 });
             }
 
-            LibraryMsg::FetchArticle { id, title } => {
+            LibraryMsg::FetchArticle { id, title, codec } => {
                 // We've been asked to fetch an article. Immediately set its progress to 0%
                 self.download_progresses
                     .insert(id.clone(), DownloadProgress::InProgress(0.0));
@@ -303,7 +311,7 @@ impl Component for Library {
                 // post it
                 let id_copy = id.clone();
                 ctx.link().send_future(async move {
-                    let article = match fetch_article(&id_copy, &title, lib_link).await {
+                    let article = match fetch_article(&id_copy, &title, codec, lib_link).await {
                         Ok(a) => a,
                         Err(e) => return LibraryMsg::SetError(e),
                     };
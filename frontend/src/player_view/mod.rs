@@ -38,9 +38,9 @@ async fn prepare_for_play(id: &ArticleId, audio_link: &Scope<Audio>) -> f64 {
     // Load the article and set the <audio> src to it
     match caching::load_article(&id).await {
         Ok(article) => {
-            let mp3_blob = utils::bytes_to_mp3_blob(&article.audio_blob);
+            let audio_blob = utils::bytes_to_audio_blob(&article.audio_blob, article.codec);
             audio_link.send_message(AudioMsg::Load {
-                src: mp3_blob,
+                src: audio_blob,
                 title: article.title,
                 elapsed,
             });
@@ -5,6 +5,7 @@ use crate::{
     WeakComponentLink,
 };
 
+use common::AudioCodec;
 use serde::{Deserialize, Serialize};
 use wasm_bindgen_futures::spawn_local;
 use yew::prelude::*;
@@ -44,6 +45,9 @@ pub struct CachedArticle {
     pub title: String,
     pub id: ArticleId,
     pub audio_blob: Vec<u8>,
+    /// The format of `audio_blob`
+    #[serde(default)]
+    pub codec: AudioCodec,
 }
 
 impl From<&CachedArticle> for QueueEntry {
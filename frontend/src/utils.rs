@@ -1,4 +1,5 @@
 use anyhow::Error as AnyError;
+use common::AudioCodec;
 use js_sys::Uint8Array;
 use wasm_bindgen::{closure::Closure, JsCast, JsValue};
 use wasm_bindgen_futures::{spawn_local, JsFuture};
@@ -7,10 +8,8 @@ use web_sys::{
     ReadableStreamDefaultReader,
 };
 
-const MP3_MIME_TYPE: &str = "audio/mp3";
-
-/// Converts bytes to a blob with the given MIME type
-pub fn bytes_to_mp3_blob(bytes: &[u8]) -> Blob {
+/// Converts bytes to a blob with the MIME type of the given codec
+pub fn bytes_to_audio_blob(bytes: &[u8], codec: AudioCodec) -> Blob {
     let arr = js_sys::Uint8Array::from(bytes);
 
     // A blob is made from an array of arrays. So construct [bytes] and use that.
@@ -18,7 +17,7 @@ pub fn bytes_to_mp3_blob(bytes: &[u8]) -> Blob {
     parts.set(0, JsValue::from(arr));
     Blob::new_with_u8_array_sequence_and_options(
         &parts,
-        BlobPropertyBag::new().type_(MP3_MIME_TYPE),
+        BlobPropertyBag::new().type_(codec.mime_type()),
     )
     .unwrap()
 }
@@ -0,0 +1,108 @@
+# This Python 3 script produces assets/chime.mp3, the earcon that's played between the sections of
+# an article. There's no MP3 encoder involved. A chime is just a couple of decaying tones, and a tone
+# is a single nonzero line of the MDCT spectrum, so we write the Layer III frames by hand.
+#
+# The frames are MPEG-1 Layer III, 64kbps, 48kHz, mono. That's what Google Cloud and our ffmpeg
+# encoding output, so the chime can be spliced between TTS chunks frame by frame.
+
+# The frame header: MPEG-1, Layer III, no CRC, 64kbps, 48kHz, no padding, mono
+HEADER = bytes([0xFF, 0xFB, 0x54, 0xC4])
+# 144 * 64000 / 48000
+FRAME_SIZE = 192
+# The number of spectral lines in a granule. A frame has 2 granules
+LINES_PER_GRANULE = 576
+
+# Huffman table 1, which codes pairs of values whose magnitudes are at most 1. Maps (|x|, |y|) to
+# (code, code length)
+TABLE_1 = {(0, 0): (0b1, 1), (0, 1): (0b001, 3), (1, 0): (0b01, 2), (1, 1): (0b000, 3)}
+
+# The notes of the chime, as (spectral line, starting granule). Line k sounds at roughly
+# (k + 0.5) * 24000 / 576 Hz. Lines with k = 3 mod 6, whose sign flips every granule, give the
+# cleanest tones. These are 1375Hz and 1125Hz
+NOTES = [(33, 0), (27, 16)]
+# The global gain of a note when it starts. Every step is 1.5dB. This peaks at about -10dBFS
+START_GAIN = 199
+# How much the gain of a note drops every granule
+DECAY_PER_GRANULE = 0.75
+# The length of the chime. 48 granules is 0.576s
+NUM_GRANULES = 48
+
+
+class BitWriter:
+    def __init__(self):
+        self.bits = []
+
+    def write(self, value, num_bits):
+        for i in reversed(range(num_bits)):
+            self.bits.append((value >> i) & 1)
+
+    def to_bytes(self):
+        bits = self.bits + [0] * (-len(self.bits) % 8)
+        return bytes(
+            int("".join(map(str, bits[i : i + 8])), 2) for i in range(0, len(bits), 8)
+        )
+
+
+def huffman_code(lines):
+    """Codes the spectral lines with table 1. Returns the number of pairs coded, and the bits"""
+    writer = BitWriter()
+    nonzero = [i for i, v in enumerate(lines) if v]
+    num_pairs = nonzero[-1] // 2 + 1 if nonzero else 0
+    for i in range(num_pairs):
+        x, y = lines[2 * i], lines[2 * i + 1]
+        code, code_len = TABLE_1[(abs(x), abs(y))]
+        writer.write(code, code_len)
+        # Every nonzero value is followed by its sign. 1 is negative
+        for v in (x, y):
+            if v:
+                writer.write(1 if v < 0 else 0, 1)
+    return num_pairs, writer.bits
+
+
+def make_frame(granules):
+    """Makes a frame out of 2 granules, each given as (spectral lines, global gain)"""
+    side_info = BitWriter()
+    main_data = BitWriter()
+    # main_data_begin (no bit reservoir), private bits, and scfsi
+    side_info.write(0, 9)
+    side_info.write(0, 5)
+    side_info.write(0, 4)
+    for lines, gain in granules:
+        num_pairs, bits = huffman_code(lines)
+        # part2_3_length. There are no scalefactors, so this is just the Huffman code
+        side_info.write(len(bits), 12)
+        # big_values, global_gain, scalefac_compress, window_switching_flag
+        side_info.write(num_pairs, 9)
+        side_info.write(gain, 8)
+        side_info.write(0, 4)
+        side_info.write(0, 1)
+        # table_select for the 3 regions, region0_count, region1_count
+        for _ in range(3):
+            side_info.write(1, 5)
+        side_info.write(7, 4)
+        side_info.write(7, 3)
+        # preflag, scalefac_scale, count1table_select
+        side_info.write(0, 3)
+        main_data.bits += bits
+
+    frame = HEADER + side_info.to_bytes() + main_data.to_bytes()
+    assert len(frame) <= FRAME_SIZE
+    return frame + bytes(FRAME_SIZE - len(frame))
+
+
+def make_chime():
+    granules = []
+    for g in range(NUM_GRANULES):
+        lines = [0] * LINES_PER_GRANULE
+        gain = 0
+        for line, start in NOTES:
+            if g >= start:
+                lines[line] = 1 if (g - start) % 2 == 0 else -1
+                gain = round(START_GAIN - DECAY_PER_GRANULE * (g - start))
+        granules.append((lines, gain))
+
+    return b"".join(make_frame(granules[i : i + 2]) for i in range(0, len(granules), 2))
+
+
+with open("assets/chime.mp3", "wb") as f:
+    f.write(make_chime())
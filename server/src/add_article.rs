@@ -1,33 +1,54 @@
 use crate::{
     error::RtmsError,
-    lang::pick_tts_voice,
-    tts::{get_api_key, tts, TtsRequest, VoiceQuality, VoiceType},
-    util::{derive_article_id, get_mp3_duration, save_metadata, truncate_to_bytes, StrEncoding},
+    extract::{
+        epub::read_epub,
+        pdf::extract_pdf,
+        text_file::{read_markdown, read_plain_text},
+        ExtractedArticle, Extractors,
+    },
+    lang::{article_lang, byline, segment_by_language, ArticleLang, IntroPart},
+    lexicon::Lexicon,
+    normalize::Normalizer,
+    quotes::split_quotations,
+    tts::{
+        cache::ChunkCache, plan_requests, ssml::escape_blocks, tts, AudioCodec, SectionCue,
+        SharedTtsEngine, TtsEngine, TtsPlan, TtsProgress, VoiceQuality, VoiceType, VoicedSegment,
+    },
+    usage::UsageLog,
+    util::{
+        derive_article_id, get_audio_duration, save_alignment, save_metadata, save_sections,
+        truncate_to_bytes, StrEncoding,
+    },
 };
 use common::{
-    ArticleBookmarkletSubmission, ArticleMetadata, ArticleTextSubmission, ArticleUrlSubmission,
+    ArticleAlignment, ArticleBookmarkletSubmission, ArticleMetadata, ArticleProgress,
+    ArticleSections, ArticleSeries, ArticleTextSubmission, ArticleUrlSubmission, VoiceSelection,
     MAX_TITLE_UTF16_CODEUNITS,
 };
-use futures::AsyncWriteExt;
 
 use std::{
+    collections::HashMap,
     fs::{self, File, OpenOptions},
     io::Write,
     num::{NonZeroU32, NonZeroUsize},
-    path::Path,
-    process::Stdio,
-    sync::Arc,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
     time::SystemTime,
 };
 
-use anyhow::{anyhow, Context};
-use async_process::Command;
-use axum::{extract::Extension, routing::post, Form, Json, Router};
+use anyhow::{anyhow, bail, Context};
+use axum::{
+    extract::{multipart::Field, Extension, Multipart},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use bytes::Bytes;
 use governor::{
     clock::DefaultClock, middleware::NoOpMiddleware, state::direct::NotKeyed, state::InMemoryState,
     Quota, RateLimiter as BaseRateLimiter,
 };
-use serde::Deserialize;
+use tokio::sync::watch;
+use whatlang::Lang;
 
 type DefaultRateLimiter = BaseRateLimiter<NotKeyed, InMemoryState, DefaultClock, NoOpMiddleware>;
 
@@ -38,23 +59,127 @@ struct RateLimiter {
     quota: Quota,
 }
 
-/// A portion of trafilatura's extracted text. The rest of the fields are: title, author, hostname,
-/// date, categories, tags, fingerprint, id, license, comments, raw_text, source, source_hostname,
-/// excerpt, text
-#[derive(Deserialize)]
-struct ExtractedArticle {
+impl RateLimiter {
+    /// Makes a rate limiter that allows `max_chars_per_min` characters per minute
+    fn new(max_chars_per_min: NonZeroU32) -> Self {
+        let quota = Quota::per_minute(max_chars_per_min);
+        RateLimiter {
+            base_rl: Arc::new(DefaultRateLimiter::direct(quota)),
+            quota,
+        }
+    }
+}
+
+/// The state that synthesizing articles shares: the chunk cache, the usage log, the text
+/// normalizer, the pronunciation lexicon, what the intro says after the title, how many chunks of
+/// an article can be synthesized at once, the cue between sections, and the articles that are
+/// being synthesized right now
+#[derive(Clone)]
+pub(crate) struct TtsContext {
+    chunk_cache: ChunkCache,
+    usage_log: UsageLog,
+    normalizer: Normalizer,
+    lexicon: Lexicon,
+    intro: Vec<IntroPart>,
+    max_concurrent_chunks: NonZeroUsize,
+    section_cue: Option<SectionCue>,
+    /// Maps the IDs of the articles in progress to their titles and progress
+    in_progress: Arc<Mutex<HashMap<String, TtsJob>>>,
+}
+
+/// An article that's being synthesized
+struct TtsJob {
     title: String,
-    text: String,
+    progress: watch::Receiver<TtsProgress>,
+}
+
+/// Removes an article from the in-progress list when dropped
+struct TtsJobGuard {
+    ctx: TtsContext,
+    id: String,
+}
+
+impl TtsContext {
+    pub(crate) fn new(
+        chunk_cache: ChunkCache,
+        usage_log: UsageLog,
+        normalizer: Normalizer,
+        lexicon: Lexicon,
+        intro: Vec<IntroPart>,
+        max_concurrent_chunks: NonZeroUsize,
+        section_cue: Option<SectionCue>,
+    ) -> Self {
+        TtsContext {
+            chunk_cache,
+            usage_log,
+            normalizer,
+            lexicon,
+            intro,
+            max_concurrent_chunks,
+            section_cue,
+            in_progress: Arc::default(),
+        }
+    }
+
+    /// Adds the given article to the in-progress list. Returns the channel to report its progress
+    /// on, and a guard that takes it off the list once the article is done, successfully or not
+    fn start(&self, id: &str, title: &str) -> (watch::Sender<TtsProgress>, TtsJobGuard) {
+        let (progress_tx, progress_rx) = watch::channel(TtsProgress::default());
+        self.in_progress.lock().unwrap().insert(
+            id.to_string(),
+            TtsJob {
+                title: title.to_string(),
+                progress: progress_rx,
+            },
+        );
+
+        let guard = TtsJobGuard {
+            ctx: self.clone(),
+            id: id.to_string(),
+        };
+        (progress_tx, guard)
+    }
+
+    /// Returns the progress of every article in progress, sorted by title
+    fn list(&self) -> Vec<ArticleProgress> {
+        let mut list: Vec<ArticleProgress> = self
+            .in_progress
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, job)| {
+                let progress = *job.progress.borrow();
+                ArticleProgress {
+                    id: id.clone(),
+                    title: job.title.clone(),
+                    chunks_done: progress.chunks_done,
+                    chunks_total: progress.chunks_total,
+                }
+            })
+            .collect();
+        list.sort_by(|a, b| a.title.cmp(&b.title));
+        list
+    }
 }
 
-// Sets the /api/add-article route
-pub(crate) fn setup(router: Router, max_chars_per_min: NonZeroU32, audio_blob_dir: &str) -> Router {
+impl Drop for TtsJobGuard {
+    fn drop(&mut self) {
+        self.ctx.in_progress.lock().unwrap().remove(&self.id);
+    }
+}
+
+// Sets the /api/add-article-* and /api/add-article-progress routes
+pub(crate) fn setup(
+    router: Router,
+    max_chars_per_min: NonZeroU32,
+    max_upload_size: usize,
+    audio_blob_dir: &str,
+    extractors: Extractors,
+    tts_engine: SharedTtsEngine,
+    tts_ctx: TtsContext,
+) -> Router {
     // Set up the rate limiter for our TTS queries
-    let quota = Quota::per_minute(max_chars_per_min);
-    let tts_rate_limiter = RateLimiter {
-        base_rl: Arc::new(DefaultRateLimiter::direct(quota.clone())),
-        quota,
-    };
+    let tts_rate_limiter = RateLimiter::new(max_chars_per_min);
 
     // Set up the routes
     router.nest(
@@ -66,7 +191,13 @@ pub(crate) fn setup(router: Router, max_chars_per_min: NonZeroU32, audio_blob_di
                 "/add-article-by-bookmarklet",
                 post(add_article_by_bookmarklet_endpoint),
             )
+            .route("/add-article-by-file", post(add_article_by_file_endpoint))
+            .route("/add-article-progress", get(add_article_progress_endpoint))
+            .layer(Extension(extractors))
+            .layer(Extension(UploadLimit(max_upload_size)))
             .layer(Extension(tts_rate_limiter))
+            .layer(Extension(tts_ctx))
+            .layer(Extension(tts_engine))
             .layer(Extension(audio_blob_dir.to_string())),
     )
 }
@@ -75,11 +206,29 @@ pub(crate) fn setup(router: Router, max_chars_per_min: NonZeroU32, audio_blob_di
 async fn add_article_by_text_endpoint(
     Json(article): Json<ArticleTextSubmission>,
     Extension(tts_rate_limiter): Extension<RateLimiter>,
+    Extension(tts_engine): Extension<SharedTtsEngine>,
+    Extension(tts_ctx): Extension<TtsContext>,
     Extension(audio_blob_dir): Extension<String>,
 ) -> Result<String, RtmsError> {
-    // Just call down to add_article_by_text
+    // The text is raw, so none of it is markup. The ID is still of the text as submitted, so that
+    // it's the same however the text is escaped. Then just call down to add_article_with_id
     tracing::debug!("Adding article by text: '{}'", article.title);
-    let meta = match add_article_by_text(&article, tts_rate_limiter, &audio_blob_dir).await {
+    let id = derive_article_id(&article);
+    let escaped = ArticleTextSubmission {
+        body: escape_blocks(&article.body),
+        ..article
+    };
+    let meta = match add_article_with_id(
+        id,
+        &escaped,
+        None,
+        tts_rate_limiter,
+        tts_engine,
+        tts_ctx,
+        &audio_blob_dir,
+    )
+    .await
+    {
         Ok(m) => m,
         Err(e) => {
             tracing::error!("Error adding by text: {:?}", e);
@@ -96,12 +245,24 @@ async fn add_article_by_text_endpoint(
 
 /// Fetches the article at the given URL, converts it to speech, and returns the new filename
 async fn add_article_by_url_endpoint(
-    Json(ArticleUrlSubmission { url }): Json<ArticleUrlSubmission>,
+    Json(submission): Json<ArticleUrlSubmission>,
+    Extension(extractors): Extension<Extractors>,
     Extension(tts_rate_limiter): Extension<RateLimiter>,
+    Extension(tts_engine): Extension<SharedTtsEngine>,
+    Extension(tts_ctx): Extension<TtsContext>,
     Extension(audio_blob_dir): Extension<String>,
 ) -> Result<String, RtmsError> {
-    tracing::debug!("Adding article by URL: {url}");
-    let meta = match add_article_by_url(&url, tts_rate_limiter, &audio_blob_dir).await {
+    tracing::debug!("Adding article by URL: {}", submission.url);
+    let meta = match add_article_by_url(
+        submission,
+        &extractors,
+        tts_rate_limiter,
+        tts_engine,
+        tts_ctx,
+        &audio_blob_dir,
+    )
+    .await
+    {
         Ok(m) => m,
         Err(e) => {
             tracing::error!("Error adding by url: {:?}", e);
@@ -119,12 +280,23 @@ async fn add_article_by_url_endpoint(
 /// Converts the given article contents to speech, assigns it the given URL metadata, and returns the new filename
 async fn add_article_by_bookmarklet_endpoint(
     Form(ArticleBookmarkletSubmission { url, page_html }): Form<ArticleBookmarkletSubmission>,
+    Extension(extractors): Extension<Extractors>,
     Extension(tts_rate_limiter): Extension<RateLimiter>,
+    Extension(tts_engine): Extension<SharedTtsEngine>,
+    Extension(tts_ctx): Extension<TtsContext>,
     Extension(audio_blob_dir): Extension<String>,
 ) -> Result<String, RtmsError> {
     tracing::debug!("Adding article by bookmarklet input: url={url}");
-    let meta = match add_article_by_bookmarklet(&url, &page_html, tts_rate_limiter, &audio_blob_dir)
-        .await
+    let meta = match add_article_by_bookmarklet(
+        &url,
+        &page_html,
+        &extractors,
+        tts_rate_limiter,
+        tts_engine,
+        tts_ctx,
+        &audio_blob_dir,
+    )
+    .await
     {
         Ok(m) => m,
         Err(e) => {
@@ -140,43 +312,146 @@ async fn add_article_by_bookmarklet_endpoint(
     Ok(format!("Successfully added article '{}'", meta.title))
 }
 
-/// The real logic. Converts the given article contents to speech, and returns the new filename
+/// Converts the uploaded file to speech, and returns the new filenames, one per line. A book is
+/// added as one article per chapter. The request is a multipart form with the file in the `file`
+/// field. The optional `lang`, `voice`, `quality`, `pitch`, and `dual_voice` fields are the same as
+/// in the other submissions.
+async fn add_article_by_file_endpoint(
+    multipart: Multipart,
+    Extension(UploadLimit(max_upload_size)): Extension<UploadLimit>,
+    Extension(extractors): Extension<Extractors>,
+    Extension(tts_rate_limiter): Extension<RateLimiter>,
+    Extension(tts_engine): Extension<SharedTtsEngine>,
+    Extension(tts_ctx): Extension<TtsContext>,
+    Extension(audio_blob_dir): Extension<String>,
+) -> Result<String, RtmsError> {
+    let submission = FileSubmission::read(multipart, max_upload_size).await?;
+    tracing::debug!(
+        "Adding article by file: {:?} ({:?}, {} bytes)",
+        submission.file_name,
+        submission.content_type,
+        submission.contents.len()
+    );
+    let metas = match add_article_by_file(
+        submission,
+        &extractors,
+        tts_rate_limiter,
+        tts_engine,
+        tts_ctx,
+        &audio_blob_dir,
+    )
+    .await
+    {
+        Ok(m) => m,
+        Err(e) => {
+            tracing::error!("Error adding by file: {:?}", e);
+            return Err(e);
+        }
+    };
+
+    let ids: Vec<String> = metas.into_iter().map(|meta| meta.id).collect();
+    Ok(ids.join("\n"))
+}
+
+/// Lists the articles that are being synthesized right now, and how far along they are
+async fn add_article_progress_endpoint(
+    Extension(tts_ctx): Extension<TtsContext>,
+) -> Json<Vec<ArticleProgress>> {
+    Json(tts_ctx.list())
+}
+
+/// Returns the path of the article with the given ID, if it's in the library. It might have been
+/// saved with any codec, since the engine's codec can change between runs.
+fn existing_article(audio_blob_dir: &str, id: &str) -> Option<PathBuf> {
+    AudioCodec::ALL
+        .into_iter()
+        .map(|codec| Path::new(audio_blob_dir).join(format!("{id}.{}", codec.extension())))
+        .find(|path| path.exists())
+}
+
+/// The real logic. Converts the given article contents to speech, and returns the new filename.
+/// `source_url` is where the article came from, if anywhere.
 async fn add_article_by_text(
     article: &ArticleTextSubmission,
+    source_url: Option<&str>,
+    tts_rate_limiter: RateLimiter,
+    tts_engine: SharedTtsEngine,
+    tts_ctx: TtsContext,
+    audio_blob_dir: &str,
+) -> Result<ArticleMetadata, RtmsError> {
+    add_article_with_id(
+        derive_article_id(article),
+        article,
+        source_url,
+        tts_rate_limiter,
+        tts_engine,
+        tts_ctx,
+        audio_blob_dir,
+    )
+    .await
+}
+
+/// Like `add_article_by_text`, but the article is saved under the given ID rather than one derived
+/// from its contents
+async fn add_article_with_id(
+    id: String,
+    article: &ArticleTextSubmission,
+    source_url: Option<&str>,
     tts_rate_limiter: RateLimiter,
+    tts_engine: SharedTtsEngine,
+    tts_ctx: TtsContext,
     audio_blob_dir: &str,
 ) -> Result<ArticleMetadata, RtmsError> {
     tracing::debug!("Processing article with title '{}'", article.title);
 
-    // Serialize the article and get its bytelen
-    let text = article.serialize();
-    let text_len: NonZeroU32 = {
-        let n = text.len();
-        let nzn = NonZeroUsize::new(n).or(NonZeroUsize::new(1)).unwrap();
-        NonZeroU32::try_from(nzn).with_context(|| "Article is is far too large")?
-    };
+    // Serialize the article with its intro in the article's language, clean it up, and apply the
+    // pronunciation lexicon, using the entries for its language and source domain
+    let domain = source_url
+        .and_then(|url| reqwest::Url::parse(url).ok())
+        .and_then(|url| url.host_str().map(str::to_string));
+    let ArticleLang { lang, confidence } = article_lang(&article.body, article.lang.as_deref())?;
+    let byline = byline(
+        &tts_ctx.intro,
+        lang,
+        article.author.as_deref(),
+        domain.as_deref(),
+    );
+    let text = article.serialize(byline.as_deref());
+    let text = tts_ctx.normalizer.normalize(&text, lang);
+    let text = tts_ctx.lexicon.apply(&text, lang, domain.as_deref());
 
-    // If the article bytelen exceeds the limit, error out
-    if tts_rate_limiter.base_rl.check_n(text_len).is_err() {
-        Err(anyhow!(
-            "Usage limit exceeded. This server processes at most {} letters per minute.",
-            tts_rate_limiter.quota.burst_size().get(),
-        ))?;
-    }
+    // Pick the voices and break the article into requests. Do this before checking the rate
+    // limit, so that a bad voice choice doesn't eat into the quota
+    let segments = plan_voices(tts_engine.as_ref(), &text, lang, &article.voice)?;
+    let plan = plan_requests(tts_engine.as_ref(), &segments)?;
 
-    let id = derive_article_id(&article);
+    // If the bytelen of the requests we have to make exceeds the limit, error out. Requests whose
+    // audio is already cached are free
+    let uncached_len = plan.uncached_len(&tts_ctx.chunk_cache, tts_engine.output_format());
+    if let Some(n) = NonZeroUsize::new(uncached_len) {
+        let n = NonZeroU32::try_from(n).with_context(|| "Article is is far too large")?;
+        if tts_rate_limiter.base_rl.check_n(n).is_err() {
+            Err(anyhow!(
+                "Usage limit exceeded. This server processes at most {} letters per minute.",
+                tts_rate_limiter.quota.burst_size().get(),
+            ))?;
+        }
+    }
 
-    // Fail if the article already exists. The filename is ID.mp333
-    let savepath = Path::new(&audio_blob_dir).join(&format!("{id}.mp3"));
-    if savepath.exists() {
-        Err(anyhow!("File '{:?}' already exists", savepath))?;
+    // Fail if the article already exists, with any codec. The filename is ID.EXT, where EXT depends
+    // on the codec the TTS engine outputs
+    if let Some(existing) = existing_article(audio_blob_dir, &id) {
+        Err(anyhow!("File '{:?}' already exists", existing))?;
     }
+    let codec = tts_engine.output_codec();
+    let ext = codec.extension();
+    let savepath = Path::new(&audio_blob_dir).join(&format!("{id}.{ext}"));
 
     // Open a temp file. This is so that list-articles won't try to read it while we're writing.
     // Again, fail if the file exists.
     let tmp_savepath = Path::new(&audio_blob_dir)
         .join(&id)
-        .with_extension("mp3.tmp");
+        .with_extension(format!("{ext}.tmp"));
     let mut tmp_savefile = OpenOptions::new()
         .write(true)
         .create(false)
@@ -184,8 +459,18 @@ async fn add_article_by_text(
         .open(&tmp_savepath)
         .map_err(|e| anyhow!("Couldn't open tmp savefile '{:?}': {:?}", tmp_savepath, e))?;
 
-    // Try to do a TTS and save to the savefile. On error, make sure to clean up the empty file
-    tts_to_file(&mut tmp_savefile, text).await.map_err(|e| {
+    // Try to do a TTS and save to the savefile, reporting progress as we go. On error, make sure
+    // to clean up the empty file
+    let (progress_tx, _job_guard) = tts_ctx.start(&id, &article.title);
+    let (alignment, sections) = tts_to_file(
+        tts_engine.as_ref(),
+        &mut tmp_savefile,
+        &plan,
+        &tts_ctx,
+        &progress_tx,
+    )
+    .await
+    .map_err(|e| {
         // Remove the file
         if let Err(f) = fs::remove_file(&tmp_savepath) {
             let context = format!("could not delete {id}: {f}");
@@ -199,8 +484,20 @@ async fn add_article_by_text(
     std::fs::rename(&tmp_savepath, &savepath)
         .map_err(|e| anyhow!("could not rename {:?} to {:?}: {e}", tmp_savepath, savepath))?;
 
+    // Save the text-to-audio alignment, if the TTS engine gave us one
+    if !alignment.0.is_empty() {
+        let _ = save_alignment(&id, &alignment, audio_blob_dir)
+            .map_err(|e| tracing::error!("Error saving alignment: {e}"));
+    }
+
+    // Save the start times of the sections, if the article has headings
+    if !sections.0.is_empty() {
+        let _ = save_sections(&id, &sections, audio_blob_dir)
+            .map_err(|e| tracing::error!("Error saving sections: {e}"));
+    }
+
     // Measure its duration. This goes in metadata
-    let article_duration = get_mp3_duration(&savepath).ok();
+    let article_duration = get_audio_duration(&savepath, codec).ok();
 
     // Get the current time. This is the official time the article was added to the library
     let unix_epoch_now = SystemTime::now()
@@ -224,114 +521,1080 @@ async fn add_article_by_text(
         title: truncated_title,
         duration: article_duration,
         datetime_added: Some(unix_epoch_now),
-        source_url: None,
+        source_url: source_url.map(str::to_string),
+        lang: Some(lang.code().to_string()),
+        lang_confidence: confidence,
+        codec,
+        series: None,
     })
 }
 
-/// The real logic. Fetches the article at the given URL, converts it to speech, and returns the
-/// new filename
+/// The real logic. Fetches the article at the submitted URL, converts it to speech, and returns
+/// the new filename.
 async fn add_article_by_url(
-    url: &str,
+    submission: ArticleUrlSubmission,
+    extractors: &Extractors,
     tts_rate_limiter: RateLimiter,
+    tts_engine: SharedTtsEngine,
+    tts_ctx: TtsContext,
     audio_blob_dir: &str,
 ) -> Result<ArticleMetadata, RtmsError> {
-    // TODO: Check earlier that trafilatura is present
-
-    // Run trafilatura on the given URL
-    let output = Command::new("../python_deps/bin/trafilatura")
-        .env("PYTHONPATH", "../python_deps")
-        .arg("--json")
-        .arg("--URL")
-        .arg(&url)
-        .output()
-        .await
-        .map_err(|e| anyhow!("IO error running trafulatura: {:?}", e))?;
-    // See if the command failed
-    if !output.status.success() {
-        Err(anyhow!("Text extraction failed"))?;
-    }
+    let ArticleUrlSubmission { url, lang, voice } = submission;
 
-    // Convert the CLI output from JSON and turn it into a `ArticleTextSubmission`
-    let parsed_res: ExtractedArticle =
-        serde_json::from_slice(&output.stdout).map_err(|e| anyhow!("Text extraction failed"))?;
+    // Extract the article and turn it into a `ArticleTextSubmission`
+    let extracted = extractors.extract_url(&url).await?;
+    log_extraction(&extracted);
+    let ExtractedArticle {
+        title,
+        author,
+        text,
+        ..
+    } = extracted;
     let text_submission = ArticleTextSubmission {
-        title: parsed_res.title,
-        body: parsed_res.text,
+        title,
+        body: text,
+        author,
+        lang,
+        voice,
     };
 
     // Now that we have the article body, call down to add_article_by_text
-    let mut meta = add_article_by_text(&text_submission, tts_rate_limiter, audio_blob_dir).await?;
-    // Add the URL to the metadata
-    meta.source_url = Some(url.to_string());
-
-    Ok(meta)
+    add_article_by_text(
+        &text_submission,
+        Some(&url),
+        tts_rate_limiter,
+        tts_engine,
+        tts_ctx,
+        audio_blob_dir,
+    )
+    .await
 }
 
-/// Nearly identical to the fetch_by_url. Passes raw html to trafilatura instead of the URL
+/// Nearly identical to the fetch_by_url. Extracts the article from the given HTML instead of
+/// fetching it
 async fn add_article_by_bookmarklet(
     url: &str,
     page_html: &str,
+    extractors: &Extractors,
     tts_rate_limiter: RateLimiter,
+    tts_engine: SharedTtsEngine,
+    tts_ctx: TtsContext,
     audio_blob_dir: &str,
 ) -> Result<ArticleMetadata, RtmsError> {
-    // Run trafilatura on the given HTML
-    let mut child = Command::new("../python_deps/bin/trafilatura")
-        .env("PYTHONPATH", "../python_deps")
-        .arg("--json")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .map_err(|e| anyhow!("error starting trafulatura: {:?}", e))?;
-    // Write the HTML to the child process via stdin
-    let stdin = child
-        .stdin
-        .as_mut()
-        .ok_or(anyhow!("could not get stdin from child process"))?;
-    stdin
-        .write_all(page_html.as_bytes())
-        .await
-        .map_err(|e| anyhow!("error feeding article to child process: {:?}", e))?;
+    // Extract the article and turn it into a `ArticleTextSubmission`
+    let extracted = extractors.extract_html(page_html).await?;
+    log_extraction(&extracted);
+    let ExtractedArticle {
+        title,
+        author,
+        text,
+        ..
+    } = extracted;
+    let text_submission = ArticleTextSubmission {
+        title,
+        body: text,
+        author,
+        lang: None,
+        voice: VoiceSelection::default(),
+    };
+
+    // Now that we have the article body, call down to add_article_by_text
+    add_article_by_text(
+        &text_submission,
+        Some(url),
+        tts_rate_limiter,
+        tts_engine,
+        tts_ctx,
+        audio_blob_dir,
+    )
+    .await
+}
+
+/// The most bytes of a file that /api/add-article-by-file will read
+#[derive(Clone, Copy)]
+struct UploadLimit(usize);
 
-    // See if the command failed
-    let output = child.output().await.unwrap();
-    if !output.status.success() {
-        Err(anyhow!("Text extraction failed"))?;
+/// The most bytes read from any of the options submitted with a file. They're all short
+const MAX_UPLOAD_OPTION_SIZE: usize = 1024;
+
+/// A file uploaded to /api/add-article-by-file, and the options it was submitted with
+struct FileSubmission {
+    /// The name of the file on the client, if it sent one
+    file_name: Option<String>,
+    /// The MIME type of the file, if the client sent one
+    content_type: Option<String>,
+    contents: Bytes,
+    lang: Option<String>,
+    voice: VoiceSelection,
+}
+
+/// The kinds of files that can be added as articles
+#[derive(Debug, PartialEq)]
+enum FileKind {
+    Pdf,
+    Epub,
+    Text,
+    Markdown,
+    Html,
+}
+
+impl FileSubmission {
+    /// Reads the file and the options from a multipart form. Empty options are ignored, since
+    /// that's how browsers send unfilled form fields. Files over `max_size` bytes are rejected
+    async fn read(mut multipart: Multipart, max_size: usize) -> Result<Self, RtmsError> {
+        let mut file = None;
+        let mut lang = None;
+        let mut voice = VoiceSelection::default();
+        while let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(|e| anyhow!("Couldn't read upload: {e}"))?
+        {
+            let name = field.name().unwrap_or_default().to_string();
+            if name == "file" {
+                let file_name = field.file_name().map(str::to_string);
+                let content_type = field.content_type().map(str::to_string);
+                let contents = read_field(field, max_size)
+                    .await
+                    .map_err(|e| anyhow!("Couldn't read uploaded file: {e}"))?;
+                file = Some((file_name, content_type, Bytes::from(contents)));
+                continue;
+            }
+
+            let value = read_field(field, MAX_UPLOAD_OPTION_SIZE)
+                .await
+                .map_err(|e| anyhow!("Couldn't read field '{name}': {e}"))?;
+            let value = String::from_utf8(value)
+                .map_err(|_| anyhow!("Field '{name}' isn't valid UTF-8"))?;
+            if value.is_empty() {
+                continue;
+            }
+            match name.as_str() {
+                "lang" => lang = Some(value),
+                "voice" => voice.voice = Some(value),
+                "quality" => voice.quality = Some(parse_field(&name, value)?),
+                "pitch" => voice.pitch = Some(parse_field(&name, value)?),
+                "dual_voice" => voice.dual_voice = matches!(value.as_str(), "true" | "on" | "1"),
+                _ => tracing::debug!("Ignoring unknown upload field '{name}'"),
+            }
+        }
+
+        let (file_name, content_type, contents) = file.ok_or(anyhow!("No file was uploaded"))?;
+        Ok(FileSubmission {
+            file_name,
+            content_type,
+            contents,
+            lang,
+            voice,
+        })
     }
 
-    tracing::warn!("output length: {}", output.stdout.len());
+    /// Tells what kind of file this is. PDFs and EPUBs are told by their contents, MIME type, or
+    /// extension, in that order. Text files are told by their extension, then their MIME type,
+    /// since browsers don't agree on the MIME type of Markdown.
+    fn kind(&self) -> Option<FileKind> {
+        let content_type = self.content_type.as_deref().unwrap_or_default();
+        let content_type = content_type.split(';').next().unwrap_or_default().trim();
+        let ext = self
+            .file_name
+            .as_deref()
+            .and_then(|name| Path::new(name).extension())
+            .and_then(|ext| ext.to_str())
+            .map(str::to_lowercase);
+
+        // An EPUB is a zip file whose first entry is its uncompressed MIME type
+        let epub_magic = self.contents.starts_with(b"PK\x03\x04")
+            && self.contents.get(30..58) == Some(b"mimetypeapplication/epub+zip");
 
-    // Convert the CLI output from JSON and turn it into a `ArticleTextSubmission`
-    let parsed_res: ExtractedArticle = serde_json::from_slice(&output.stdout)
-        .map_err(|e| anyhow!("Trafilatura output parsing failed: {:?}", e))?;
+        if self.contents.starts_with(b"%PDF-")
+            || content_type == "application/pdf"
+            || ext.as_deref() == Some("pdf")
+        {
+            Some(FileKind::Pdf)
+        } else if epub_magic
+            || content_type == "application/epub+zip"
+            || ext.as_deref() == Some("epub")
+        {
+            Some(FileKind::Epub)
+        } else {
+            match (ext.as_deref(), content_type) {
+                (Some("txt" | "text"), _) => Some(FileKind::Text),
+                (Some("md" | "markdown"), _) => Some(FileKind::Markdown),
+                (Some("html" | "htm" | "xhtml"), _) => Some(FileKind::Html),
+                (_, "text/plain") => Some(FileKind::Text),
+                (_, "text/markdown" | "text/x-markdown") => Some(FileKind::Markdown),
+                (_, "text/html" | "application/xhtml+xml") => Some(FileKind::Html),
+                _ => None,
+            }
+        }
+    }
+
+    /// Returns the name of the file without its extension, which is the title of a text file that
+    /// doesn't have one
+    fn file_title(&self) -> Option<&str> {
+        self.file_name
+            .as_deref()
+            .and_then(|name| Path::new(name).file_stem())
+            .and_then(|stem| stem.to_str())
+            .filter(|stem| !stem.is_empty())
+    }
+
+    /// Returns the contents of a text file, without the byte order mark. Fails if it isn't UTF-8
+    fn text(&self) -> Result<&str, RtmsError> {
+        let text = std::str::from_utf8(&self.contents)
+            .map_err(|_| anyhow!("The file isn't UTF-8 text"))?;
+        Ok(text.trim_start_matches('\u{feff}'))
+    }
+}
+
+/// Reads a form field a chunk at a time, and stops with an error as soon as it's over `max_size`
+/// bytes. This way an oversized upload is never held in memory
+async fn read_field(mut field: Field<'_>, max_size: usize) -> Result<Vec<u8>, anyhow::Error> {
+    let mut contents = Vec::new();
+    while let Some(chunk) = field.chunk().await? {
+        if contents.len() + chunk.len() > max_size {
+            if max_size >= 1024 * 1024 {
+                bail!("it's over the {} MB limit", max_size / 1024 / 1024);
+            }
+            bail!("it's over the {max_size} byte limit");
+        }
+        contents.extend_from_slice(&chunk);
+    }
+    Ok(contents)
+}
+
+/// Parses a form field whose value is one of the variants of `T`, like `quality` or `pitch`
+fn parse_field<T: serde::de::DeserializeOwned>(name: &str, value: String) -> Result<T, RtmsError> {
+    serde_json::from_value(serde_json::Value::String(value.clone()))
+        .map_err(|_| anyhow!("Invalid {name} '{value}'").into())
+}
+
+/// The real logic. Extracts the article from the uploaded file, converts it to speech, and saves
+/// its metadata. Returns the metadata of the new articles. A book has an article per chapter.
+async fn add_article_by_file(
+    submission: FileSubmission,
+    extractors: &Extractors,
+    tts_rate_limiter: RateLimiter,
+    tts_engine: SharedTtsEngine,
+    tts_ctx: TtsContext,
+    audio_blob_dir: &str,
+) -> Result<Vec<ArticleMetadata>, RtmsError> {
+    // Extract the article and turn it into a `ArticleTextSubmission`
+    let extracted = match submission.kind() {
+        Some(FileKind::Pdf) => extract_pdf(submission.contents.to_vec()).await?,
+        Some(FileKind::Epub) => {
+            return add_book(
+                submission,
+                tts_rate_limiter,
+                tts_engine,
+                tts_ctx,
+                audio_blob_dir,
+            )
+            .await
+        }
+        Some(FileKind::Text) => read_plain_text(submission.text()?, submission.file_title()),
+        Some(FileKind::Markdown) => read_markdown(submission.text()?, submission.file_title()),
+        Some(FileKind::Html) => extractors.extract_html(submission.text()?).await?,
+        None => Err(anyhow!(
+            "Unsupported file type. Upload a PDF, EPUB, text, Markdown, or HTML file"
+        ))?,
+    };
+    log_extraction(&extracted);
+    if extracted.title.is_empty() || extracted.text.is_empty() {
+        Err(anyhow!("The file has no text"))?;
+    }
+    let ExtractedArticle {
+        title,
+        author,
+        text,
+        ..
+    } = extracted;
     let text_submission = ArticleTextSubmission {
-        title: parsed_res.title,
-        body: parsed_res.text,
+        title,
+        body: text,
+        author,
+        lang: submission.lang,
+        voice: submission.voice,
     };
 
     // Now that we have the article body, call down to add_article_by_text
-    let mut meta = add_article_by_text(&text_submission, tts_rate_limiter, audio_blob_dir).await?;
-    // Add the URL to the metadata
-    meta.source_url = Some(url.to_string());
+    let meta = add_article_by_text(
+        &text_submission,
+        None,
+        tts_rate_limiter,
+        tts_engine,
+        tts_ctx,
+        audio_blob_dir,
+    )
+    .await?;
 
-    Ok(meta)
+    // Save the metadata in the ID3 tags
+    let _ = save_metadata(&meta, audio_blob_dir)
+        .map_err(|e| tracing::error!("Error saving metadata: {e}"));
+
+    Ok(vec![meta])
 }
 
-/// Converts an article to speech and saves to the given file
-async fn tts_to_file(file: &mut File, text: String) -> Result<(), RtmsError> {
-    let api_key = get_api_key().map_err(|e| anyhow!("Failed to get Google API key: {:?}", e))?;
+/// Adds every chapter of the uploaded EPUB as an article, in order, and saves their metadata. The
+/// chapters are linked as a series. Chapters that are already in the library are skipped, so that
+/// a book that failed partway through can be uploaded again to add the rest.
+async fn add_book(
+    submission: FileSubmission,
+    tts_rate_limiter: RateLimiter,
+    tts_engine: SharedTtsEngine,
+    tts_ctx: TtsContext,
+    audio_blob_dir: &str,
+) -> Result<Vec<ArticleMetadata>, RtmsError> {
+    let book = read_epub(&submission.contents)?;
+    let num_chapters = book.chapters.len();
+    tracing::debug!(
+        "Read '{}' by {:?}, with {num_chapters} chapters",
+        book.title,
+        book.author
+    );
 
-    // Use the language detector to pick the TTS voice
-    let voice_name = pick_tts_voice(&text, VoiceQuality::High, VoiceType::HighPitch)?;
+    // The book's language is used if the client didn't give one
+    let lang = submission.lang.or(book.lang);
+    let mut metas = Vec::new();
+    for (i, chapter) in book.chapters.into_iter().enumerate() {
+        let text_submission = ArticleTextSubmission {
+            title: chapter.title,
+            body: chapter.text,
+            author: chapter.author,
+            lang: lang.clone(),
+            voice: submission.voice.clone(),
+        };
+        let id = derive_article_id(&text_submission);
+        if existing_article(audio_blob_dir, &id).is_some() {
+            tracing::info!(
+                "Skipping '{}', which was already added",
+                text_submission.title
+            );
+            continue;
+        }
 
-    // Make the TTS request
-    let req = TtsRequest { text, voice_name };
-    let bytes = tts(&api_key, req)
+        let mut meta = add_article_by_text(
+            &text_submission,
+            None,
+            tts_rate_limiter.clone(),
+            tts_engine.clone(),
+            tts_ctx.clone(),
+            audio_blob_dir,
+        )
         .await
-        .map_err(|e| anyhow!("TTS failed: {:?}", e))?;
+        .map_err(|e| {
+            e.context(format!(
+                "Adding chapter {} of {num_chapters} of '{}' failed",
+                i + 1,
+                book.title
+            ))
+        })?;
+
+        // Link the chapter to the rest of the book, and save the metadata in the ID3 tags
+        meta.series = Some(ArticleSeries {
+            title: book.title.clone(),
+            index: i as u32 + 1,
+            len: num_chapters as u32,
+        });
+        let _ = save_metadata(&meta, audio_blob_dir)
+            .map_err(|e| tracing::error!("Error saving metadata: {e}"));
+        metas.push(meta);
+    }
+
+    Ok(metas)
+}
+
+/// Logs what was extracted from a page
+fn log_extraction(extracted: &ExtractedArticle) {
+    tracing::debug!(
+        "Extracted '{}' by {:?}, published {:?}, {} bytes long",
+        extracted.title,
+        extracted.author,
+        extracted.date,
+        extracted.text.len()
+    );
+}
+
+/// Splits the text into segments and picks the voice to read each one with. If the client chose a
+/// specific voice, it must be one the engine has, and it reads the whole text. Otherwise, every
+/// segment is read by a voice in its language with the client's desired quality and pitch.
+/// Segments in languages the engine can't speak are read in the article's language, `dominant`.
+/// If the client asked for two voices, the quotations are then given to a second voice.
+fn plan_voices(
+    tts_engine: &dyn TtsEngine,
+    text: &str,
+    dominant: Lang,
+    selection: &VoiceSelection,
+) -> Result<Vec<VoicedSegment>, RtmsError> {
+    if let Some(id) = &selection.voice {
+        let voice = tts_engine
+            .list_voices()
+            .into_iter()
+            .find(|v| &v.id == id)
+            .ok_or(anyhow!("Unknown voice '{id}'"))?;
+        let (quality, ty) = (voice.quality, voice.ty);
+        let segment = VoicedSegment {
+            text: text.to_string(),
+            voice_name: voice.id,
+            lang: voice.lang,
+        };
+        return Ok(if selection.dual_voice {
+            voice_quotations(tts_engine, vec![segment], quality, ty.opposite())
+        } else {
+            vec![segment]
+        });
+    }
+
+    let quality = selection.quality.unwrap_or(VoiceQuality::High);
+    let ty = selection.pitch.unwrap_or(VoiceType::HighPitch);
+    let dominant_voice = tts_engine.pick_voice(dominant, quality, ty)?;
+
+    let mut segments: Vec<VoicedSegment> = Vec::new();
+    for (lang, seg_text) in segment_by_language(text, dominant) {
+        let voice_name = tts_engine
+            .pick_voice(lang, quality, ty)
+            .unwrap_or_else(|_| dominant_voice.clone());
+
+        // Two languages might have ended up with the same voice. Merge them if so
+        match segments.last_mut() {
+            Some(last) if last.voice_name == voice_name => {
+                last.text.push('\n');
+                last.text.push_str(&seg_text);
+            }
+            _ => segments.push(VoicedSegment {
+                text: seg_text,
+                voice_name,
+                lang,
+            }),
+        }
+    }
+
+    if selection.dual_voice {
+        segments = voice_quotations(tts_engine, segments, quality, ty.opposite());
+    }
+    Ok(segments)
+}
+
+/// Splits the quotations out of every segment, and gives them a voice in the segment's language
+/// with the given quality and, if possible, type. Segments whose language has no voice besides the
+/// narrator's are left alone.
+fn voice_quotations(
+    tts_engine: &dyn TtsEngine,
+    segments: Vec<VoicedSegment>,
+    quality: VoiceQuality,
+    quote_ty: VoiceType,
+) -> Vec<VoicedSegment> {
+    let mut voiced = Vec::new();
+    for segment in segments {
+        let quote_voice = match tts_engine.pick_voice(segment.lang, quality, quote_ty) {
+            Ok(v) if v != segment.voice_name => v,
+            _ => {
+                voiced.push(segment);
+                continue;
+            }
+        };
+
+        for run in split_quotations(&segment.text) {
+            let voice_name = if run.quoted {
+                quote_voice.clone()
+            } else {
+                segment.voice_name.clone()
+            };
+            voiced.push(VoicedSegment {
+                text: run.text,
+                voice_name,
+                lang: segment.lang,
+            });
+        }
+    }
+
+    voiced
+}
+
+/// Carries out the plan to convert an article to speech, and saves to the given file. Progress is
+/// reported on the given channel. Returns the alignment of the text to the audio, and the start
+/// times of the sections.
+async fn tts_to_file(
+    tts_engine: &dyn TtsEngine,
+    file: &mut File,
+    plan: &TtsPlan,
+    tts_ctx: &TtsContext,
+    progress: &watch::Sender<TtsProgress>,
+) -> Result<(ArticleAlignment, ArticleSections), RtmsError> {
+    // Make the TTS request
+    let output = tts(
+        tts_engine,
+        plan,
+        &tts_ctx.chunk_cache,
+        &tts_ctx.usage_log,
+        tts_ctx.max_concurrent_chunks,
+        tts_ctx.section_cue,
+        progress,
+    )
+    .await
+    .map_err(|e| anyhow!("TTS failed: {:?}", e))?;
 
     // Save the file
-    file.write_all(&bytes)
+    file.write_all(&output.audio)
         .map_err(|e| anyhow!("Save failed: {:?}", e))?;
 
-    Ok(())
+    Ok((output.alignment, output.sections))
+}
+
+/// A mock TTS server, an engine that uses it, and the state adding an article needs, kept in temp
+/// dirs that are deleted when this is dropped
+#[cfg(test)]
+struct MockSetup {
+    server: crate::tts::mock::MockTtsServer,
+    engine: SharedTtsEngine,
+    tts_ctx: TtsContext,
+    audio_blob_dir: tempfile::TempDir,
+    _tts_dir: tempfile::TempDir,
+}
+
+#[cfg(test)]
+impl MockSetup {
+    /// Spawns a mock TTS server, and makes an engine for it that outputs the given format. Articles
+    /// are introduced with the given parts after their title
+    fn new(format: crate::tts::AudioFormat, intro: Vec<IntroPart>) -> Self {
+        use crate::{
+            lang::gcp_voices,
+            tts::{gcp::GcpTts, mock::MockTtsServer},
+        };
+
+        let server = MockTtsServer::spawn(0);
+        let engine = GcpTts::new(
+            "fake-key".to_string(),
+            &server.api_base,
+            gcp_voices(),
+            format,
+        );
+        let tts_dir = tempfile::tempdir().unwrap();
+        let tts_ctx = TtsContext::new(
+            ChunkCache::new(tts_dir.path().join("chunks"), u64::MAX).unwrap(),
+            UsageLog::open(tts_dir.path().join("usage.json")).unwrap(),
+            Normalizer::default(),
+            Lexicon::open(tts_dir.path().join("lexicon.json")).unwrap(),
+            intro,
+            NonZeroUsize::new(4).unwrap(),
+            None,
+        );
+        MockSetup {
+            server,
+            engine: Arc::new(engine.unwrap()),
+            tts_ctx,
+            audio_blob_dir: tempfile::tempdir().unwrap(),
+            _tts_dir: tts_dir,
+        }
+    }
+
+    fn audio_blob_dir(&self) -> &str {
+        self.audio_blob_dir.path().to_str().unwrap()
+    }
+
+    /// Adds the given article with the given rate limit, in characters per minute
+    async fn add(
+        &self,
+        article: &ArticleTextSubmission,
+        max_chars_per_min: u32,
+    ) -> Result<ArticleMetadata, RtmsError> {
+        add_article_by_text(
+            article,
+            None,
+            RateLimiter::new(NonZeroU32::new(max_chars_per_min).unwrap()),
+            self.engine.clone(),
+            self.tts_ctx.clone(),
+            self.audio_blob_dir(),
+        )
+        .await
+    }
+}
+
+/// An article by a known author, in a known language, that fits in one chunk
+#[cfg(test)]
+fn painful_case() -> ArticleTextSubmission {
+    ArticleTextSubmission {
+        title: "A Painful Case".to_string(),
+        body: "Mr James Duffy lived in Chapelizod. ".repeat(50),
+        author: Some("James Joyce".to_string()),
+        lang: Some("en-IE".to_string()),
+        voice: VoiceSelection::default(),
+    }
+}
+
+#[tokio::test]
+async fn add_by_text_with_mock() {
+    let setup = MockSetup::new(Default::default(), vec![IntroPart::Author]);
+    let article = painful_case();
+
+    // Add the article. It should be saved under its ID, and have a nonzero duration
+    let meta = setup.add(&article, 1_000_000).await.unwrap();
+    assert_eq!(meta.id, derive_article_id(&article));
+    assert!(Path::new(setup.audio_blob_dir())
+        .join(format!("{}.mp3", meta.id))
+        .exists());
+    assert!(meta.duration.unwrap() > std::time::Duration::ZERO);
+    assert_eq!(setup.server.num_requests(), 1);
+
+    // Adding the same article again should fail, without making any TTS requests
+    assert!(setup.add(&article, 1_000_000).await.is_err());
+    assert_eq!(setup.server.num_requests(), 1);
+
+    // Now try to add an article that exceeds the rate limit. This should also fail without making
+    // any TTS requests
+    let article = ArticleTextSubmission {
+        title: "Too long".to_string(),
+        body: "a".repeat(200),
+        author: None,
+        lang: Some("en".to_string()),
+        voice: VoiceSelection::default(),
+    };
+    assert!(setup.add(&article, 100).await.is_err());
+    assert_eq!(setup.server.num_requests(), 1);
+}
+
+#[tokio::test]
+async fn add_raw_text_with_mock() {
+    let setup = MockSetup::new(Default::default(), Vec::new());
+
+    // Submitted text that looks like markup is escaped for reading, but the ID is of the text as
+    // submitted
+    let article = || ArticleTextSubmission {
+        title: "Notes".to_string(),
+        body: "# Not a heading\n- Not an item\n> Not a quote".to_string(),
+        author: None,
+        lang: Some("en".to_string()),
+        voice: VoiceSelection::default(),
+    };
+    let id = add_article_by_text_endpoint(
+        Json(article()),
+        Extension(RateLimiter::new(NonZeroU32::new(1_000_000).unwrap())),
+        Extension(setup.engine.clone()),
+        Extension(setup.tts_ctx.clone()),
+        Extension(setup.audio_blob_dir().to_string()),
+    )
+    .await
+    .unwrap();
+    assert_eq!(id, derive_article_id(&article()));
+    assert!(existing_article(setup.audio_blob_dir(), &id).is_some());
+}
+
+#[tokio::test]
+async fn add_saves_alignment_with_mock() {
+    let setup = MockSetup::new(Default::default(), vec![IntroPart::Author]);
+    let meta = setup.add(&painful_case(), 1_000_000).await.unwrap();
+
+    // The alignment is saved next to the audio, with one entry for every sentence, including the
+    // title and the byline
+    let alignment_path =
+        Path::new(setup.audio_blob_dir()).join(format!("{}.alignment.json", meta.id));
+    let alignment: ArticleAlignment =
+        serde_json::from_slice(&fs::read(alignment_path).unwrap()).unwrap();
+    assert_eq!(alignment.0.len(), 52);
+    assert_eq!(alignment.0[0].text, "A Painful Case");
+    assert_eq!(alignment.0[1].text, "By James Joyce.");
+}
+
+#[tokio::test]
+async fn add_with_unknown_voice_with_mock() {
+    let setup = MockSetup::new(Default::default(), Vec::new());
+
+    // Asking for a voice the engine doesn't have should fail, without making any requests
+    let article = ArticleTextSubmission {
+        title: "Eveline".to_string(),
+        body: "She sat at the window watching the evening invade the avenue.".to_string(),
+        author: None,
+        lang: None,
+        voice: VoiceSelection {
+            voice: Some("en-US-Imaginary-Z".to_string()),
+            ..Default::default()
+        },
+    };
+    assert!(setup.add(&article, 1_000_000).await.is_err());
+    assert_eq!(setup.server.num_requests(), 0);
+}
+
+#[tokio::test]
+async fn add_progress_with_mock() {
+    let setup = MockSetup::new(Default::default(), Vec::new());
+
+    // The article is no longer in progress once it's added, or once adding it fails
+    setup.add(&painful_case(), 1_000_000).await.unwrap();
+    assert!(setup.tts_ctx.list().is_empty());
+    assert!(setup.add(&painful_case(), 1_000_000).await.is_err());
+    assert!(setup.tts_ctx.list().is_empty());
+}
+
+#[tokio::test]
+async fn add_cached_article_with_mock() {
+    let setup = MockSetup::new(Default::default(), Vec::new());
+    let meta = setup.add(&painful_case(), 1_000_000).await.unwrap();
+    assert_eq!(setup.server.num_requests(), 1);
+
+    // Delete the article and add it again. Its chunks are cached, so this makes no TTS requests,
+    // and isn't held to the rate limit
+    fs::remove_file(Path::new(setup.audio_blob_dir()).join(format!("{}.mp3", meta.id))).unwrap();
+    setup.add(&painful_case(), 1).await.unwrap();
+    assert_eq!(setup.server.num_requests(), 1);
+}
+
+#[tokio::test]
+async fn add_article_language_with_mock() {
+    use crate::util::get_metadata;
+
+    let setup = MockSetup::new(Default::default(), Vec::new());
+
+    // The given language is recorded. It wasn't detected, so there's no confidence. Both survive
+    // saving and reading the metadata
+    let meta = setup.add(&painful_case(), 1_000_000).await.unwrap();
+    assert_eq!(meta.lang.as_deref(), Some("eng"));
+    assert_eq!(meta.lang_confidence, None);
+    save_metadata(&meta, setup.audio_blob_dir()).unwrap();
+    let entry = fs::read_dir(setup.audio_blob_dir())
+        .unwrap()
+        .map(Result::unwrap)
+        .find(|e| e.path().extension().unwrap() == "mp3")
+        .unwrap();
+    let read_meta = get_metadata(&entry).unwrap();
+    assert_eq!(read_meta.lang, meta.lang);
+    assert_eq!(read_meta.lang_confidence, meta.lang_confidence);
+
+    // An article whose language can't be told should fail, and ask for the language, rather than
+    // be read in some guessed language. No requests are made
+    let article = ArticleTextSubmission {
+        title: "Counting".to_string(),
+        body: "1, 2, 3, 4, 5. OK.".to_string(),
+        author: None,
+        lang: None,
+        voice: VoiceSelection::default(),
+    };
+    let err = setup.add(&article, 1_000_000).await.unwrap_err();
+    assert!(format!("{err:?}").contains("Set the article's language"));
+    assert_eq!(setup.server.num_requests(), 1);
+}
+
+#[tokio::test]
+async fn add_opus_article_with_mock() {
+    use crate::{
+        lang::gcp_voices,
+        tts::{gcp::GcpTts, AudioCodec, AudioFormat},
+        util::get_metadata,
+    };
+
+    let format = AudioFormat {
+        codec: AudioCodec::OggOpus,
+        bitrate_kbps: 32,
+    };
+    let setup = MockSetup::new(format, vec![IntroPart::Author]);
+    let audio_blob_dir = setup.audio_blob_dir();
+
+    // Make an article that takes a few chunks to synthesize
+    let article = ArticleTextSubmission {
+        title: "The Dead".to_string(),
+        body: "Lily, the caretaker's daughter, was literally run off her feet.\n".repeat(200),
+        author: None,
+        lang: None,
+        voice: VoiceSelection::default(),
+    };
+    let mut meta = setup.add(&article, 1_000_000).await.unwrap();
+    assert!(setup.server.num_requests() > 1);
+    assert_eq!(meta.codec, AudioCodec::OggOpus);
+    assert!(meta.duration.unwrap() > std::time::Duration::ZERO);
+    // The language was detected, and the detector was sure of it
+    assert_eq!(meta.lang.as_deref(), Some("eng"));
+    assert!(meta.lang_confidence.unwrap() > 0.5);
+
+    // Save the metadata and read it back. It should be the same, and be listed as Opus
+    meta.source_url = Some("https://example.com/dubliners".to_string());
+    meta.series = Some(ArticleSeries {
+        title: "Dubliners".to_string(),
+        index: 15,
+        len: 15,
+    });
+    save_metadata(&meta, audio_blob_dir).unwrap();
+    let entry = fs::read_dir(audio_blob_dir)
+        .unwrap()
+        .map(Result::unwrap)
+        .find(|e| e.path().extension().unwrap() == "ogg")
+        .unwrap();
+    let read_meta = get_metadata(&entry).unwrap();
+    assert_eq!(read_meta.id, meta.id);
+    assert_eq!(read_meta.title, meta.title);
+    assert_eq!(read_meta.source_url, meta.source_url);
+    assert_eq!(read_meta.datetime_added, meta.datetime_added);
+    assert_eq!(read_meta.duration, meta.duration);
+    assert_eq!(read_meta.lang.as_deref(), Some("eng"));
+    assert_eq!(read_meta.lang_confidence, meta.lang_confidence);
+    assert_eq!(read_meta.codec, AudioCodec::OggOpus);
+    assert_eq!(read_meta.series, meta.series);
+
+    // The article can't be added again, even as MP3
+    let mp3_engine = GcpTts::new(
+        "fake-key".to_string(),
+        &setup.server.api_base,
+        gcp_voices(),
+        AudioFormat::default(),
+    );
+    let readded = add_article_by_text(
+        &article,
+        None,
+        RateLimiter::new(NonZeroU32::new(1_000_000).unwrap()),
+        Arc::new(mp3_engine.unwrap()),
+        setup.tts_ctx.clone(),
+        audio_blob_dir,
+    )
+    .await;
+    assert!(readded.is_err());
+}
+
+#[test]
+fn dual_voice_planning() {
+    use crate::{
+        lang::gcp_voices,
+        tts::{gcp::GcpTts, AudioFormat},
+    };
+
+    let engine = GcpTts::new(
+        "fake-key".to_string(),
+        "http://localhost",
+        gcp_voices(),
+        AudioFormat::default(),
+    )
+    .unwrap();
+    let text = "# Interview\n\
+        She said, \"We will keep going until it's done\", and left.\n\
+        > A block quote, read by the second voice.";
+
+    // With one voice, the narrator reads everything
+    let segments = plan_voices(&engine, text, Lang::Eng, &VoiceSelection::default()).unwrap();
+    assert_eq!(segments.len(), 1);
+
+    // With two, the quotations go to a voice of the opposite pitch, in order
+    let selection = VoiceSelection {
+        dual_voice: true,
+        ..Default::default()
+    };
+    let segments = plan_voices(&engine, text, Lang::Eng, &selection).unwrap();
+    let voice_names: Vec<&str> = segments.iter().map(|s| s.voice_name.as_str()).collect();
+    assert_eq!(
+        voice_names,
+        [
+            "en-US-Wavenet-C",
+            "en-US-Wavenet-B",
+            "en-US-Wavenet-C",
+            "en-US-Wavenet-B"
+        ]
+    );
+    assert_eq!(segments[1].text, "\"We will keep going until it's done\"");
+    assert_eq!(
+        segments.iter().map(|s| s.text.as_str()).collect::<String>(),
+        text
+    );
+
+    // A chosen narrator gets a second voice in its own language and quality
+    let selection = VoiceSelection {
+        voice: Some("en-GB-Standard-B".to_string()),
+        dual_voice: true,
+        ..Default::default()
+    };
+    let segments = plan_voices(&engine, text, Lang::Eng, &selection).unwrap();
+    assert_eq!(segments.len(), 4);
+    let voices = engine.list_voices();
+    let quote_voice = voices
+        .iter()
+        .find(|v| v.id == segments[1].voice_name)
+        .unwrap();
+    assert_eq!(quote_voice.lang, Lang::Eng);
+    assert_eq!(quote_voice.quality, VoiceQuality::Standard);
+    assert_eq!(quote_voice.ty, VoiceType::HighPitch);
+}
+
+#[tokio::test]
+async fn add_book_with_mock() {
+    use crate::{extract::readability::Readability, util::get_metadata};
+    use std::io::{Cursor, Write};
+    use zip::{write::FileOptions, ZipWriter};
+
+    let setup = MockSetup::new(Default::default(), Vec::new());
+    let (server, audio_blob_dir) = (&setup.server, setup.audio_blob_dir());
+
+    // Make a two-chapter EPUB. The mimetype comes first, uncompressed, as in a real one
+    let opf = r#"<package xmlns="http://www.idpf.org/2007/opf" version="3.0">
+          <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+            <dc:title>Dubliners</dc:title><dc:language>en</dc:language>
+          </metadata>
+          <manifest>
+            <item id="c1" href="sisters.xhtml" media-type="application/xhtml+xml"/>
+            <item id="c2" href="encounter.xhtml" media-type="application/xhtml+xml"/>
+          </manifest>
+          <spine><itemref idref="c1"/><itemref idref="c2"/></spine>
+        </package>"#;
+    let files = [
+        ("mimetype", "application/epub+zip".to_string()),
+        (
+            "META-INF/container.xml",
+            r#"<container><rootfiles><rootfile full-path="content.opf"/></rootfiles></container>"#
+                .to_string(),
+        ),
+        ("content.opf", opf.to_string()),
+        (
+            "sisters.xhtml",
+            "<html><body><h1>The Sisters</h1><p>There was no hope for him this time.</p></body></html>"
+                .to_string(),
+        ),
+        (
+            "encounter.xhtml",
+            "<html><body><h1>An Encounter</h1><p>It was Joe Dillon who introduced the Wild West to us.</p></body></html>"
+                .to_string(),
+        ),
+    ];
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for (path, contents) in files {
+        let options = FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        zip.start_file(path, options).unwrap();
+        zip.write_all(contents.as_bytes()).unwrap();
+    }
+    let epub = zip.finish().unwrap().into_inner();
+    let submission = || FileSubmission {
+        file_name: None,
+        content_type: None,
+        contents: Bytes::from(epub.clone()),
+        lang: None,
+        voice: VoiceSelection::default(),
+    };
+    assert_eq!(submission().kind(), Some(FileKind::Epub));
+
+    // Every chapter becomes an article, linked to the others as a series
+    let rate_limiter = RateLimiter::new(NonZeroU32::new(1_000_000).unwrap());
+    let extractors = Extractors::new(vec![Box::new(Readability)]);
+    let metas = add_article_by_file(
+        submission(),
+        &extractors,
+        rate_limiter.clone(),
+        setup.engine.clone(),
+        setup.tts_ctx.clone(),
+        audio_blob_dir,
+    )
+    .await
+    .unwrap();
+    let titles: Vec<&str> = metas.iter().map(|m| m.title.as_str()).collect();
+    assert_eq!(
+        titles,
+        [
+            "Dubliners — Chapter 1: The Sisters",
+            "Dubliners — Chapter 2: An Encounter"
+        ]
+    );
+    let mut read_metas: Vec<ArticleMetadata> = fs::read_dir(audio_blob_dir)
+        .unwrap()
+        .map(Result::unwrap)
+        .filter(|e| e.path().extension().unwrap() == "mp3")
+        .map(|e| get_metadata(&e).unwrap())
+        .collect();
+    assert_eq!(read_metas.len(), 2);
+    read_metas.sort_by_key(|m| m.series.as_ref().unwrap().index);
+    for (i, meta) in read_metas.iter().enumerate() {
+        let series = meta.series.as_ref().unwrap();
+        assert_eq!(series.title, "Dubliners");
+        assert_eq!((series.index, series.len), (i as u32 + 1, 2));
+    }
+
+    // Uploading the book again skips the chapters that are already there
+    let num_requests = server.num_requests();
+    let metas = add_article_by_file(
+        submission(),
+        &extractors,
+        rate_limiter,
+        setup.engine.clone(),
+        setup.tts_ctx.clone(),
+        audio_blob_dir,
+    )
+    .await
+    .unwrap();
+    assert!(metas.is_empty());
+    assert_eq!(server.num_requests(), num_requests);
+}
+
+#[test]
+fn file_kinds() {
+    let kind = |file_name: Option<&str>, content_type: Option<&str>, contents: &'static [u8]| {
+        FileSubmission {
+            file_name: file_name.map(str::to_string),
+            content_type: content_type.map(str::to_string),
+            contents: Bytes::from_static(contents),
+            lang: None,
+            voice: VoiceSelection::default(),
+        }
+        .kind()
+    };
+
+    // PDFs are told by their contents, whatever they're called
+    assert_eq!(
+        kind(Some("report.bin"), None, b"%PDF-1.7\n"),
+        Some(FileKind::Pdf)
+    );
+    // Text files are told by their extension first, since browsers send Markdown as anything
+    assert_eq!(
+        kind(
+            Some("notes.md"),
+            Some("application/octet-stream"),
+            b"# Notes"
+        ),
+        Some(FileKind::Markdown)
+    );
+    assert_eq!(
+        kind(Some("notes.TXT"), None, b"Notes"),
+        Some(FileKind::Text)
+    );
+    assert_eq!(
+        kind(None, Some("text/html; charset=utf-8"), b"<html></html>"),
+        Some(FileKind::Html)
+    );
+    assert_eq!(
+        kind(Some("photo.jpg"), Some("image/jpeg"), b"\xff\xd8"),
+        None
+    );
+}
+
+#[tokio::test]
+async fn upload_size_limit() {
+    use axum::{
+        body::Body,
+        extract::{FromRequest, RequestParts},
+        http::Request,
+    };
+
+    // Reads a multipart form with the given file and language
+    async fn read(
+        contents: &str,
+        lang: &str,
+        max_size: usize,
+    ) -> Result<FileSubmission, RtmsError> {
+        let body = format!(
+            "--X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+             Content-Type: text/plain\r\n\r\n{contents}\r\n\
+             --X\r\nContent-Disposition: form-data; name=\"lang\"\r\n\r\n{lang}\r\n--X--\r\n"
+        );
+        let req = Request::post("/")
+            .header("content-type", "multipart/form-data; boundary=X")
+            .body(Body::from(body))
+            .unwrap();
+        let multipart = Multipart::from_request(&mut RequestParts::new(req))
+            .await
+            .unwrap();
+        FileSubmission::read(multipart, max_size).await
+    }
+
+    let submission = read("Hello", "en", 5).await.unwrap();
+    assert_eq!(&submission.contents[..], b"Hello");
+    assert_eq!(submission.lang.as_deref(), Some("en"));
+
+    let err = read("Hello!", "en", 5).await.err().unwrap();
+    assert_eq!(
+        format!("{err:?}"),
+        "RtmsError(Couldn't read uploaded file: it's over the 5 byte limit)"
+    );
+
+    let long_lang = "x".repeat(MAX_UPLOAD_OPTION_SIZE + 1);
+    assert!(read("Hello", &long_lang, 5).await.is_err());
 }
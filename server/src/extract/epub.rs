@@ -0,0 +1,346 @@
+//! Reads the chapters of an EPUB. The book's package document (the OPF) lists its files, and its
+//! spine gives the order they're read in. Every XHTML file in the spine with any text in it is a
+//! chapter, except for the table of contents and the files the book marks as not part of the main
+//! reading order. Chapters are titled "Book — Chapter N: Heading", after their first heading.
+
+use crate::extract::{
+    readability::{body_text, clean, selector, text_of},
+    ExtractedArticle,
+};
+
+use std::{
+    collections::HashMap,
+    io::{Cursor, Read},
+    sync::LazyLock,
+};
+
+use anyhow::{anyhow, bail, Error as AnyError};
+use quick_xml::events::Event;
+use regex::Regex;
+use scraper::Html;
+use zip::ZipArchive;
+
+/// Where the EPUB says its package document is
+const CONTAINER_PATH: &str = "META-INF/container.xml";
+
+/// The most bytes decompressed from any one file in the archive. Chapters are nowhere near this,
+/// and it keeps a small, highly compressed upload from filling the server's memory
+const MAX_ENTRY_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The MIME type of chapters
+const XHTML_TYPE: &str = "application/xhtml+xml";
+
+/// A chapter number at the start of a heading, like "Chapter 12." or "CHAPTER IV:". It's left out
+/// of chapter titles, which have their own number
+static CHAPTER_NUMBER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)^(chapter|chap\.)\s+\S+?[.:—–-]*(\s+|$)").unwrap());
+
+/// A book read from an EPUB
+pub(crate) struct Book {
+    pub(crate) title: String,
+    pub(crate) author: Option<String>,
+    /// The language given in the book's metadata. This is usually a BCP-47 tag
+    pub(crate) lang: Option<String>,
+    /// The chapters, in reading order. They have the book's author and date
+    pub(crate) chapters: Vec<ExtractedArticle>,
+}
+
+/// An element of an XML document
+struct XmlElement {
+    /// The name of the element, without its namespace prefix
+    name: String,
+    /// The attributes of the element, without their namespace prefixes
+    attrs: HashMap<String, String>,
+    /// The text directly inside the element
+    text: String,
+}
+
+/// Reads every element of the XML document, in order
+fn xml_elements(xml: &str) -> Result<Vec<XmlElement>, AnyError> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut elements: Vec<XmlElement> = Vec::new();
+    // The indices of the elements we're inside of
+    let mut open: Vec<usize> = Vec::new();
+    loop {
+        let (e, is_empty) = match reader.read_event()? {
+            Event::Start(e) => (e, false),
+            Event::Empty(e) => (e, true),
+            Event::End(_) => {
+                open.pop();
+                continue;
+            }
+            Event::Text(t) => {
+                if let Some(&i) = open.last() {
+                    elements[i].text.push_str(&t.unescape()?);
+                }
+                continue;
+            }
+            Event::CData(t) => {
+                if let Some(&i) = open.last() {
+                    elements[i].text.push_str(&String::from_utf8_lossy(&t));
+                }
+                continue;
+            }
+            Event::Eof => break,
+            _ => continue,
+        };
+
+        let mut attrs = HashMap::new();
+        for attr in e.attributes() {
+            let attr = attr?;
+            let key = String::from_utf8_lossy(attr.key.local_name().as_ref()).to_string();
+            attrs.insert(key, attr.unescape_value()?.to_string());
+        }
+        elements.push(XmlElement {
+            name: String::from_utf8_lossy(e.local_name().as_ref()).to_string(),
+            attrs,
+            text: String::new(),
+        });
+        if !is_empty {
+            open.push(elements.len() - 1);
+        }
+    }
+    Ok(elements)
+}
+
+/// Reads the file at the given path in the archive
+fn read_file(zip: &mut ZipArchive<Cursor<&[u8]>>, path: &str) -> Result<String, AnyError> {
+    let file = zip
+        .by_name(path)
+        .map_err(|e| anyhow!("EPUB is missing {path}: {e}"))?;
+    let mut bytes = Vec::new();
+    file.take(MAX_ENTRY_SIZE + 1).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 > MAX_ENTRY_SIZE {
+        bail!(
+            "EPUB file {path} is over the {} MB limit",
+            MAX_ENTRY_SIZE / 1024 / 1024
+        );
+    }
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Resolves a link in the file at `base` to a path in the archive. Links are percent-encoded, and
+/// may have `..` in them
+fn resolve_href(base: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or_default();
+    let href = urlencoding::decode(href).map_or(href.to_string(), |h| h.into_owned());
+    let mut parts: Vec<&str> = base.split('/').collect();
+    // Links are relative to the directory the file is in
+    parts.pop();
+    for part in href.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// Makes the title of the `n`th chapter of the book, from its heading, if it has one
+fn chapter_title(book_title: &str, n: usize, heading: Option<&str>) -> String {
+    let name = heading
+        .map(|h| {
+            CHAPTER_NUMBER
+                .replace(h, "")
+                .trim_end_matches('.')
+                .trim()
+                .to_string()
+        })
+        .filter(|name| !name.is_empty());
+    match name {
+        Some(name) => format!("{book_title} — Chapter {n}: {name}"),
+        None => format!("{book_title} — Chapter {n}"),
+    }
+}
+
+/// Reads the book in the given EPUB. Fails if it isn't an EPUB, or has no chapters.
+pub(crate) fn read_epub(epub: &[u8]) -> Result<Book, AnyError> {
+    let mut zip = ZipArchive::new(Cursor::new(epub)).map_err(|e| anyhow!("Not an EPUB: {e}"))?;
+
+    // Find the package document
+    let container = xml_elements(&read_file(&mut zip, CONTAINER_PATH)?)?;
+    let opf_path = container
+        .iter()
+        .find(|e| e.name == "rootfile")
+        .and_then(|e| e.attrs.get("full-path"))
+        .ok_or(anyhow!("EPUB has no package document"))?
+        .clone();
+    let opf = xml_elements(&read_file(&mut zip, &opf_path)?)?;
+
+    // Read the book's metadata. Only the first title, author, and language count
+    let first_text = |name: &str| {
+        opf.iter()
+            .find(|e| e.name == name)
+            .map(|e| clean(&e.text))
+            .filter(|t| !t.is_empty())
+    };
+    let title = first_text("title").ok_or(anyhow!("EPUB has no title"))?;
+    let author = first_text("creator");
+    let lang = first_text("language");
+    let date = first_text("date")
+        .and_then(|d| d.get(..10).map(str::to_string))
+        .filter(|d| d.as_bytes()[4] == b'-' && d.as_bytes()[7] == b'-');
+
+    // The manifest maps the IDs in the spine to files
+    let manifest: HashMap<&str, &XmlElement> = opf
+        .iter()
+        .filter(|e| e.name == "item")
+        .filter_map(|e| Some((e.attrs.get("id")?.as_str(), e)))
+        .collect();
+
+    let mut chapters = Vec::new();
+    for itemref in opf.iter().filter(|e| e.name == "itemref") {
+        if itemref.attrs.get("linear").map(String::as_str) == Some("no") {
+            continue;
+        }
+        let item = match itemref
+            .attrs
+            .get("idref")
+            .and_then(|id| manifest.get(id.as_str()))
+        {
+            Some(item) => item,
+            None => continue,
+        };
+        let is_nav = item
+            .attrs
+            .get("properties")
+            .is_some_and(|p| p.split_whitespace().any(|p| p == "nav"));
+        if is_nav || item.attrs.get("media-type").map(String::as_str) != Some(XHTML_TYPE) {
+            continue;
+        }
+        let href = match item.attrs.get("href") {
+            Some(href) => resolve_href(&opf_path, href),
+            None => continue,
+        };
+
+        // Title the chapter after its first heading, and leave the heading out of its text
+        let doc = Html::parse_document(&read_file(&mut zip, &href)?);
+        let heading = doc
+            .select(&selector("h1, h2, h3"))
+            .map(text_of)
+            .find(|h| !h.is_empty());
+        let text = body_text(&doc, heading.as_deref().unwrap_or_default());
+        // Pages with nothing but a heading, or an image, like the cover, aren't chapters
+        if text.is_empty() {
+            continue;
+        }
+
+        chapters.push(ExtractedArticle {
+            title: chapter_title(&title, chapters.len() + 1, heading.as_deref()),
+            author: author.clone(),
+            date: date.clone(),
+            text,
+        });
+    }
+
+    if chapters.is_empty() {
+        bail!("EPUB has no chapters");
+    }
+    Ok(Book {
+        title,
+        author,
+        lang,
+        chapters,
+    })
+}
+
+#[test]
+fn epub_reading() {
+    use std::io::Write;
+    use zip::{write::FileOptions, ZipWriter};
+
+    let container = r#"<?xml version="1.0"?>
+        <container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+          <rootfiles>
+            <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+          </rootfiles>
+        </container>"#;
+    let opf = r#"<?xml version="1.0"?>
+        <package xmlns="http://www.idpf.org/2007/opf" version="3.0">
+          <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+            <dc:title>The Lighthouse Keeper</dc:title>
+            <dc:creator>Grace Darling</dc:creator>
+            <dc:language>en-GB</dc:language>
+            <dc:date>1838-09-07</dc:date>
+          </metadata>
+          <manifest>
+            <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+            <item id="cover" href="text/cover.xhtml" media-type="application/xhtml+xml"/>
+            <item id="c1" href="text/chapter%201.xhtml" media-type="application/xhtml+xml"/>
+            <item id="c2" href="text/chapter2.xhtml" media-type="application/xhtml+xml"/>
+            <item id="notes" href="text/notes.xhtml" media-type="application/xhtml+xml"/>
+            <item id="css" href="style.css" media-type="text/css"/>
+          </manifest>
+          <spine>
+            <itemref idref="nav"/>
+            <itemref idref="cover"/>
+            <itemref idref="c1"/>
+            <itemref idref="notes" linear="no"/>
+            <itemref idref="c2"/>
+          </spine>
+        </package>"#;
+    let page =
+        |body: &str| format!("<html><head><title>x</title></head><body>{body}</body></html>");
+    let files = [
+        ("META-INF/container.xml", container.to_string()),
+        ("OEBPS/content.opf", opf.to_string()),
+        (
+            "OEBPS/nav.xhtml",
+            page("<nav><ol><li>Chapter 1</li></ol></nav>"),
+        ),
+        ("OEBPS/text/cover.xhtml", page("<img src='cover.jpg'/>")),
+        (
+            "OEBPS/text/chapter 1.xhtml",
+            page("<h1>Chapter 1. The Storm</h1><p>The wind rose in the <em>night</em>.</p>"),
+        ),
+        ("OEBPS/text/notes.xhtml", page("<p>A note.</p>")),
+        (
+            "OEBPS/text/chapter2.xhtml",
+            page("<h2>CHAPTER II</h2><p>The boat set out at dawn.</p>"),
+        ),
+    ];
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for (path, contents) in files {
+        zip.start_file(path, FileOptions::default()).unwrap();
+        zip.write_all(contents.as_bytes()).unwrap();
+    }
+    let epub = zip.finish().unwrap().into_inner();
+
+    let book = read_epub(&epub).unwrap();
+    assert_eq!(book.title, "The Lighthouse Keeper");
+    assert_eq!(book.author.as_deref(), Some("Grace Darling"));
+    assert_eq!(book.lang.as_deref(), Some("en-GB"));
+    let titles: Vec<&str> = book.chapters.iter().map(|c| c.title.as_str()).collect();
+    assert_eq!(
+        titles,
+        [
+            "The Lighthouse Keeper — Chapter 1: The Storm",
+            "The Lighthouse Keeper — Chapter 2"
+        ]
+    );
+    assert_eq!(book.chapters[0].text, "The wind rose in the *night*.");
+    assert_eq!(book.chapters[1].date.as_deref(), Some("1838-09-07"));
+
+    assert!(read_epub(b"%PDF-1.4").is_err());
+}
+
+#[test]
+fn epub_entry_limit() {
+    use std::io::Write;
+    use zip::{write::FileOptions, ZipWriter};
+
+    // Compresses to a few kilobytes, but would decompress to more than the limit
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    zip.start_file(CONTAINER_PATH, FileOptions::default())
+        .unwrap();
+    zip.write_all(&vec![b' '; MAX_ENTRY_SIZE as usize + 1])
+        .unwrap();
+    let epub = zip.finish().unwrap().into_inner();
+    assert!(epub.len() < 1024 * 1024);
+
+    let err = read_epub(&epub).err().unwrap().to_string();
+    assert!(err.contains("over the 16 MB limit"), "{err}");
+}
@@ -0,0 +1,188 @@
+//! Defines the interface that article extractors implement. An extractor takes a web page and
+//! finds the article in it. There's our own readability-style extractor, and trafilatura, if it's
+//! installed. The server tries the configured extractors in order, until one finds the article.
+//! URLs that point at a PDF skip the extractors, and have the PDF's text read instead.
+
+pub(crate) mod epub;
+pub(crate) mod pdf;
+pub(crate) mod readability;
+pub(crate) mod text_file;
+pub(crate) mod trafilatura;
+
+use core::time::Duration;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Error as AnyError};
+use clap::ValueEnum;
+use futures::future::BoxFuture;
+use reqwest::header::CONTENT_TYPE;
+use serde::Deserialize;
+
+/// The user agent we fetch pages with
+const USER_AGENT: &str = concat!("readtomyshoe/", env!("CARGO_PKG_VERSION"));
+
+/// How long to wait for a page before giving up
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The page every extractor is tested on at startup, and what should be found in it
+const TEST_PAGE: &str = include_str!("../../assets/extractor_test.html");
+const TEST_PAGE_TITLE: &str = "How Lighthouses Work";
+const TEST_PAGE_PHRASE: &str = "Augustin Fresnel designed a lens";
+
+/// The article extractors this server knows how to use
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub(crate) enum ExtractorKind {
+    /// The built-in readability-style extractor
+    Readability,
+    /// trafilatura, the Python extractor. Requires a trafilatura install
+    Trafilatura,
+}
+
+/// An article extracted from a web page. This is a portion of trafilatura's JSON output. The rest
+/// of its fields are: hostname, categories, tags, fingerprint, id, license, comments, raw_text,
+/// source, source_hostname, excerpt
+#[derive(Debug, Deserialize)]
+pub(crate) struct ExtractedArticle {
+    pub(crate) title: String,
+    #[serde(default)]
+    pub(crate) author: Option<String>,
+    /// The date the article was published, as YYYY-MM-DD
+    #[serde(default)]
+    pub(crate) date: Option<String>,
+    /// The body of the article, in the Markdown-like format described in `tts::ssml`
+    pub(crate) text: String,
+}
+
+/// Something that finds the article in a web page
+pub(crate) trait Extractor: Send + Sync {
+    /// The name of the extractor, for logging
+    fn name(&self) -> &str;
+
+    /// Extracts the article from the given page. Fails if the page doesn't seem to have one.
+    fn extract_html<'a>(
+        &'a self,
+        html: &'a str,
+    ) -> BoxFuture<'a, Result<ExtractedArticle, AnyError>>;
+}
+
+/// A document downloaded from a URL
+enum Fetched {
+    Html(String),
+    Pdf(Vec<u8>),
+}
+
+/// Downloads the document at the given URL. It's a PDF if the server says so, or if it starts like
+/// one. Servers often send PDFs as `application/octet-stream`.
+async fn fetch(url: &str) -> Result<Fetched, AnyError> {
+    let client = reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .timeout(FETCH_TIMEOUT)
+        .build()?;
+    let resp = client.get(url).send().await?.error_for_status()?;
+
+    let content_type = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_lowercase();
+    if content_type.starts_with("text/") || content_type.contains("html") {
+        // Let reqwest decode the page with the charset it was sent with
+        return Ok(Fetched::Html(resp.text().await?));
+    }
+
+    let body = resp.bytes().await?;
+    if content_type.starts_with("application/pdf") || body.starts_with(b"%PDF-") {
+        Ok(Fetched::Pdf(body.to_vec()))
+    } else {
+        Ok(Fetched::Html(String::from_utf8_lossy(&body).into_owned()))
+    }
+}
+
+/// The extractors the server uses, in the order they're tried. This is shared between all the
+/// request handlers.
+#[derive(Clone)]
+pub(crate) struct Extractors(Arc<Vec<Box<dyn Extractor>>>);
+
+impl Extractors {
+    pub(crate) fn new(extractors: Vec<Box<dyn Extractor>>) -> Self {
+        Extractors(Arc::new(extractors))
+    }
+
+    /// Fetches the page at the given URL and extracts its article, with the first extractor that
+    /// can. If the URL points at a PDF, its text is extracted instead.
+    pub(crate) async fn extract_url(&self, url: &str) -> Result<ExtractedArticle, AnyError> {
+        match fetch(url).await? {
+            Fetched::Html(html) => self.extract_html(&html).await,
+            Fetched::Pdf(pdf) => {
+                tracing::debug!("{url} is a PDF");
+                pdf::extract_pdf(pdf).await
+            }
+        }
+    }
+
+    /// Extracts the article from the given page, with the first extractor that can
+    pub(crate) async fn extract_html(&self, html: &str) -> Result<ExtractedArticle, AnyError> {
+        for extractor in self.0.iter() {
+            match extractor.extract_html(html).await {
+                Ok(article) => return Ok(article),
+                Err(e) => tracing::info!("{} couldn't extract page: {e:#}", extractor.name()),
+            }
+        }
+        bail!("Text extraction failed")
+    }
+
+    /// Runs every extractor on a test page, and logs an error for each one that doesn't find the
+    /// article. Fails if none of them do, since the server couldn't add articles by URL.
+    pub(crate) async fn self_test(&self) -> Result<(), AnyError> {
+        let mut num_working = 0;
+        for extractor in self.0.iter() {
+            let checked = extractor.extract_html(TEST_PAGE).await.and_then(|article| {
+                if article.title != TEST_PAGE_TITLE || !article.text.contains(TEST_PAGE_PHRASE) {
+                    bail!("found the wrong article, titled {:?}", article.title);
+                }
+                Ok(())
+            });
+            match checked {
+                Ok(()) => {
+                    tracing::info!("Extractor {} works", extractor.name());
+                    num_working += 1;
+                }
+                Err(e) => tracing::error!("Extractor {} doesn't work: {e:#}", extractor.name()),
+            }
+        }
+
+        if num_working == 0 {
+            Err(anyhow!(
+                "None of the {} article extractors work",
+                self.0.len()
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[tokio::test]
+async fn extractor_self_test() {
+    use crate::extract::{readability::Readability, trafilatura::Trafilatura};
+
+    // A trafilatura that isn't installed fails the test, but doesn't stop the extractors after it
+    // from working
+    let missing = || {
+        Box::new(Trafilatura::new(
+            "/nonexistent/trafilatura".into(),
+            Vec::new(),
+        )) as Box<dyn Extractor>
+    };
+    let extractors = Extractors::new(vec![missing(), Box::new(Readability)]);
+    extractors.self_test().await.unwrap();
+    let article = extractors.extract_html(TEST_PAGE).await.unwrap();
+    assert_eq!(article.title, TEST_PAGE_TITLE);
+    assert_eq!(article.author.as_deref(), Some("Ada Lovelace"));
+
+    // With no working extractor, the test fails, and so does extraction
+    let extractors = Extractors::new(vec![missing()]);
+    assert!(extractors.self_test().await.is_err());
+    assert!(extractors.extract_html(TEST_PAGE).await.is_err());
+}
@@ -0,0 +1,308 @@
+//! Extracts the article from a PDF's text layer. The text of every page is read, and the lines
+//! that aren't part of the article are dropped: running headers and footers, which are the lines
+//! at the top or bottom of most pages, and page numbers. Lines are then joined back into
+//! paragraphs, undoing the hyphenation of words broken across lines. The title, author, and
+//! creation date come from the document's metadata.
+
+use crate::extract::ExtractedArticle;
+
+use std::{collections::HashMap, sync::LazyLock};
+
+use anyhow::{anyhow, bail, Error as AnyError};
+use pdf_extract::{decode_text_string, Document, Object};
+use regex::Regex;
+
+/// How many lines at the top and bottom of a page can be running headers or footers
+const EDGE_LINES: usize = 2;
+
+/// A line is a running header or footer if it's at the edge of at least this fraction of the
+/// pages
+const MIN_RUNNING_FRACTION: f64 = 0.5;
+
+/// Titles shorter than this many characters aren't trusted. Some tools set a placeholder title,
+/// like "untitled" or a single letter
+const MIN_TITLE_LEN: usize = 3;
+
+/// Page numbers, alone or with a total: "12", "- 12 -", "Page 12", "12 of 30", "xiv"
+static PAGE_NUMBER: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)^[-–—\s]*(page\s+)?(\d+|[ivxlc]+)(\s*(/|of)\s*\d+)?[-–—\s]*$").unwrap()
+});
+
+/// Runs of digits, which are replaced when comparing headers and footers, since they often contain
+/// the page number
+static DIGITS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\d+").unwrap());
+
+/// Extracts the article from the given PDF. Fails if the PDF can't be parsed, or has no text layer,
+/// like a scanned document.
+pub(crate) async fn extract_pdf(pdf: Vec<u8>) -> Result<ExtractedArticle, AnyError> {
+    // Parsing is CPU-bound, and the PDF library panics on some malformed files. Run it on the
+    // blocking pool, so it neither stalls the server nor takes it down
+    tokio::task::spawn_blocking(move || extract(&pdf))
+        .await
+        .map_err(|e| anyhow!("PDF parsing failed: {e}"))?
+}
+
+fn extract(pdf: &[u8]) -> Result<ExtractedArticle, AnyError> {
+    let doc = Document::load_mem(pdf).map_err(|e| anyhow!("Couldn't read PDF: {e}"))?;
+    let pages = pdf_extract::extract_text_from_mem_by_pages(pdf)
+        .map_err(|e| anyhow!("Couldn't extract PDF text: {e}"))?;
+
+    let text = clean_pages(&pages);
+    if text.is_empty() {
+        bail!("The PDF has no text. Scanned documents aren't supported");
+    }
+
+    // Use the title in the metadata. Failing that, the first paragraph is probably the title
+    let title = info_string(&doc, b"Title")
+        .filter(|t| t.chars().count() >= MIN_TITLE_LEN)
+        .or_else(|| text.lines().next().map(str::to_string))
+        .unwrap_or_default();
+    // Don't read the title twice if it's also the first paragraph
+    let text = match text.split_once('\n') {
+        Some((first, rest)) if first == title => rest.to_string(),
+        _ => text,
+    };
+
+    Ok(ExtractedArticle {
+        title,
+        author: info_string(&doc, b"Author"),
+        date: info_string(&doc, b"CreationDate").and_then(|d| pdf_date(&d)),
+        text,
+    })
+}
+
+/// Returns the given entry of the document information dictionary, if it's a nonempty string
+fn info_string(doc: &Document, key: &[u8]) -> Option<String> {
+    let info = match doc.trailer.get(b"Info").ok()? {
+        Object::Reference(id) => doc.get_dictionary(*id).ok()?,
+        Object::Dictionary(dict) => dict,
+        _ => return None,
+    };
+    let s = decode_text_string(info.get(key).ok()?).ok()?;
+    let s = s.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!s.is_empty()).then_some(s)
+}
+
+/// Converts a PDF date, like "D:20230115093000+01'00'", to YYYY-MM-DD
+fn pdf_date(date: &str) -> Option<String> {
+    let digits = date.strip_prefix("D:").unwrap_or(date).get(..8)?;
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(format!(
+        "{}-{}-{}",
+        &digits[..4],
+        &digits[4..6],
+        &digits[6..]
+    ))
+}
+
+/// Normalizes a line for comparison with the lines on other pages
+fn running_key(line: &str) -> String {
+    DIGITS.replace_all(&line.to_lowercase(), "#").to_string()
+}
+
+/// Turns the text of every page into paragraphs, one per line. Running headers, footers, and page
+/// numbers are dropped.
+fn clean_pages(pages: &[String]) -> String {
+    // Split the pages into lines, trimming the whitespace
+    let pages: Vec<Vec<&str>> = pages
+        .iter()
+        .map(|page| page.lines().map(str::trim).collect())
+        .collect();
+
+    // The lines at the edges of a page are the first and last few nonempty ones
+    let edges = |page: &[&str]| -> Vec<usize> {
+        let nonempty: Vec<usize> = (0..page.len()).filter(|&i| !page[i].is_empty()).collect();
+        let mut edges: Vec<usize> = nonempty.iter().take(EDGE_LINES).copied().collect();
+        edges.extend(nonempty.iter().rev().take(EDGE_LINES));
+        edges.sort_unstable();
+        edges.dedup();
+        edges
+    };
+
+    // Count the pages every edge line appears on. The ones on enough pages are running headers and
+    // footers. A document needs a few pages for this to mean anything
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for page in &pages {
+        let mut keys: Vec<String> = edges(page).iter().map(|&i| running_key(page[i])).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        for key in keys {
+            *counts.entry(key).or_default() += 1;
+        }
+    }
+    let min_count = ((pages.len() as f64 * MIN_RUNNING_FRACTION).ceil() as usize).max(2);
+    let is_running = |line: &str| pages.len() >= 3 && counts[&running_key(line)] >= min_count;
+
+    // Blank out the running lines and page numbers, and collect the paragraphs. Blank lines
+    // separate paragraphs. A page break only ends a paragraph if the last sentence is finished
+    let mut paragraphs: Vec<Vec<&str>> = Vec::new();
+    for page in &pages {
+        let edges = edges(page);
+        let mut lines = page.clone();
+        for i in edges {
+            if is_running(lines[i]) || PAGE_NUMBER.is_match(lines[i]) {
+                lines[i] = "";
+            }
+        }
+        // Skip the blanked lines, so they don't end a paragraph
+        let lines = lines
+            .iter()
+            .enumerate()
+            .filter(|&(i, l)| !l.is_empty() || page[i].is_empty())
+            .map(|(_, l)| *l);
+
+        let mut at_page_start = true;
+        let mut in_paragraph = false;
+        for line in lines {
+            if line.is_empty() {
+                in_paragraph = false;
+            } else if in_paragraph
+                || (at_page_start && paragraphs.last().is_some_and(|p| !ends_sentence(p)))
+            {
+                paragraphs.last_mut().unwrap().push(line);
+                in_paragraph = true;
+            } else {
+                paragraphs.push(vec![line]);
+                in_paragraph = true;
+            }
+            at_page_start &= line.is_empty();
+        }
+    }
+
+    paragraphs
+        .iter()
+        .map(|lines| join_lines(lines))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Whether the paragraph ends at the end of a sentence
+fn ends_sentence(lines: &[&str]) -> bool {
+    lines.last().is_some_and(|l| {
+        l.trim_end_matches(['"', '\'', '”', '’', ')'])
+            .ends_with(['.', '!', '?', ':', '。', '！', '？'])
+    })
+}
+
+/// Joins the lines of a paragraph with spaces. A word broken across two lines with a hyphen is put
+/// back together.
+fn join_lines(lines: &[&str]) -> String {
+    let mut joined = String::new();
+    for line in lines {
+        let broken_word = joined
+            .strip_suffix(['-', '\u{ad}'])
+            .filter(|rest| rest.ends_with(char::is_alphabetic))
+            .is_some();
+        let continues_word = line.starts_with(char::is_lowercase);
+        if broken_word && continues_word {
+            joined.pop();
+        } else if !joined.is_empty() {
+            joined.push(' ');
+        }
+        joined.push_str(line);
+    }
+    joined
+}
+
+#[test]
+fn pdf_cleanup() {
+    let page =
+        |n: usize, body: &str| format!("Journal of Lighthouse Studies, Vol. 3\n\n{body}\n\n{n}\n");
+    let pages = vec![
+        page(
+            1,
+            "The Fresnel Lens\n\nLighthouses were once lit by open fires. Augustin Fresnel de-\n\
+            signed a lens that made the light visible from far away. Its rings",
+        ),
+        page(
+            2,
+            "of glass bend the light into a beam.\n\nThe lens was first used in 1823.",
+        ),
+        page(3, "Today, most lighthouses are automated."),
+    ];
+    assert_eq!(
+        clean_pages(&pages),
+        "The Fresnel Lens\n\
+        Lighthouses were once lit by open fires. Augustin Fresnel designed a lens that made the \
+        light visible from far away. Its rings of glass bend the light into a beam.\n\
+        The lens was first used in 1823.\n\
+        Today, most lighthouses are automated."
+    );
+
+    // With too few pages, nothing counts as a running header
+    let pages = vec![page(1, "Short."), page(2, "Document.")];
+    assert_eq!(
+        clean_pages(&pages),
+        "Journal of Lighthouse Studies, Vol. 3\nShort.\nJournal of Lighthouse Studies, Vol. 3\n\
+        Document."
+    );
+
+    assert_eq!(
+        pdf_date("D:20230115093000+01'00'").as_deref(),
+        Some("2023-01-15")
+    );
+    assert_eq!(pdf_date("D:2023"), None);
+}
+
+#[tokio::test]
+async fn pdf_extraction() {
+    use pdf_extract::{dictionary, Stream};
+
+    // Make a two-page PDF with a title in its metadata
+    let mut doc = Document::with_version("1.5");
+    let pages_id = doc.new_object_id();
+    let font_id = doc.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type1",
+        "BaseFont" => "Helvetica",
+        "Encoding" => "WinAnsiEncoding",
+    });
+    let resources_id = doc.add_object(dictionary! {
+        "Font" => dictionary! { "F1" => font_id },
+    });
+    let mut page_ids = Vec::new();
+    for text in ["The first page of the report.", "The second page of it."] {
+        let content = format!("BT /F1 12 Tf 72 720 Td ({text}) Tj ET");
+        let content_id = doc.add_object(Stream::new(dictionary! {}, content.into_bytes()));
+        page_ids.push(Object::from(doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content_id,
+        })));
+    }
+    doc.objects.insert(
+        pages_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Kids" => page_ids,
+            "Count" => 2,
+            "Resources" => resources_id,
+            "MediaBox" => vec![0.into(), 0.into(), 612.into(), 792.into()],
+        }),
+    );
+    let catalog_id = doc.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    });
+    let info_id = doc.add_object(dictionary! {
+        "Title" => Object::string_literal("Annual Report"),
+        "Author" => Object::string_literal("Grace Darling"),
+    });
+    doc.trailer.set("Root", catalog_id);
+    doc.trailer.set("Info", info_id);
+    let mut pdf = Vec::new();
+    doc.save_to(&mut pdf).unwrap();
+
+    let article = extract_pdf(pdf).await.unwrap();
+    assert_eq!(article.title, "Annual Report");
+    assert_eq!(article.author.as_deref(), Some("Grace Darling"));
+    assert_eq!(
+        article.text,
+        "The first page of the report.\nThe second page of it."
+    );
+
+    // Something that isn't a PDF fails
+    assert!(extract_pdf(b"<html></html>".to_vec()).await.is_err());
+}
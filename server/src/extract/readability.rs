@@ -0,0 +1,603 @@
+//! A readability-style article extractor. Every paragraph of the page scores points for the
+//! elements that contain it, by how much it looks like article text: long, with commas, and not
+//! mostly links. The element with the best score is the article. Its contents are written out in
+//! the Markdown-like format that trafilatura outputs with `--formatting`, which is what `tts::ssml`
+//! reads. The title, author, and publication date come from the page's metadata.
+
+use crate::{
+    extract::{ExtractedArticle, Extractor},
+    tts::ssml::escape_block,
+};
+
+use std::{collections::HashMap, sync::LazyLock};
+
+use anyhow::{anyhow, bail, Error as AnyError};
+use ego_tree::{NodeId, NodeRef};
+use futures::future::{self, BoxFuture, FutureExt};
+use regex::Regex;
+use scraper::{ElementRef, Html, Node, Selector};
+use serde_json::Value as JsonValue;
+
+/// Elements that are never part of the article text
+const SKIPPED_TAGS: &[&str] = &[
+    "aside", "audio", "button", "canvas", "dialog", "embed", "figure", "footer", "form", "header",
+    "iframe", "img", "input", "menu", "nav", "noscript", "object", "picture", "script", "select",
+    "style", "svg", "template", "textarea", "video",
+];
+
+/// Elements that start a new line. Everything else is inline
+const BLOCK_TAGS: &[&str] = &[
+    "address",
+    "article",
+    "blockquote",
+    "body",
+    "dd",
+    "details",
+    "div",
+    "dl",
+    "dt",
+    "fieldset",
+    "figcaption",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "li",
+    "main",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "summary",
+    "table",
+    "tbody",
+    "td",
+    "tfoot",
+    "th",
+    "thead",
+    "tr",
+    "ul",
+];
+
+/// Paragraphs shorter than this many characters don't count towards scores
+const MIN_PARAGRAPH_LEN: usize = 25;
+
+/// Elements whose text is more than this fraction links are navigation, not article text
+const MAX_LINK_DENSITY: f64 = 0.5;
+
+/// Class names and IDs of elements that are probably not the article
+static UNLIKELY: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        "(?i)-ad-|ad-break|adbox|advert|banner|breadcrumb|comment|cookie|disqus|footer|header|\
+          menu|modal|masthead|newsletter|outbrain|pagination|popup|promo|related|share|sidebar|\
+          social|sponsor|subscribe|taboola|toolbar|widget",
+    )
+    .unwrap()
+});
+
+/// Class names and IDs of elements that probably are the article
+static LIKELY: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)article|blog|body|content|entry|main|page|post|story|text").unwrap()
+});
+
+/// The separators that sites put between an article's title and their own name
+static TITLE_SEPARATOR: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r" (?:[|\-–—/»·]|::) ").unwrap());
+
+/// A date at the start of a timestamp
+static DATE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\d{4}-\d{2}-\d{2}").unwrap());
+
+/// Footnote markers, like `1` or `[1]`
+static FOOTNOTE_MARKER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\[?\d+\]?$").unwrap());
+
+/// Parses a selector that's known to be valid
+pub(super) fn selector(s: &str) -> Selector {
+    Selector::parse(s).unwrap()
+}
+
+/// The built-in extractor
+pub(crate) struct Readability;
+
+impl Extractor for Readability {
+    fn name(&self) -> &str {
+        "readability"
+    }
+
+    fn extract_html<'a>(
+        &'a self,
+        html: &'a str,
+    ) -> BoxFuture<'a, Result<ExtractedArticle, AnyError>> {
+        future::ready(extract(html)).boxed()
+    }
+}
+
+/// Extracts the article from the given page. Fails if the page doesn't seem to have one.
+fn extract(html: &str) -> Result<ExtractedArticle, AnyError> {
+    let doc = Html::parse_document(html);
+    let json_ld = json_ld_article(&doc);
+
+    let title = find_title(&doc, json_ld.as_ref()).ok_or(anyhow!("no title"))?;
+    let author = find_author(&doc, json_ld.as_ref());
+    let date = find_date(&doc, json_ld.as_ref());
+
+    let article = find_article(&doc).ok_or(anyhow!("no article text"))?;
+    let mut lines = Vec::new();
+    for elem in article {
+        write_blocks(elem, &title, &mut lines);
+    }
+    if lines.is_empty() {
+        bail!("no article text");
+    }
+
+    Ok(ExtractedArticle {
+        title,
+        author,
+        date,
+        text: lines.join("\n"),
+    })
+}
+
+/// Writes the whole body of the page as article text. This is for pages that are nothing but
+/// article, like the chapters of an EPUB. Headings that repeat `title` are left out.
+pub(super) fn body_text(doc: &Html, title: &str) -> String {
+    let mut lines = Vec::new();
+    if let Some(body) = doc.select(&selector("body")).next() {
+        write_blocks(body, title, &mut lines);
+    }
+    lines.join("\n")
+}
+
+/// Collapses the whitespace in the text
+pub(super) fn clean(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Returns the text of the element, with its whitespace collapsed
+pub(super) fn text_of(elem: ElementRef) -> String {
+    clean(&elem.text().collect::<String>())
+}
+
+/// Returns the content of the first `<meta>` that matches the selector
+fn meta_content(doc: &Html, sel: &str) -> Option<String> {
+    doc.select(&selector(sel))
+        .filter_map(|m| m.value().attr("content"))
+        .map(clean)
+        .find(|c| !c.is_empty())
+}
+
+/// Returns the page's JSON-LD description of the article, if it has one. This is the first
+/// object whose type is an article or blog post, or that has a headline.
+fn json_ld_article(doc: &Html) -> Option<JsonValue> {
+    fn find(value: JsonValue) -> Option<JsonValue> {
+        match value {
+            JsonValue::Array(items) => items.into_iter().find_map(find),
+            JsonValue::Object(mut obj) => {
+                if let Some(graph) = obj.remove("@graph") {
+                    return find(graph);
+                }
+                let is_article = obj.contains_key("headline")
+                    || obj
+                        .get("@type")
+                        .and_then(JsonValue::as_str)
+                        .is_some_and(|ty| {
+                            ty.ends_with("Article") || ty == "BlogPosting" || ty == "Report"
+                        });
+                is_article.then_some(JsonValue::Object(obj))
+            }
+            _ => None,
+        }
+    }
+
+    doc.select(&selector(r#"script[type="application/ld+json"]"#))
+        .filter_map(|s| serde_json::from_str(&s.text().collect::<String>()).ok())
+        .find_map(find)
+}
+
+/// Finds the title of the article. The page's own title usually has the site's name in it, so it
+/// comes last.
+fn find_title(doc: &Html, json_ld: Option<&JsonValue>) -> Option<String> {
+    meta_content(doc, r#"meta[property="og:title"]"#)
+        .or_else(|| meta_content(doc, r#"meta[name="twitter:title"]"#))
+        .or_else(|| json_ld?.get("headline")?.as_str().map(clean))
+        .or_else(|| {
+            let title = text_of(doc.select(&selector("title")).next()?);
+            // Cut off the site's name, unless that leaves too little of a title
+            let cut = TITLE_SEPARATOR
+                .find_iter(&title)
+                .last()
+                .map(|sep| &title[..sep.start()])
+                .filter(|t| t.split_whitespace().count() >= 3);
+            Some(cut.map(str::to_string).unwrap_or(title))
+        })
+        .or_else(|| doc.select(&selector("h1")).map(text_of).next())
+        .filter(|t| !t.is_empty())
+}
+
+/// Finds the author of the article. Bylines lose their "By"
+fn find_author(doc: &Html, json_ld: Option<&JsonValue>) -> Option<String> {
+    fn json_name(value: &JsonValue) -> Option<String> {
+        match value {
+            JsonValue::String(s) => Some(clean(s)),
+            JsonValue::Object(obj) => obj.get("name").and_then(json_name),
+            JsonValue::Array(items) => {
+                let names: Vec<String> = items.iter().filter_map(json_name).collect();
+                (!names.is_empty()).then(|| names.join(", "))
+            }
+            _ => None,
+        }
+    }
+
+    meta_content(doc, r#"meta[name="author"]"#)
+        .or_else(|| json_ld?.get("author").and_then(json_name))
+        .or_else(|| {
+            // This is often a link to the author's page
+            meta_content(doc, r#"meta[property="article:author"]"#)
+                .filter(|a| !a.starts_with("http"))
+        })
+        .or_else(|| {
+            doc.select(&selector(r#"[rel="author"], [itemprop="author"], .byline"#))
+                .map(text_of)
+                .next()
+        })
+        .map(|a| {
+            a.strip_prefix("By ")
+                .or_else(|| a.strip_prefix("by "))
+                .unwrap_or(&a)
+                .to_string()
+        })
+        .filter(|a| !a.is_empty() && a.len() < 100)
+}
+
+/// Finds the date the article was published, as YYYY-MM-DD
+fn find_date(doc: &Html, json_ld: Option<&JsonValue>) -> Option<String> {
+    let timestamp = meta_content(doc, r#"meta[property="article:published_time"]"#)
+        .or_else(|| json_ld?.get("datePublished")?.as_str().map(str::to_string))
+        .or_else(|| meta_content(doc, r#"meta[itemprop="datePublished"]"#))
+        .or_else(|| meta_content(doc, r#"meta[name="date"]"#))
+        .or_else(|| {
+            doc.select(&selector("time[datetime]"))
+                .filter_map(|t| t.value().attr("datetime"))
+                .map(str::to_string)
+                .next()
+        })?;
+    DATE.find(timestamp.trim()).map(|d| d.as_str().to_string())
+}
+
+/// Returns whether the element is never part of the article text, by its tag, class, or ID
+fn is_skipped(elem: ElementRef) -> bool {
+    let e = elem.value();
+    if SKIPPED_TAGS.contains(&e.name()) {
+        return true;
+    }
+    if matches!(e.name(), "html" | "body" | "article" | "main") {
+        return false;
+    }
+    let names = format!("{} {}", e.attr("class").unwrap_or(""), e.id().unwrap_or(""));
+    UNLIKELY.is_match(&names) && !LIKELY.is_match(&names)
+}
+
+/// Returns whether the element or any of its ancestors is never part of the article text
+fn is_excluded(elem: ElementRef) -> bool {
+    is_skipped(elem)
+        || elem
+            .ancestors()
+            .filter_map(ElementRef::wrap)
+            .any(is_skipped)
+}
+
+/// Returns the fraction of the element's text that's in links
+fn link_density(elem: ElementRef) -> f64 {
+    let text_len = text_of(elem).len();
+    if text_len == 0 {
+        return 0.0;
+    }
+    let link_len: usize = elem.select(&selector("a")).map(|a| text_of(a).len()).sum();
+    link_len as f64 / text_len as f64
+}
+
+/// The score an element starts out with, by its tag and class names
+fn initial_score(elem: ElementRef) -> f64 {
+    let e = elem.value();
+    let tag_score = match e.name() {
+        "article" => 10.0,
+        "div" | "main" | "section" => 5.0,
+        "blockquote" | "pre" | "td" => 3.0,
+        "address" | "dd" | "dl" | "dt" | "li" | "ol" | "ul" => -3.0,
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "th" => -5.0,
+        _ => 0.0,
+    };
+    let name_score: f64 = [e.attr("class"), e.id()]
+        .into_iter()
+        .flatten()
+        .map(|name| {
+            if LIKELY.is_match(name) {
+                25.0
+            } else if UNLIKELY.is_match(name) {
+                -25.0
+            } else {
+                0.0
+            }
+        })
+        .sum();
+    tag_score + name_score
+}
+
+/// Finds the elements that make up the article: the element with the best score, and the siblings
+/// of it that look like they continue the article
+fn find_article(doc: &Html) -> Option<Vec<ElementRef<'_>>> {
+    // Every paragraph gives points to its parent, and fewer points to its grandparent and
+    // great-grandparent. Longer paragraphs with more commas give more points
+    let mut scores: HashMap<NodeId, (ElementRef, f64)> = HashMap::new();
+    for para in doc.select(&selector("p, pre, td")) {
+        let text = text_of(para);
+        if text.chars().count() < MIN_PARAGRAPH_LEN || is_excluded(para) {
+            continue;
+        }
+        let commas = text.matches([',', '，', '、', '،']).count();
+        let points = 1.0 + commas as f64 + (text.chars().count() as f64 / 100.0).min(3.0);
+
+        let ancestors = para.ancestors().filter_map(ElementRef::wrap).take(3);
+        for (level, ancestor) in ancestors.enumerate() {
+            let divider = [1.0, 2.0, 6.0][level];
+            let entry = scores
+                .entry(ancestor.id())
+                .or_insert_with(|| (ancestor, initial_score(ancestor)));
+            entry.1 += points / divider;
+        }
+    }
+
+    // Elements that are mostly links are lists of other articles
+    let (top, top_score) = scores
+        .values()
+        .map(|&(elem, score)| (elem, score * (1.0 - link_density(elem))))
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+
+    // Articles are sometimes split up into sibling elements, say, by an ad. Take the siblings
+    // that score well, or that are paragraphs of text
+    let Some(parent) = top.parent().and_then(ElementRef::wrap) else {
+        return Some(vec![top]);
+    };
+    let threshold = f64::max(10.0, top_score * 0.2);
+    let article = parent
+        .children()
+        .filter_map(ElementRef::wrap)
+        .filter(|&sibling| {
+            if sibling == top {
+                return true;
+            }
+            if is_skipped(sibling) {
+                return false;
+            }
+            let score = scores.get(&sibling.id()).map(|&(_, s)| s).unwrap_or(0.0);
+            if score * (1.0 - link_density(sibling)) >= threshold {
+                return true;
+            }
+            let text = text_of(sibling);
+            sibling.value().name() == "p"
+                && link_density(sibling) < 0.25
+                && (text.len() > 80 || text.contains(". "))
+        })
+        .collect();
+
+    Some(article)
+}
+
+/// Writes the element as lines of text. Headings start with `#`, list items with `- `, and quotes
+/// with `>`. Every other line is a paragraph. Headings that repeat the title are left out, since
+/// the title is read anyway.
+fn write_blocks(elem: ElementRef, title: &str, lines: &mut Vec<String>) {
+    if is_skipped(elem) {
+        return;
+    }
+    let name = elem.value().name();
+    match name {
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            let heading = clean(&inline_text(elem));
+            if !heading.is_empty() && !heading.eq_ignore_ascii_case(title) {
+                let level = name[1..].parse().unwrap_or(1);
+                lines.push(format!("{} {heading}", "#".repeat(level)));
+            }
+        }
+        "p" | "pre" | "dt" | "dd" | "figcaption" => {
+            if link_density(elem) <= MAX_LINK_DENSITY {
+                push_paragraphs(&inline_text(elem), "", lines);
+            }
+        }
+        "li" => {
+            // Nested lists are written after the item's own text
+            let mut text = String::new();
+            for child in elem.children() {
+                match ElementRef::wrap(child) {
+                    Some(c) if matches!(c.value().name(), "ul" | "ol") => (),
+                    _ => push_inline(child, &mut text),
+                }
+            }
+            push_paragraphs(&text, "- ", lines);
+            for list in elem.children().filter_map(ElementRef::wrap) {
+                if matches!(list.value().name(), "ul" | "ol") {
+                    write_blocks(list, title, lines);
+                }
+            }
+        }
+        "blockquote" => {
+            let mut quoted = Vec::new();
+            write_container(elem, title, &mut quoted);
+            lines.extend(quoted.into_iter().map(|line| format!("> {line}")));
+        }
+        "ul" | "ol" | "table" if link_density(elem) > MAX_LINK_DENSITY => (),
+        _ => write_container(elem, title, lines),
+    }
+}
+
+/// Writes the children of the element as lines of text. Runs of text and inline elements between
+/// the block elements are paragraphs.
+fn write_container(elem: ElementRef, title: &str, lines: &mut Vec<String>) {
+    let mut run = String::new();
+    for child in elem.children() {
+        match ElementRef::wrap(child) {
+            Some(c) if BLOCK_TAGS.contains(&c.value().name()) => {
+                push_paragraphs(&std::mem::take(&mut run), "", lines);
+                write_blocks(c, title, lines);
+            }
+            _ => push_inline(child, &mut run),
+        }
+    }
+    push_paragraphs(&run, "", lines);
+}
+
+/// Returns the text of the node if it's a text node. Newlines in the HTML source are just
+/// whitespace, so they're replaced with spaces.
+fn node_text(node: &Node) -> String {
+    node.as_text()
+        .map(|t| t.replace(['\n', '\r'], " "))
+        .unwrap_or_default()
+}
+
+/// Adds the lines of the text, with the given prefix. Blank lines are skipped. Lines without a
+/// prefix are escaped, so that a paragraph that starts with `# ` isn't read as a heading.
+fn push_paragraphs(text: &str, prefix: &str, lines: &mut Vec<String>) {
+    for line in text.lines().map(clean).filter(|l| !l.is_empty()) {
+        if prefix.is_empty() {
+            lines.push(escape_block(&line));
+        } else {
+            lines.push(format!("{prefix}{line}"));
+        }
+    }
+}
+
+/// Returns the text of the element. See [`push_inline`].
+fn inline_text(elem: ElementRef) -> String {
+    let mut text = String::new();
+    for child in elem.children() {
+        push_inline(child, &mut text);
+    }
+    text
+}
+
+/// Adds the text of the node. Emphasis becomes `*` and strong emphasis becomes `**`. Line breaks
+/// are kept, and footnote markers are dropped.
+fn push_inline(node: NodeRef<Node>, text: &mut String) {
+    let elem = match ElementRef::wrap(node) {
+        Some(e) if !is_skipped(e) => e,
+        Some(_) => return,
+        None => {
+            text.push_str(&node_text(node.value()));
+            return;
+        }
+    };
+    match elem.value().name() {
+        "br" => text.push('\n'),
+        "sup" if FOOTNOTE_MARKER.is_match(&text_of(elem)) => (),
+        tag @ ("em" | "i" | "strong" | "b") => {
+            let marker = if matches!(tag, "strong" | "b") {
+                "**"
+            } else {
+                "*"
+            };
+            // Keep the spaces around the emphasized text outside the markers
+            let inner = inline_text(elem);
+            let trimmed = inner.trim();
+            if trimmed.is_empty() || trimmed.contains('\n') {
+                text.push_str(&inner);
+            } else {
+                let start = inner.len() - inner.trim_start().len();
+                let end = inner.trim_end().len();
+                text.push_str(&inner[..start]);
+                text.push_str(&format!("{marker}{trimmed}{marker}"));
+                text.push_str(&inner[end..]);
+            }
+        }
+        _ => text.push_str(&inline_text(elem)),
+    }
+}
+
+#[test]
+fn readability_extraction() {
+    let html = r#"
+        <html>
+        <head>
+            <title>The Lighthouse Keeper | The Coastal Review</title>
+            <meta name="author" content="By Ada Lovelace">
+            <meta property="article:published_time" content="2022-09-12T08:30:00+00:00">
+        </head>
+        <body>
+            <nav><ul><li><a href="/">Home</a></li><li><a href="/news">News</a></li></ul></nav>
+            <div class="sidebar">
+                <p>Subscribe to our newsletter, and get the best of the coast, every week.</p>
+            </div>
+            <article class="post">
+                <h1>The Lighthouse Keeper</h1>
+                <p>The keeper climbed the stairs every evening, counting them as he went, and the
+                   light turned above him until morning.</p>
+                <h2>The <em>Storm</em></h2>
+                <p>In the winter of that year, a storm came in from the west, and the sea rose
+                   higher than anyone could remember.<sup>1</sup></p>
+                <blockquote><p>It was the worst night of my life, he said.</p></blockquote>
+                <ul>
+                    <li>The windows broke.</li>
+                    <li>The <strong>lamp</strong> held.</li>
+                </ul>
+                <div class="share-buttons"><a href="/share">Share this story</a></div>
+                <figure><img src="storm.jpg"><figcaption>The storm</figcaption></figure>
+            </article>
+            <footer><p>Copyright The Coastal Review, all rights reserved, since forever.</p></footer>
+        </body>
+        </html>
+    "#;
+
+    let article = extract(html).unwrap();
+    assert_eq!(article.title, "The Lighthouse Keeper");
+    assert_eq!(article.author.as_deref(), Some("Ada Lovelace"));
+    assert_eq!(article.date.as_deref(), Some("2022-09-12"));
+
+    // The navigation, sidebar, share buttons, figure, and footer are gone. So are the heading
+    // that repeats the title, and the footnote marker
+    assert_eq!(
+        article.text,
+        "The keeper climbed the stairs every evening, counting them as he went, and the light \
+         turned above him until morning.\n\
+         ## The *Storm*\n\
+         In the winter of that year, a storm came in from the west, and the sea rose higher than \
+         anyone could remember.\n\
+         > It was the worst night of my life, he said.\n\
+         - The windows broke.\n\
+         - The **lamp** held."
+    );
+
+    // JSON-LD metadata is read too. The article is in the div with the most text, even without a
+    // helpful class name
+    let html = r#"
+        <html>
+        <head>
+            <title>Site name</title>
+            <script type="application/ld+json">
+                {"@context": "https://schema.org", "@graph": [
+                    {"@type": "WebSite", "name": "Site name"},
+                    {"@type": "NewsArticle", "headline": "Tides, explained",
+                     "author": [{"@type": "Person", "name": "Jane Doe"}, "John Roe"],
+                     "datePublished": "2021-03-04"}
+                ]}
+            </script>
+        </head>
+        <body>
+            <div><p><a href="/a">Another article</a></p><p><a href="/b">And another</a></p></div>
+            <div>
+                <p>The tides are caused by the moon, mostly, and a little by the sun as well.</p>
+                <p>Twice a day, the water rises and falls, and the fishermen plan around it.</p>
+            </div>
+        </body>
+        </html>
+    "#;
+    let article = extract(html).unwrap();
+    assert_eq!(article.title, "Tides, explained");
+    assert_eq!(article.author.as_deref(), Some("Jane Doe, John Roe"));
+    assert_eq!(article.date.as_deref(), Some("2021-03-04"));
+    assert_eq!(article.text.lines().count(), 2);
+    assert!(article.text.starts_with("The tides are caused by the moon"));
+
+    // A page with no paragraphs has no article
+    assert!(extract("<html><head><title>Login</title></head><body><form></form></body>").is_err());
+}
@@ -0,0 +1,190 @@
+//! Reads articles from plain text and Markdown files. Markdown is converted to the Markdown-like
+//! format described in `tts::ssml`: headings start with `#`, list items with `- `, and block quotes
+//! with `> `, emphasis is marked with `*` and `**`, and every other line is a paragraph. Links are
+//! read as their text, and images and raw HTML are dropped. Paragraphs that would look like
+//! another block, as can any line of a plain text file, are escaped.
+
+use crate::{
+    extract::{readability::clean, ExtractedArticle},
+    tts::ssml::escape_block,
+};
+
+use pulldown_cmark::{Event, HeadingLevel, Parser, Tag};
+
+/// Reads a plain text file. Paragraphs are separated by blank lines, and the lines within a
+/// paragraph are joined, since text files are often wrapped. A file without blank lines has a
+/// paragraph per line. The title is the given one, from the file name, or failing that, the first
+/// line.
+pub(crate) fn read_plain_text(text: &str, title: Option<&str>) -> ExtractedArticle {
+    let lines: Vec<&str> = text.lines().map(str::trim).collect();
+    let mut paragraphs: Vec<String> = if lines.contains(&"") {
+        lines
+            .split(|l| l.is_empty())
+            .map(|p| clean(&p.join(" ")))
+            .filter(|p| !p.is_empty())
+            .collect()
+    } else {
+        lines.iter().map(|l| clean(l)).collect()
+    };
+
+    let title = match title {
+        Some(t) => t.to_string(),
+        None if !paragraphs.is_empty() => paragraphs.remove(0),
+        None => String::new(),
+    };
+    // None of it is markup
+    let paragraphs: Vec<String> = paragraphs.iter().map(|p| escape_block(p)).collect();
+    ExtractedArticle {
+        title,
+        author: None,
+        date: None,
+        text: paragraphs.join("\n"),
+    }
+}
+
+/// Reads a Markdown file. If it starts with a top-level heading, that's the title. Otherwise, the
+/// title is the given one, from the file name, or failing that, the first line.
+pub(crate) fn read_markdown(md: &str, title: Option<&str>) -> ExtractedArticle {
+    let mut lines: Vec<String> = Vec::new();
+    // The text of the block being read, and whether it's a list item
+    let mut block = String::new();
+    let mut in_item = false;
+    let mut quote_depth = 0;
+    let mut heading: Option<HeadingLevel> = None;
+    let mut image_depth = 0;
+    let mut first_heading: Option<String> = None;
+
+    // Writes the block being read as lines. Only its first line gets the list item marker
+    let flush = |block: &mut String, in_item: &mut bool, quote_depth: usize, lines: &mut Vec<_>| {
+        for line in block.lines().map(clean).filter(|l| !l.is_empty()) {
+            let quote = if quote_depth > 0 { "> " } else { "" };
+            let item = if std::mem::take(in_item) { "- " } else { "" };
+            let line = if quote.is_empty() && item.is_empty() {
+                escape_block(&line)
+            } else {
+                line
+            };
+            lines.push(format!("{quote}{item}{line}"));
+        }
+        block.clear();
+    };
+
+    for event in Parser::new(md) {
+        match event {
+            Event::Start(Tag::Heading(level, ..)) => {
+                flush(&mut block, &mut in_item, quote_depth, &mut lines);
+                heading = Some(level);
+            }
+            Event::End(Tag::Heading(level, ..)) => {
+                let text = clean(&block);
+                block.clear();
+                heading = None;
+                if text.is_empty() {
+                    continue;
+                }
+                // A top-level heading at the very start is the title
+                if level == HeadingLevel::H1 && lines.is_empty() && first_heading.is_none() {
+                    first_heading = Some(text);
+                } else {
+                    lines.push(format!("{} {text}", "#".repeat(level as usize)));
+                }
+            }
+            Event::Start(Tag::Item) => {
+                flush(&mut block, &mut in_item, quote_depth, &mut lines);
+                in_item = true;
+            }
+            Event::Start(Tag::BlockQuote) => {
+                flush(&mut block, &mut in_item, quote_depth, &mut lines);
+                quote_depth += 1;
+            }
+            Event::End(Tag::BlockQuote) => {
+                flush(&mut block, &mut in_item, quote_depth, &mut lines);
+                quote_depth -= 1;
+            }
+            Event::End(
+                Tag::Paragraph
+                | Tag::Item
+                | Tag::CodeBlock(_)
+                | Tag::TableHead
+                | Tag::TableRow
+                | Tag::FootnoteDefinition(_),
+            )
+            | Event::Rule => flush(&mut block, &mut in_item, quote_depth, &mut lines),
+            // Headings are emphasized anyway
+            Event::Start(Tag::Emphasis) | Event::End(Tag::Emphasis) if heading.is_none() => {
+                block.push('*')
+            }
+            Event::Start(Tag::Strong) | Event::End(Tag::Strong) if heading.is_none() => {
+                block.push_str("**")
+            }
+            Event::Start(Tag::Image(..)) => image_depth += 1,
+            Event::End(Tag::Image(..)) => image_depth -= 1,
+            Event::Text(text) | Event::Code(text) if image_depth == 0 => block.push_str(&text),
+            Event::SoftBreak | Event::End(Tag::TableCell) => block.push(' '),
+            Event::HardBreak => block.push('\n'),
+            _ => (),
+        }
+    }
+    flush(&mut block, &mut in_item, quote_depth, &mut lines);
+
+    let title = match (first_heading, title) {
+        (Some(t), _) => t,
+        (None, Some(t)) => t.to_string(),
+        (None, None) if !lines.is_empty() => lines.remove(0),
+        (None, None) => String::new(),
+    };
+    ExtractedArticle {
+        title,
+        author: None,
+        date: None,
+        text: lines.join("\n"),
+    }
+}
+
+#[test]
+fn text_file_reading() {
+    // Wrapped paragraphs are joined
+    let article = read_plain_text(
+        "The lamp was lit at dusk,\nas always.\n\nThe keeper slept at dawn.\n",
+        Some("keeper"),
+    );
+    assert_eq!(article.title, "keeper");
+    assert_eq!(
+        article.text,
+        "The lamp was lit at dusk, as always.\nThe keeper slept at dawn."
+    );
+    // Without blank lines, and without a title, every line is a paragraph, and the first is the
+    // title
+    let article = read_plain_text("The Keeper\nThe lamp was lit.\nThe keeper slept.", None);
+    assert_eq!(article.title, "The Keeper");
+    assert_eq!(article.text, "The lamp was lit.\nThe keeper slept.");
+    // Lines that look like markup are escaped
+    let article = read_plain_text("Notes\n# Not a heading\n> Not a quote", None);
+    assert_eq!(article.text, "\\# Not a heading\n\\> Not a quote");
+
+    let md = "# How Lighthouses Work\n\n\
+        ![A lighthouse](lighthouse.jpg)\n\n\
+        Lighthouses were *once* lit by **open fires**,\nand later by \
+        [oil lamps](https://example.com/oil).\n\n\
+        ## The *Fresnel* Lens\n\n\
+        > A lens made of rings\n> of glass.\n\n\
+        - Lighter\n- Brighter\n  - Much brighter\n\n\
+        ```\nlamp --> lens\n```\n";
+    let article = read_markdown(md, Some("lighthouses"));
+    assert_eq!(article.title, "How Lighthouses Work");
+    assert_eq!(
+        article.text,
+        "Lighthouses were *once* lit by **open fires**, and later by oil lamps.\n\
+        ## The Fresnel Lens\n\
+        > A lens made of rings of glass.\n\
+        - Lighter\n\
+        - Brighter\n\
+        - Much brighter\n\
+        lamp --> lens"
+    );
+
+    // Without a top-level heading, the file name is the title
+    let article = read_markdown("Just a *note*.", Some("note"));
+    assert_eq!(article.title, "note");
+    assert_eq!(article.text, "Just a *note*.");
+}
@@ -0,0 +1,78 @@
+//! Runs trafilatura, the Python article extractor
+
+use crate::extract::{ExtractedArticle, Extractor};
+
+use std::{path::PathBuf, process::Stdio};
+
+use anyhow::{anyhow, Error as AnyError};
+use async_process::Command;
+use futures::{
+    future::{BoxFuture, FutureExt},
+    AsyncWriteExt,
+};
+
+/// An installed trafilatura
+pub(crate) struct Trafilatura {
+    /// The path to the trafilatura binary
+    command: PathBuf,
+    /// The environment variables to run it with, e.g., the PYTHONPATH it's installed under
+    env: Vec<(String, String)>,
+}
+
+impl Trafilatura {
+    pub(crate) fn new(command: PathBuf, env: Vec<(String, String)>) -> Self {
+        Trafilatura { command, env }
+    }
+
+    /// Makes the command that runs trafilatura. It outputs JSON, with Markdown-like formatting
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.command);
+        cmd.envs(self.env.iter().map(|(k, v)| (k, v)))
+            .arg("--json")
+            .arg("--formatting");
+        cmd
+    }
+
+    /// Runs trafilatura on the given HTML
+    async fn run_on_html(&self, page_html: &str) -> Result<ExtractedArticle, AnyError> {
+        let mut child = self
+            .command()
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("error starting trafulatura: {:?}", e))?;
+        // Write the HTML to the child process via stdin
+        let stdin = child
+            .stdin
+            .as_mut()
+            .ok_or(anyhow!("could not get stdin from child process"))?;
+        stdin
+            .write_all(page_html.as_bytes())
+            .await
+            .map_err(|e| anyhow!("error feeding article to child process: {:?}", e))?;
+
+        // See if the command failed
+        let output = child.output().await?;
+        if !output.status.success() {
+            return Err(anyhow!("Text extraction failed"));
+        }
+
+        tracing::debug!("trafilatura output length: {}", output.stdout.len());
+
+        serde_json::from_slice(&output.stdout)
+            .map_err(|e| anyhow!("Trafilatura output parsing failed: {:?}", e))
+    }
+}
+
+impl Extractor for Trafilatura {
+    fn name(&self) -> &str {
+        "trafilatura"
+    }
+
+    fn extract_html<'a>(
+        &'a self,
+        html: &'a str,
+    ) -> BoxFuture<'a, Result<ExtractedArticle, AnyError>> {
+        self.run_on_html(html).boxed()
+    }
+}
@@ -1,35 +1,202 @@
-use crate::tts::{GcpVoice, VoiceQuality, VoiceType};
+use crate::tts::{gcp::GcpVoice, VoiceInfo, VoiceQuality, VoiceTier, VoiceType};
 
-use anyhow::{bail, Error as AnyError};
+use std::collections::HashSet;
+
+use anyhow::{anyhow, bail, Error as AnyError};
+use clap::ValueEnum;
 use whatlang::Lang;
 
-/// Determines the language of the text and returns the ID of a voice matching the given sound
-/// quality. If the voice type is available, the voice will match that too.
-pub(crate) fn pick_tts_voice(
-    text: &str,
-    quality: VoiceQuality,
-    ty: VoiceType,
-) -> Result<&'static str, AnyError> {
-    // Try to determine the language. If it's inconclusive, default to English
-    let lang = whatlang::detect_lang(text).unwrap_or(Lang::Eng);
-    tracing::info!("detected language {lang}");
+/// The minimum confidence the language detector must have in a line's language for the line to
+/// be read in that language. Below this, the line is read in the article's dominant language.
+const MIN_LANG_CONFIDENCE: f64 = 0.9;
+
+/// A run of lines in a language other than the article's dominant one must be at least this many
+/// bytes long to get its own voice. Shorter runs are read in the dominant language, so that the
+/// voice doesn't flip-flop on every foreign phrase.
+const MIN_SEGMENT_LEN: usize = 200;
+
+/// The minimum confidence the language detector must have in an article's language to read the
+/// article in that language. Below this, the article isn't read, and the submitter is asked for its
+/// language.
+const MIN_ARTICLE_LANG_CONFIDENCE: f64 = 0.5;
+
+/// The language of an article, and how it was determined
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct ArticleLang {
+    pub(crate) lang: Lang,
+    /// The language detector's confidence in `lang`, from 0 to 1. This is `None` if the language
+    /// was given by the submitter
+    pub(crate) confidence: Option<f64>,
+}
+
+/// Determines the language of an article. If the submitter gave a BCP-47 language tag, that's the
+/// language. Otherwise, it's detected from the body. It's an error if the tag is for a language we
+/// don't know, or if the detector isn't confident. Better to ask than to read, say, Dutch with an
+/// English voice.
+pub(crate) fn article_lang(body: &str, tag: Option<&str>) -> Result<ArticleLang, AnyError> {
+    if let Some(tag) = tag {
+        let lang = lang_from_bcp47(tag).ok_or_else(|| anyhow!("Unsupported language '{tag}'"))?;
+        return Ok(ArticleLang {
+            lang,
+            confidence: None,
+        });
+    }
 
-    // Get the list of voices to choose from. If we chose high quality, then pick from Neural2,
-    // then Wavenet, then Standard if need be.
-    let list: Box<dyn Iterator<Item = &(Lang, GcpVoice)>> = match quality {
-        VoiceQuality::Standard => Box::new(STANDARD_VOICES.iter()),
-        VoiceQuality::High => Box::new(
-            VOICE_OVERRIDES
-                .iter()
-                .chain(NEURAL2_VOICES.iter())
-                .chain(WAVENET_VOICES.iter())
-                .chain(STANDARD_VOICES.iter()),
+    match whatlang::detect(body) {
+        Some(info) if info.confidence() >= MIN_ARTICLE_LANG_CONFIDENCE => {
+            tracing::info!(
+                "detected article language {} with confidence {:.2}",
+                info.lang().code(),
+                info.confidence()
+            );
+            Ok(ArticleLang {
+                lang: info.lang(),
+                confidence: Some(info.confidence()),
+            })
+        }
+        Some(info) => bail!(
+            "Couldn't tell what language the article is in. It might be {} ({:.0}% confidence). \
+            Set the article's language and try again.",
+            info.lang().eng_name(),
+            100.0 * info.confidence(),
         ),
+        None => bail!(
+            "Couldn't tell what language the article is in. Set the article's language and try \
+            again."
+        ),
+    }
+}
+
+/// Splits the text into runs of consecutive lines in the same language. Lines whose language can't
+/// be confidently detected, and runs that are too short, are assigned the `dominant` language.
+/// Returns the language and text of every run, in order. Adjacent runs always have different
+/// languages.
+pub(crate) fn segment_by_language(text: &str, dominant: Lang) -> Vec<(Lang, String)> {
+    // Group the lines into runs. Blank lines belong to whatever run they're in the middle of
+    let mut runs: Vec<(Lang, Vec<&str>)> = Vec::new();
+    for line in text.lines() {
+        let line_lang = if line.trim().is_empty() {
+            None
+        } else {
+            match whatlang::detect(line) {
+                Some(info) if info.confidence() >= MIN_LANG_CONFIDENCE => Some(info.lang()),
+                _ => Some(dominant),
+            }
+        };
+
+        match (runs.last_mut(), line_lang) {
+            (Some((_, lines)), None) => lines.push(line),
+            (Some((run_lang, lines)), Some(l)) if *run_lang == l => lines.push(line),
+            (_, l) => runs.push((l.unwrap_or(dominant), vec![line])),
+        }
+    }
+
+    // Give the short runs to the dominant language, and merge the runs that are now adjacent and
+    // in the same language
+    let mut segments: Vec<(Lang, String)> = Vec::new();
+    for (mut lang, lines) in runs {
+        let run_text = lines.join("\n");
+        if run_text.len() < MIN_SEGMENT_LEN {
+            lang = dominant;
+        }
+
+        match segments.last_mut() {
+            Some((seg_lang, seg_text)) if *seg_lang == lang => {
+                seg_text.push('\n');
+                seg_text.push_str(&run_text);
+            }
+            _ => segments.push((lang, run_text)),
+        }
+    }
+
+    tracing::info!(
+        "detected languages {:?}",
+        segments.iter().map(|(l, _)| l.code()).collect::<Vec<_>>()
+    );
+    segments
+}
+
+/// Returns the character that ends a sentence in the given language
+pub(crate) fn full_stop(lang: Lang) -> char {
+    match lang {
+        Lang::Cmn | Lang::Jpn => '。',
+        Lang::Hin | Lang::Mar | Lang::Nep => '।',
+        Lang::Urd => '۔',
+        _ => '.',
+    }
+}
+
+/// The parts of an article's spoken intro that can follow its title
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub(crate) enum IntroPart {
+    /// "By AUTHOR."
+    Author,
+    /// "From DOMAIN.", for articles that came from a URL
+    Source,
+}
+
+/// Returns how to say "By {}" and "From {}" in the given language. Languages we don't have
+/// phrases for get English.
+fn byline_phrases(lang: Lang) -> (&'static str, &'static str) {
+    match lang {
+        Lang::Deu => ("Von {}", "Quelle: {}"),
+        Lang::Fra => ("Par {}", "Source : {}"),
+        Lang::Spa => ("Por {}", "Fuente: {}"),
+        Lang::Ita => ("Di {}", "Fonte: {}"),
+        Lang::Por => ("Por {}", "Fonte: {}"),
+        Lang::Nld => ("Door {}", "Bron: {}"),
+        Lang::Cmn => ("作者：{}", "来源：{}"),
+        Lang::Jpn => ("著者：{}", "出典：{}"),
+        _ => ("By {}", "From {}"),
+    }
+}
+
+/// Returns the line that's read after an article's title, saying the given parts in the given
+/// language, e.g., "By Jane Doe. From example.com." Parts whose values are unknown are left out.
+/// Returns `None` if there's nothing to say.
+pub(crate) fn byline(
+    parts: &[IntroPart],
+    lang: Lang,
+    author: Option<&str>,
+    source: Option<&str>,
+) -> Option<String> {
+    let (by, from) = byline_phrases(lang);
+    let stop = full_stop(lang);
+
+    let sentences = parts
+        .iter()
+        .filter_map(|part| match part {
+            IntroPart::Author => author
+                .map(|a| by.replace("{}", &a.split_whitespace().collect::<Vec<_>>().join(" "))),
+            IntroPart::Source => source.map(|s| from.replace("{}", s.trim_start_matches("www."))),
+        })
+        .map(|s| format!("{s}{stop}"))
+        .collect::<Vec<_>>();
+
+    // Chinese and Japanese don't put spaces between sentences
+    let sep = if matches!(lang, Lang::Cmn | Lang::Jpn) {
+        ""
+    } else {
+        " "
     };
+    (!sentences.is_empty()).then(|| sentences.join(sep))
+}
 
-    // Collect all the voices in the desired language
-    let lang_voices: Vec<GcpVoice> = list
-        .filter_map(|v| if v.0 == lang { Some(v.1) } else { None })
+/// Returns the ID of a voice from `voices` that speaks the given language and matches the given
+/// sound quality. If the voice type is available, the voice will match that too. `voices` is
+/// assumed to be sorted in decreasing order of preference.
+pub(crate) fn pick_tts_voice(
+    voices: &[VoiceInfo],
+    lang: Lang,
+    quality: VoiceQuality,
+    ty: VoiceType,
+) -> Result<String, AnyError> {
+    // Collect all the voices in the desired language. If we chose standard quality, then only pick
+    // from the standard voices. Otherwise, pick from whatever's best.
+    let lang_voices: Vec<&VoiceInfo> = voices
+        .iter()
+        .filter(|v| v.lang == lang)
+        .filter(|v| quality == VoiceQuality::High || v.quality == quality)
         .collect();
 
     // If there are no supported voices in this langauge, error
@@ -44,7 +211,218 @@ pub(crate) fn pick_tts_voice(
         .unwrap_or(&lang_voices[0]);
 
     // Return the voice ID
-    Ok(voice.id)
+    Ok(voice.id.clone())
+}
+
+/// Returns all the Google Cloud voices. If we want high quality, we pick from Neural2, then
+/// Wavenet, then Standard if need be. So that's the order they appear in, after the overrides.
+/// The overrides are also in the tier tables, but every voice is listed once, where it first
+/// appears.
+pub(crate) fn gcp_voices() -> Vec<VoiceInfo> {
+    let overrides = VOICE_OVERRIDES
+        .iter()
+        .map(|v| (v, VoiceQuality::High, gcp_voice_tier(v.1.id)));
+    let neural2 = NEURAL2_VOICES
+        .iter()
+        .map(|v| (v, VoiceQuality::High, VoiceTier::Neural2));
+    let wavenet = WAVENET_VOICES
+        .iter()
+        .map(|v| (v, VoiceQuality::High, VoiceTier::Wavenet));
+    let standard = STANDARD_VOICES
+        .iter()
+        .map(|v| (v, VoiceQuality::Standard, VoiceTier::Standard));
+
+    let mut seen = HashSet::new();
+    overrides
+        .chain(neural2)
+        .chain(wavenet)
+        .chain(standard)
+        .filter(|(&(_, voice), _, _)| seen.insert(voice.id))
+        .map(|(&(lang, voice), quality, tier)| VoiceInfo {
+            id: voice.id.to_string(),
+            lang,
+            english_desc: voice.english_desc.to_string(),
+            quality,
+            ty: voice.ty,
+            tier,
+        })
+        .collect()
+}
+
+/// Returns the pricing tier of the Google Cloud voice with the given ID. This is determined by
+/// which table the voice is in.
+fn gcp_voice_tier(id: &str) -> VoiceTier {
+    let in_table = |table: &[(Lang, GcpVoice)]| table.iter().any(|(_, v)| v.id == id);
+    if in_table(NEURAL2_VOICES) {
+        VoiceTier::Neural2
+    } else if in_table(WAVENET_VOICES) {
+        VoiceTier::Wavenet
+    } else {
+        VoiceTier::Standard
+    }
+}
+
+/// The locales whose voices come first among the voices of their language, so that a language's
+/// default voice is from the region with the most speakers. This is the same as in gen_langs.py
+const MOST_COMMON_VARIANTS: &[&str] = &["en-US", "fr-FR", "es-US", "cmn-CN", "pt-BR", "nl-NL"];
+
+/// Returns the language of the given BCP-47 language tag, e.g., `pt-BR`. Returns `None` if the
+/// language isn't one that we detect.
+pub(crate) fn lang_from_bcp47(tag: &str) -> Option<Lang> {
+    let primary = tag.split(['-', '_']).next()?.to_ascii_lowercase();
+    let lang = match primary.as_str() {
+        "af" => Lang::Afr,
+        "ak" => Lang::Aka,
+        "am" => Lang::Amh,
+        "ar" => Lang::Ara,
+        "az" => Lang::Aze,
+        "be" => Lang::Bel,
+        "bg" => Lang::Bul,
+        "bn" => Lang::Ben,
+        "ca" => Lang::Cat,
+        "cs" => Lang::Ces,
+        "da" => Lang::Dan,
+        "de" => Lang::Deu,
+        "el" => Lang::Ell,
+        "en" => Lang::Eng,
+        "eo" => Lang::Epo,
+        "es" => Lang::Spa,
+        "et" => Lang::Est,
+        "fa" => Lang::Pes,
+        "fi" => Lang::Fin,
+        "fil" | "tl" => Lang::Tgl,
+        "fr" => Lang::Fra,
+        "gu" => Lang::Guj,
+        "he" | "iw" => Lang::Heb,
+        "hi" => Lang::Hin,
+        "hr" => Lang::Hrv,
+        "hu" => Lang::Hun,
+        "hy" => Lang::Hye,
+        "id" => Lang::Ind,
+        "it" => Lang::Ita,
+        "ja" => Lang::Jpn,
+        "jv" => Lang::Jav,
+        "ka" => Lang::Kat,
+        "km" => Lang::Khm,
+        "kn" => Lang::Kan,
+        "ko" => Lang::Kor,
+        "la" => Lang::Lat,
+        "lt" => Lang::Lit,
+        "lv" => Lang::Lav,
+        "mk" => Lang::Mkd,
+        "ml" => Lang::Mal,
+        "mr" => Lang::Mar,
+        "my" => Lang::Mya,
+        "nb" | "no" => Lang::Nob,
+        "ne" => Lang::Nep,
+        "nl" => Lang::Nld,
+        "or" => Lang::Ori,
+        "pa" => Lang::Pan,
+        "pl" => Lang::Pol,
+        "pt" => Lang::Por,
+        "ro" => Lang::Ron,
+        "ru" => Lang::Rus,
+        "si" => Lang::Sin,
+        "sk" => Lang::Slk,
+        "sl" => Lang::Slv,
+        "sn" => Lang::Sna,
+        "sr" => Lang::Srp,
+        "sv" => Lang::Swe,
+        "ta" => Lang::Tam,
+        "te" => Lang::Tel,
+        "th" => Lang::Tha,
+        "tk" => Lang::Tuk,
+        "tr" => Lang::Tur,
+        "uk" => Lang::Ukr,
+        "ur" => Lang::Urd,
+        "uz" => Lang::Uzb,
+        "vi" => Lang::Vie,
+        "yi" => Lang::Yid,
+        "zh" | "cmn" | "yue" => Lang::Cmn,
+        "zu" => Lang::Zul,
+        _ => return None,
+    };
+    Some(lang)
+}
+
+/// Returns the English description of the given Google Cloud locale, e.g., "English (UK)" for
+/// `en-GB`. Locales that aren't in the compiled-in tables are described by their language and
+/// region, e.g., "Turkish (TR)".
+fn gcp_locale_desc(locale: &str, lang: Lang) -> String {
+    let known_desc = VOICE_OVERRIDES
+        .iter()
+        .chain(NEURAL2_VOICES)
+        .chain(WAVENET_VOICES)
+        .chain(STANDARD_VOICES)
+        .find(|(_, v)| {
+            v.id.strip_prefix(locale)
+                .is_some_and(|rest| rest.starts_with('-'))
+        })
+        .map(|(_, v)| v.english_desc.to_string());
+
+    known_desc.unwrap_or_else(|| match locale.split_once('-') {
+        Some((_, region)) => format!("{} ({region})", lang.eng_name()),
+        None => lang.eng_name().to_string(),
+    })
+}
+
+/// Makes the Google Cloud voice list out of the voices listed by the voices API, given as
+/// `(locale, voice ID, voice type)`. The voices are ordered like in `gcp_voices`. Voices whose
+/// language we don't detect, or whose tier we don't know the price of, are left out.
+pub(crate) fn gcp_voices_from_list(listed: &[(String, String, VoiceType)]) -> Vec<VoiceInfo> {
+    // Sort the voices by tier, remembering whether they're in a common variant
+    let mut neural2 = Vec::new();
+    let mut wavenet = Vec::new();
+    let mut standard = Vec::new();
+    let mut overrides = Vec::new();
+    for (locale, id, ty) in listed {
+        let Some(lang) = lang_from_bcp47(locale) else {
+            tracing::debug!("skipping voice {id}: unsupported language");
+            continue;
+        };
+        let (tier_voices, quality, tier) = if id.contains("-Neural2-") {
+            (&mut neural2, VoiceQuality::High, VoiceTier::Neural2)
+        } else if id.contains("-Wavenet-") {
+            (&mut wavenet, VoiceQuality::High, VoiceTier::Wavenet)
+        } else if id.contains("-Standard-") {
+            (&mut standard, VoiceQuality::Standard, VoiceTier::Standard)
+        } else {
+            tracing::debug!("skipping voice {id}: unknown tier");
+            continue;
+        };
+
+        let voice = VoiceInfo {
+            id: id.clone(),
+            lang,
+            english_desc: gcp_locale_desc(locale, lang),
+            quality,
+            ty: *ty,
+            tier,
+        };
+        // An override is listed only among the overrides
+        if let Some(i) = VOICE_OVERRIDES.iter().position(|(_, v)| v.id == id) {
+            overrides.push((i, voice));
+            continue;
+        }
+        tier_voices.push((MOST_COMMON_VARIANTS.contains(&locale.as_str()), voice));
+    }
+
+    // The overrides come first, in the order they're given. Within each tier, the common variants
+    // come first. These are stable sorts, so the API's order is otherwise kept
+    overrides.sort_by_key(|(i, _)| *i);
+    for tier_voices in [&mut neural2, &mut wavenet, &mut standard] {
+        tier_voices.sort_by_key(|(is_common, _)| !is_common);
+    }
+    overrides
+        .into_iter()
+        .map(|(_, v)| v)
+        .chain(
+            [neural2, wavenet, standard]
+                .into_iter()
+                .flatten()
+                .map(|(_, v)| v),
+        )
+        .collect()
 }
 
 // The following code was generated by gen_langs.py
@@ -2556,3 +2934,100 @@ const NEURAL2_VOICES: &[(Lang, GcpVoice)] = &[
         },
     ),
 ];
+
+#[test]
+fn language_segmentation() {
+    let english = "It was the best of times, it was the worst of times, it was the age of wisdom, \
+        it was the age of foolishness, it was the epoch of belief, it was the epoch of incredulity, \
+        it was the season of Light, it was the season of Darkness.";
+    let french =
+        "Longtemps, je me suis couché de bonne heure. Parfois, à peine ma bougie éteinte, \
+        mes yeux se fermaient si vite que je n'avais pas le temps de me dire : Je m'endors. Et, \
+        une demi-heure après, la pensée qu'il était temps de chercher le sommeil m'éveillait.";
+    let spanish = "En un lugar de la Mancha, de cuyo nombre no quiero acordarme.";
+
+    // A long French quote gets its own segment. The blank line before it stays with the English
+    // segment
+    let text = [english, "", french, english].join("\n");
+    let segments = segment_by_language(&text, Lang::Eng);
+    let langs: Vec<Lang> = segments.iter().map(|(l, _)| *l).collect();
+    assert_eq!(langs, vec![Lang::Eng, Lang::Fra, Lang::Eng]);
+    assert_eq!(segments[0].1, format!("{english}\n"));
+    assert_eq!(segments[1].1, french);
+
+    // Joining the segments gives back the original text
+    let rejoined = segments
+        .iter()
+        .map(|(_, t)| t.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    assert_eq!(rejoined, text);
+
+    // A short Spanish phrase is read in the dominant language, and doesn't split up the article
+    let text = [english, spanish, english].join("\n");
+    let segments = segment_by_language(&text, Lang::Eng);
+    assert_eq!(segments.len(), 1);
+    assert_eq!(segments[0], (Lang::Eng, text));
+}
+
+#[test]
+fn article_intros() {
+    let parts = [IntroPart::Author, IntroPart::Source];
+
+    assert_eq!(
+        byline(
+            &parts,
+            Lang::Eng,
+            Some(" Jane\nDoe "),
+            Some("www.example.com")
+        )
+        .as_deref(),
+        Some("By Jane Doe. From example.com.")
+    );
+    assert_eq!(
+        byline(&parts, Lang::Deu, None, Some("zeit.de")).as_deref(),
+        Some("Quelle: zeit.de.")
+    );
+    assert_eq!(
+        byline(&parts, Lang::Cmn, Some("鲁迅"), Some("example.cn")).as_deref(),
+        Some("作者：鲁迅。来源：example.cn。")
+    );
+
+    // Parts that aren't configured, or aren't known, are left out
+    assert_eq!(byline(&[], Lang::Eng, Some("Jane Doe"), None), None);
+    assert_eq!(
+        byline(&[IntroPart::Source], Lang::Eng, Some("Jane Doe"), None),
+        None
+    );
+}
+
+#[test]
+fn article_language() {
+    let dutch = "Het was een koude, heldere dag in april en de klokken sloegen dertien. Winston \
+        Smith haastte zich, met zijn kin tegen zijn borst gedrukt om de gemene wind te ontwijken, \
+        door de glazen deuren van het flatgebouw.";
+
+    // The language is detected, and the detector is sure of it
+    let detected = article_lang(dutch, None).unwrap();
+    assert_eq!(detected.lang, Lang::Nld);
+    assert!(detected.confidence.unwrap() >= MIN_ARTICLE_LANG_CONFIDENCE);
+
+    // A given language overrides detection, whatever the region or case
+    for tag in ["nl", "nl-BE", "NL_nl"] {
+        let given = article_lang("Hello there", Some(tag)).unwrap();
+        assert_eq!(
+            given,
+            ArticleLang {
+                lang: Lang::Nld,
+                confidence: None
+            }
+        );
+    }
+    assert!(article_lang(dutch, Some("tlh")).is_err());
+
+    // Text the detector can't place is an error that asks for the language
+    for text in ["", "OK", "12 345 678"] {
+        let err = article_lang(text, None).unwrap_err();
+        assert!(err.to_string().contains("Set the article's language"));
+    }
+}
@@ -0,0 +1,443 @@
+//! The pronunciation lexicon. TTS engines mispronounce names, acronyms, and jargon, and spell out
+//! abbreviations like "e.g." letter by letter. The lexicon is a list of rules that rewrite article
+//! text before it's synthesized. A rule can substitute text, or leave a hint saying how the text
+//! should be read (see [`crate::tts::ssml`]). Rules apply to every article, or only to articles in
+//! a given language or from a given domain.
+//!
+//! The lexicon is saved in a JSON file, and can be changed at runtime via /api/lexicon. That API is
+//! unauthenticated, like the rest of the server, so restrict access to it at the reverse proxy if
+//! need be.
+
+use crate::{
+    error::RtmsError,
+    tts::ssml::{map_unhinted, phoneme_hint, sub_hint, HINT_CLOSE, HINT_OPEN, HINT_SEP},
+};
+use common::{LexiconEntry, LexiconRule, LexiconScope};
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Error as AnyError};
+use axum::{
+    extract::{Extension, Path as UrlPath},
+    routing::{delete, get},
+    Json, Router,
+};
+use regex::{Captures, NoExpand, Regex};
+use serde::{Deserialize, Serialize};
+use whatlang::Lang;
+
+/// A lexicon entry, along with the regex that matches its pattern
+struct CompiledEntry {
+    entry: LexiconEntry,
+    regex: Regex,
+}
+
+impl CompiledEntry {
+    /// Checks the entry and compiles its pattern
+    fn new(entry: LexiconEntry) -> Result<Self, AnyError> {
+        if entry.pattern.is_empty() {
+            bail!("Lexicon pattern is empty");
+        }
+
+        // Hints can't span lines, and can't contain the characters that delimit them or emphasis
+        let hint_args = match &entry.rule {
+            LexiconRule::Sub { alias } => vec![alias],
+            LexiconRule::Phoneme { alphabet, ph } => vec![alphabet, ph],
+            LexiconRule::Plain { .. } | LexiconRule::Regex { .. } => Vec::new(),
+        };
+        for arg in hint_args {
+            if arg.is_empty() || arg.contains(['\n', '*', HINT_OPEN, HINT_SEP, HINT_CLOSE]) {
+                bail!("Invalid pronunciation {arg:?}");
+            }
+        }
+
+        // Non-regex patterns match whole words. That is, if a pattern starts or ends with a word
+        // character, there has to be a word boundary there
+        let regex_str = match entry.rule {
+            LexiconRule::Regex { .. } => entry.pattern.clone(),
+            _ => {
+                let is_word_char = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+                let start = if is_word_char(entry.pattern.chars().next()) {
+                    r"\b"
+                } else {
+                    ""
+                };
+                let end = if is_word_char(entry.pattern.chars().last()) {
+                    r"\b"
+                } else {
+                    ""
+                };
+                format!("{start}{}{end}", regex::escape(&entry.pattern))
+            }
+        };
+        let regex = Regex::new(&regex_str)
+            .map_err(|e| anyhow!("Invalid lexicon pattern {:?}: {e}", entry.pattern))?;
+
+        Ok(CompiledEntry { entry, regex })
+    }
+
+    /// Returns whether this entry applies to an article in the given language from the given
+    /// domain
+    fn applies_to(&self, lang: Lang, domain: Option<&str>) -> bool {
+        match &self.entry.scope {
+            LexiconScope::Global => true,
+            LexiconScope::Lang(code) => lang.code().eq_ignore_ascii_case(code),
+            LexiconScope::Domain(scope) => domain.is_some_and(|d| {
+                let (d, scope) = (d.to_lowercase(), scope.to_lowercase());
+                d == scope || d.ends_with(&format!(".{scope}"))
+            }),
+        }
+    }
+
+    /// Applies this entry to the given text
+    fn apply(&self, text: &str) -> String {
+        match &self.entry.rule {
+            LexiconRule::Plain { replacement } => self
+                .regex
+                .replace_all(text, NoExpand(replacement))
+                .into_owned(),
+            LexiconRule::Regex { replacement } => self
+                .regex
+                .replace_all(text, replacement.as_str())
+                .into_owned(),
+            LexiconRule::Sub { alias } => self
+                .regex
+                .replace_all(text, |caps: &Captures| sub_hint(&caps[0], alias))
+                .into_owned(),
+            LexiconRule::Phoneme { alphabet, ph } => self
+                .regex
+                .replace_all(text, |caps: &Captures| phoneme_hint(&caps[0], alphabet, ph))
+                .into_owned(),
+        }
+    }
+}
+
+/// The contents of the lexicon file: the entries, and the ID the next added entry gets. IDs are
+/// never reused, so that a client holding the ID of a removed entry can't change some other entry
+/// with it
+#[derive(Deserialize, Serialize)]
+struct LexiconFile<E> {
+    #[serde(default)]
+    next_id: u64,
+    entries: Vec<E>,
+}
+
+/// The entries of the lexicon, and the ID the next added entry gets
+struct Entries {
+    next_id: u64,
+    list: Vec<CompiledEntry>,
+}
+
+/// The pronunciation lexicon, backed by a JSON file
+#[derive(Clone)]
+pub(crate) struct Lexicon {
+    path: Arc<PathBuf>,
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl Lexicon {
+    /// Opens the lexicon at the given path. If the file doesn't exist, the lexicon starts out
+    /// empty. Entries without an ID, or with one that's already taken, as in a hand-written file,
+    /// are given new IDs, and the lexicon is saved with them.
+    pub(crate) fn open(path: impl AsRef<Path>) -> Result<Self, AnyError> {
+        let path = path.as_ref();
+        let LexiconFile { next_id, entries } = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| anyhow!("Could not parse lexicon file {:?}: {e}", path))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => LexiconFile {
+                next_id: 1,
+                entries: Vec::new(),
+            },
+            Err(e) => Err(anyhow!("Could not read lexicon file {:?}: {e}", path))?,
+        };
+        let mut list = entries
+            .into_iter()
+            .map(CompiledEntry::new)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.context(format!("Bad entry in lexicon file {:?}", path)))?;
+
+        // The next ID is past every ID in use, even if the file says otherwise. Entries whose ID
+        // is missing or taken get the next ones, in the order they're in the file
+        let mut next_id = list
+            .iter()
+            .map(|e| e.entry.id + 1)
+            .fold(next_id.max(1), u64::max);
+        let mut taken = HashSet::new();
+        let mut renumbered = false;
+        for e in &mut list {
+            if e.entry.id == 0 || !taken.insert(e.entry.id) {
+                e.entry.id = next_id;
+                next_id += 1;
+                renumbered = true;
+            }
+        }
+        list.sort_by_key(|e| e.entry.id);
+
+        let lexicon = Lexicon {
+            path: Arc::new(path.to_path_buf()),
+            entries: Arc::new(Mutex::new(Entries { next_id, list })),
+        };
+        if renumbered {
+            lexicon.save(&lexicon.entries.lock().unwrap())?;
+        }
+        Ok(lexicon)
+    }
+
+    /// Returns every entry, in order
+    pub(crate) fn list(&self) -> Vec<LexiconEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries.list.iter().map(|e| e.entry.clone()).collect()
+    }
+
+    /// Adds the given entry to the end of the lexicon, and saves the lexicon. Returns the entry
+    /// with its newly assigned ID. IDs are never reused, even those of removed entries.
+    pub(crate) fn add(&self, mut entry: LexiconEntry) -> Result<LexiconEntry, AnyError> {
+        let mut entries = self.entries.lock().unwrap();
+        entry.id = entries.next_id;
+        entries.list.push(CompiledEntry::new(entry.clone())?);
+        entries.next_id += 1;
+
+        if let Err(e) = self.save(&entries) {
+            entries.list.pop();
+            entries.next_id -= 1;
+            return Err(e);
+        }
+        Ok(entry)
+    }
+
+    /// Removes the entry with the given ID, and saves the lexicon
+    pub(crate) fn remove(&self, id: u64) -> Result<(), AnyError> {
+        let mut entries = self.entries.lock().unwrap();
+        let idx = entries
+            .list
+            .iter()
+            .position(|e| e.entry.id == id)
+            .ok_or(anyhow!("No lexicon entry with ID {id}"))?;
+        let removed = entries.list.remove(idx);
+
+        if let Err(e) = self.save(&entries) {
+            entries.list.insert(idx, removed);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Writes the entries to the lexicon file. It's written to a temp file first, so that a crash
+    /// can't leave a partial file behind
+    fn save(&self, entries: &Entries) -> Result<(), AnyError> {
+        let file = LexiconFile {
+            next_id: entries.next_id,
+            entries: entries.list.iter().map(|e| &e.entry).collect(),
+        };
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(&file)?)?;
+        fs::rename(&tmp_path, self.path.as_ref())?;
+        Ok(())
+    }
+
+    /// Applies the lexicon to the text of an article in the given language from the given domain.
+    /// Entries are applied in order. Text that an earlier entry turned into a pronunciation hint
+    /// isn't touched by later entries.
+    pub(crate) fn apply(&self, text: &str, lang: Lang, domain: Option<&str>) -> String {
+        // Hint delimiters have no business being in article text. Remove them so they can't be
+        // mistaken for hints
+        let mut text = text.replace([HINT_OPEN, HINT_SEP, HINT_CLOSE], "");
+
+        let entries = self.entries.lock().unwrap();
+        for entry in entries.list.iter().filter(|e| e.applies_to(lang, domain)) {
+            text = map_unhinted(&text, |t| entry.apply(t));
+        }
+        text
+    }
+}
+
+// Sets the /api/lexicon routes
+pub(crate) fn setup(router: Router, lexicon: Lexicon) -> Router {
+    router.nest(
+        "/api",
+        Router::new()
+            .route("/lexicon", get(list_entries).post(add_entry))
+            .route("/lexicon/:id", delete(remove_entry))
+            .layer(Extension(lexicon)),
+    )
+}
+
+/// Lists the entries of the lexicon, in order
+async fn list_entries(Extension(lexicon): Extension<Lexicon>) -> Json<Vec<LexiconEntry>> {
+    Json(lexicon.list())
+}
+
+/// Adds an entry to the end of the lexicon, and returns it with its ID
+async fn add_entry(
+    Json(entry): Json<LexiconEntry>,
+    Extension(lexicon): Extension<Lexicon>,
+) -> Result<Json<LexiconEntry>, RtmsError> {
+    let entry = lexicon.add(entry)?;
+    tracing::info!("Added lexicon entry {}: {:?}", entry.id, entry.pattern);
+    Ok(Json(entry))
+}
+
+/// Removes the lexicon entry with the given ID
+async fn remove_entry(
+    UrlPath(id): UrlPath<u64>,
+    Extension(lexicon): Extension<Lexicon>,
+) -> Result<(), RtmsError> {
+    lexicon.remove(id)?;
+    tracing::info!("Removed lexicon entry {id}");
+    Ok(())
+}
+
+#[test]
+fn lexicon_rules() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("lexicon.json");
+    let lexicon = Lexicon::open(&path).unwrap();
+
+    let entry = |pattern: &str, rule: LexiconRule, scope: LexiconScope| LexiconEntry {
+        id: 0,
+        pattern: pattern.to_string(),
+        rule,
+        scope,
+    };
+    let plain = |replacement: &str| LexiconRule::Plain {
+        replacement: replacement.to_string(),
+    };
+
+    lexicon
+        .add(entry(
+            "e.g.",
+            LexiconRule::Sub {
+                alias: "for example".to_string(),
+            },
+            LexiconScope::Global,
+        ))
+        .unwrap();
+    let k8s = lexicon
+        .add(entry("k8s", plain("Kubernetes"), LexiconScope::Global))
+        .unwrap();
+    lexicon
+        .add(entry(
+            r"(\d+)km\b",
+            LexiconRule::Regex {
+                replacement: "$1 kilometers".to_string(),
+            },
+            LexiconScope::Lang("eng".to_string()),
+        ))
+        .unwrap();
+    lexicon
+        .add(entry(
+            "Nguyen",
+            LexiconRule::Phoneme {
+                alphabet: "ipa".to_string(),
+                ph: "wɪn".to_string(),
+            },
+            LexiconScope::Domain("example.com".to_string()),
+        ))
+        .unwrap();
+    // Later entries don't touch the hints of earlier ones
+    lexicon
+        .add(entry("example", plain("sample"), LexiconScope::Global))
+        .unwrap();
+    assert_eq!(k8s.id, 2);
+
+    // Bad regexes and bad pronunciations are rejected
+    assert!(lexicon
+        .add(entry(
+            "(",
+            LexiconRule::Regex {
+                replacement: String::new()
+            },
+            LexiconScope::Global
+        ))
+        .is_err());
+    assert!(lexicon
+        .add(entry(
+            "x",
+            LexiconRule::Sub {
+                alias: "a\nb".to_string()
+            },
+            LexiconScope::Global
+        ))
+        .is_err());
+    assert_eq!(lexicon.list().len(), 5);
+
+    // Entries respect word boundaries and scopes
+    let text = "Run k8s or k8ss 5km away, e.g. with Nguyen and an example.";
+    assert_eq!(
+        lexicon.apply(text, Lang::Eng, Some("blog.example.com")),
+        format!(
+            "Run Kubernetes or k8ss 5 kilometers away, {} with {} and an sample.",
+            sub_hint("e.g.", "for example"),
+            phoneme_hint("Nguyen", "ipa", "wɪn"),
+        )
+    );
+    assert_eq!(
+        lexicon.apply(text, Lang::Deu, Some("notexample.com")),
+        format!(
+            "Run Kubernetes or k8ss 5km away, {} with Nguyen and an sample.",
+            sub_hint("e.g.", "for example"),
+        )
+    );
+
+    // Removing an entry persists, and the IDs survive reopening
+    lexicon.remove(k8s.id).unwrap();
+    assert!(lexicon.remove(k8s.id).is_err());
+    let reopened = Lexicon::open(&path).unwrap();
+    let ids = reopened.list().iter().map(|e| e.id).collect::<Vec<_>>();
+    assert_eq!(ids, vec![1, 3, 4, 5]);
+    assert_eq!(reopened.apply("k8s", Lang::Eng, None), "k8s");
+
+    // The ID of a removed entry is never reused, even if it was the highest, and even after
+    // reopening
+    reopened.remove(5).unwrap();
+    let readded = reopened
+        .add(entry("example", plain("sample"), LexiconScope::Global))
+        .unwrap();
+    assert_eq!(readded.id, 6);
+    reopened.remove(6).unwrap();
+    let reopened = Lexicon::open(&path).unwrap();
+    let readded = reopened
+        .add(entry("example", plain("sample"), LexiconScope::Global))
+        .unwrap();
+    assert_eq!(readded.id, 7);
+
+    // In a hand-written file, entries without IDs, or with duplicate ones, get new IDs in file
+    // order, and the new IDs are saved. Every entry can then be removed by its ID
+    let written_path = dir.path().join("written.json");
+    fs::write(
+        &written_path,
+        r#"{"entries": [
+            {"pattern": "k8s", "kind": "plain", "replacement": "Kubernetes"},
+            {"id": 4, "pattern": "e.g.", "kind": "sub", "alias": "for example"},
+            {"pattern": "i.e.", "kind": "sub", "alias": "that is"},
+            {"id": 4, "pattern": "etc.", "kind": "plain", "replacement": "et cetera"}
+        ]}"#,
+    )
+    .unwrap();
+    let written = Lexicon::open(&written_path).unwrap();
+    let ids_and_patterns = |lexicon: &Lexicon| {
+        lexicon
+            .list()
+            .into_iter()
+            .map(|e| (e.id, e.pattern))
+            .collect::<Vec<_>>()
+    };
+    let expected = vec![
+        (4, "e.g.".to_string()),
+        (5, "k8s".to_string()),
+        (6, "i.e.".to_string()),
+        (7, "etc.".to_string()),
+    ];
+    assert_eq!(ids_and_patterns(&written), expected);
+    assert_eq!(
+        ids_and_patterns(&Lexicon::open(&written_path).unwrap()),
+        expected
+    );
+    written.remove(7).unwrap();
+    assert_eq!(written.list().len(), 3);
+}
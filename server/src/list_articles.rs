@@ -8,7 +8,7 @@ use std::{
     sync::{Arc, Mutex},
 };
 
-use common::{ArticleMetadata, LibraryCatalog};
+use common::{ArticleMetadata, AudioCodec, LibraryCatalog};
 
 use anyhow::bail;
 use axum::{
@@ -70,9 +70,9 @@ pub(crate) async fn get_rss(
             .map(chrono::DateTime::to_rfc2822)
             .unwrap_or(String::new());
 
-        // The URL where the MP3 for this item lives
-        let mp3_url = {
-            let filename = format!("{}.mp3", item.id);
+        // The URL where the audio for this item lives
+        let audio_url = {
+            let filename = format!("{}.{}", item.id, item.codec.extension());
             let encoded_title = urlencoding::encode(&filename);
             format!("http://localhost:9382/api/audio-blobs/{encoded_title}")
         };
@@ -108,7 +108,7 @@ pub(crate) async fn get_rss(
               <title>{item.title}</title>
               <pubDate>{datetime_added}</pubDate>
               |f| f.write_str(&duration_text)?;
-              <enclosure url={mp3_url} length="0" type="audio/mpeg" />
+              <enclosure url={audio_url} length="0" type={item.codec.mime_type()} />
               <itunes:explicit>"no"</itunes:explicit>
               <link />
               <itunes:episodeType>"full"</itunes:episodeType>
@@ -194,10 +194,9 @@ fn get_library_catalog(
             let entry = entry.unwrap();
             let path = entry.path();
 
-            // Don't list non-MP3 values
-            if path.extension() != Some(OsStr::new("mp3")) {
-                return None;
-            }
+            // Don't list anything that isn't audio
+            let ext = path.extension().and_then(OsStr::to_str);
+            ext.and_then(AudioCodec::from_extension)?;
 
             // Try to open the metadata cache
             if let Ok(mut cache) = metadata_cache.lock() {
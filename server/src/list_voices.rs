@@ -0,0 +1,89 @@
+use crate::tts::{SharedTtsEngine, VoiceInfo};
+
+use common::{VoiceCatalog, VoiceDescription, VoiceQuality, VoiceType};
+
+use axum::{
+    extract::{Extension, Query},
+    routing::get,
+    Json, Router,
+};
+use serde::Deserialize;
+
+/// The filters a client can put on the voice listing. Every field is optional
+#[derive(Default, Deserialize)]
+struct VoiceFilter {
+    /// The ISO 639-3 code of the language, e.g., "por"
+    lang: Option<String>,
+    quality: Option<VoiceQuality>,
+    pitch: Option<VoiceType>,
+}
+
+// Sets the /api/voices route
+pub(crate) fn setup(router: Router, tts_engine: SharedTtsEngine) -> Router {
+    router.nest(
+        "/api",
+        Router::new()
+            .route("/voices", get(list_voices))
+            .layer(Extension(tts_engine)),
+    )
+}
+
+/// Lists the voices the TTS engine offers, sorted by language. Within a language, the voices are
+/// in the order that voice selection prefers them.
+async fn list_voices(
+    Query(filter): Query<VoiceFilter>,
+    Extension(tts_engine): Extension<SharedTtsEngine>,
+) -> Json<VoiceCatalog> {
+    Json(voice_catalog(&tts_engine.list_voices(), &filter))
+}
+
+/// Converts the engine's voices to their API description, keeping only the ones that match the
+/// filter
+fn voice_catalog(voices: &[VoiceInfo], filter: &VoiceFilter) -> VoiceCatalog {
+    let mut descs: Vec<VoiceDescription> = voices
+        .iter()
+        .filter(|v| filter.lang.is_none() || filter.lang.as_deref() == Some(v.lang.code()))
+        .filter(|v| filter.quality.is_none() || filter.quality == Some(v.quality))
+        .filter(|v| filter.pitch.is_none() || filter.pitch == Some(v.ty))
+        .map(|v| VoiceDescription {
+            id: v.id.clone(),
+            lang: v.lang.code().to_string(),
+            language: v.english_desc.clone(),
+            quality: v.quality,
+            pitch: v.ty,
+        })
+        .collect();
+
+    // This is a stable sort, so the preference order within each language is preserved. Sorting
+    // by the description instead would put the locales of a language in alphabetical order
+    descs.sort_by(|a, b| a.lang.cmp(&b.lang));
+    VoiceCatalog(descs)
+}
+
+#[test]
+fn voice_filtering() {
+    let voices = crate::lang::gcp_voices();
+
+    // No filter lists everything
+    let all = voice_catalog(&voices, &VoiceFilter::default());
+    assert_eq!(all.0.len(), voices.len());
+    assert!(all.0.windows(2).all(|w| w[0].lang <= w[1].lang));
+
+    // Every voice is listed once, and the preferred voice of a language comes first
+    let ids: std::collections::HashSet<&str> = all.0.iter().map(|v| v.id.as_str()).collect();
+    assert_eq!(ids.len(), all.0.len());
+    let first_english = all.0.iter().find(|v| v.lang == "eng").unwrap();
+    assert_eq!(first_english.id, "en-US-Wavenet-B");
+
+    // Filter for standard high-pitched Portuguese voices
+    let filter = VoiceFilter {
+        lang: Some("por".to_string()),
+        quality: Some(VoiceQuality::Standard),
+        pitch: Some(VoiceType::HighPitch),
+    };
+    let some = voice_catalog(&voices, &filter);
+    assert!(!some.0.is_empty());
+    assert!(some.0.iter().all(|v| v.lang == "por"
+        && v.quality == VoiceQuality::Standard
+        && v.pitch == VoiceType::HighPitch));
+}
@@ -1,18 +1,29 @@
 mod add_article;
 mod error;
+mod extract;
 mod lang;
+mod lexicon;
 mod list_articles;
+mod list_voices;
+mod mp3;
+mod normalize;
+mod opus;
+mod quotes;
 mod tts;
+mod usage;
 mod util;
 
 use std::{
     future::ready,
     net::{IpAddr, Ipv6Addr, SocketAddr},
-    num::NonZeroU32,
+    num::{NonZeroU32, NonZeroUsize},
     path::PathBuf,
     str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
 
+use add_article::TtsContext;
 use axum::{
     body::Body,
     http::{HeaderValue, Request, StatusCode},
@@ -20,12 +31,25 @@ use axum::{
     routing::{get, get_service},
     Router,
 };
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use extract::{
+    readability::Readability, trafilatura::Trafilatura, Extractor, ExtractorKind, Extractors,
+};
+use lang::IntroPart;
+use lexicon::Lexicon;
+use normalize::{NormalizeRule, Normalizer};
 use tower::ServiceBuilder;
 use tower_http::{
     services::{ServeDir, ServeFile},
     trace::TraceLayer,
 };
+use tts::{
+    cache::ChunkCache,
+    gcp::{get_api_key, load_voices, refresh_voices, GcpTts},
+    local::{LocalSynth, LocalTts},
+    AudioCodec, AudioFormat, SectionCue, SectionCueKind, SharedTtsEngine, TtsEngineKind,
+};
+use usage::{TierPrices, UsageLog};
 
 #[derive(Parser, Debug)]
 #[clap(
@@ -33,6 +57,10 @@ use tower_http::{
     about = "The primary backend for Readtomyshoe"
 )]
 struct Opt {
+    /// An admin command to run instead of the server
+    #[clap(subcommand)]
+    command: Option<AdminCommand>,
+
     /// The log level
     #[clap(short = 'l', long = "log", default_value = "debug")]
     log_level: String,
@@ -57,27 +85,276 @@ struct Opt {
     #[clap(long = "audio-blob-dir", default_value = "audio_blobs")]
     audio_blob_dir: String,
 
+    /// The directory where synthesized chunks are cached. Resubmitting an article, or one that
+    /// differs only slightly, reuses the chunks that are the same, rather than paying for them again
+    #[clap(long = "chunk-cache-dir", default_value = "chunk_cache")]
+    chunk_cache_dir: String,
+
+    /// The most the chunk cache can hold, in megabytes. Past this, the least recently used chunks
+    /// are deleted
+    #[clap(long = "chunk-cache-max-mb", default_value = "1024")]
+    chunk_cache_max_mb: u64,
+
+    /// The file where TTS usage is recorded, per day and voice tier
+    #[clap(long = "usage-file", default_value = "usage.json")]
+    usage_file: PathBuf,
+
+    /// The file where the pronunciation lexicon is stored. It can be edited at runtime via
+    /// /api/lexicon
+    #[clap(long = "lexicon-file", default_value = "lexicon.json")]
+    lexicon_file: PathBuf,
+
+    /// The text normalization rules to skip, separated by commas. All are applied by default
+    #[clap(long = "skip-normalization", value_enum, value_delimiter = ',')]
+    skip_normalization: Vec<NormalizeRule>,
+
+    /// What to read after an article's title, separated by commas. "author" reads "By AUTHOR." if
+    /// the author is known, and "source" reads "From DOMAIN." if the article came from a URL. This
+    /// is localized to the article's language. By default, only the title is read
+    #[clap(long = "intro", value_enum, value_delimiter = ',')]
+    intro: Vec<IntroPart>,
+
+    /// The price of Standard voices, in US dollars per million characters. This is only used to
+    /// estimate costs in /api/usage
+    #[clap(long = "price-standard", default_value = "4")]
+    price_standard: f64,
+
+    /// The price of WaveNet voices, in US dollars per million characters
+    #[clap(long = "price-wavenet", default_value = "16")]
+    price_wavenet: f64,
+
+    /// The price of Neural2 voices, in US dollars per million characters
+    #[clap(long = "price-neural2", default_value = "16")]
+    price_neural2: f64,
+
     /// The limit on the number of article characters (bytes, really) the server will process per
     /// minute. The default is 5M because that's Google Cloud's limit. Use large values with
     /// caution: a malicious user can rack up your Google Cloud costs.
     #[clap(long = "max-chars-per-min", default_value = "5000000")]
     max_chars_per_min: NonZeroU32,
+
+    /// The largest file that can be uploaded as an article, in megabytes. Uploads are held in
+    /// memory while they're read, so keep this well under the server's memory
+    #[clap(long = "max-upload-mb", default_value = "50")]
+    max_upload_mb: usize,
+
+    /// The maximum number of chunks of an article to synthesize at once. Higher values make long
+    /// articles faster, but risk running into Google Cloud's per-minute request quota
+    #[clap(long = "max-concurrent-chunks", default_value = "4")]
+    max_concurrent_chunks: NonZeroUsize,
+
+    /// The text-to-speech engine to use
+    #[clap(long = "tts-engine", value_enum, default_value = "gcp")]
+    tts_engine: TtsEngineKind,
+
+    /// The base URL of the Google Cloud TTS API. Change this to point the gcp engine at a proxy or
+    /// a mock server
+    #[clap(
+        long = "gcp-api-base",
+        default_value = "https://texttospeech.googleapis.com/v1beta1"
+    )]
+    gcp_api_base: String,
+
+    /// The file the Google Cloud voices are loaded from, in the format of the API's voice list. If
+    /// it doesn't exist, the built-in voice list is used. Update it with the refresh-voices command
+    #[clap(long = "gcp-voices-file", default_value = "gcp_voices.json")]
+    gcp_voices_file: PathBuf,
+
+    /// The path to the synthesizer binary for the local TTS engines (espeak-ng and piper). By
+    /// default, the synthesizer is looked up in the PATH
+    #[clap(long = "tts-command")]
+    tts_command: Option<String>,
+
+    /// The directory where the Piper voice models (.onnx files) are to be found
+    #[clap(long = "piper-voice-dir", default_value = "piper_voices")]
+    piper_voice_dir: PathBuf,
+
+    /// The path to the ffmpeg binary. The local TTS engines use this for audio encoding
+    #[clap(long = "ffmpeg", default_value = "ffmpeg")]
+    ffmpeg_command: String,
+
+    /// The codec of synthesized articles. Either mp3 or ogg-opus. Opus files are about half the
+    /// size of MP3s
+    #[clap(long = "audio-codec", default_value = "mp3")]
+    audio_codec: AudioCodec,
+
+    /// The bitrate of synthesized articles, in kbps. Google Cloud only supports 32 and 64 for MP3,
+    /// and picks its own bitrate for Opus
+    #[clap(long = "audio-bitrate", default_value = "64")]
+    audio_bitrate: u32,
+
+    /// The audio cue to play between the sections of an article, i.e., before every heading but
+    /// the title. "silence" is a pause, and "earcon" is a pause with a short chime in it. Cues are
+    /// only played in MP3 audio. By default, there's no cue
+    #[clap(long = "section-cue", value_enum)]
+    section_cue: Option<SectionCueKind>,
+
+    /// The length of the pause in a section cue, in milliseconds
+    #[clap(long = "section-pause-ms", default_value = "1000")]
+    section_pause_ms: u64,
+
+    /// The article extractors to use, separated by commas. They're tried in order, until one
+    /// finds the article. Every extractor is tested at startup, and the server won't start if none
+    /// of them work
+    #[clap(
+        long = "extractors",
+        value_enum,
+        value_delimiter = ',',
+        default_value = "readability,trafilatura"
+    )]
+    extractors: Vec<ExtractorKind>,
+
+    /// The path to the trafilatura binary
+    #[clap(
+        long = "trafilatura-command",
+        default_value = "../python_deps/bin/trafilatura"
+    )]
+    trafilatura_command: PathBuf,
+
+    /// The environment variables to run trafilatura with, as KEY=VALUE, separated by commas. The
+    /// default is where the Docker image installs trafilatura's dependencies
+    #[clap(
+        long = "trafilatura-env",
+        value_parser = parse_env_var,
+        value_delimiter = ',',
+        default_value = "PYTHONPATH=../python_deps"
+    )]
+    trafilatura_env: Vec<(String, String)>,
+}
+
+/// Parses an environment variable given as KEY=VALUE
+fn parse_env_var(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("{s:?} isn't of the form KEY=VALUE")),
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum AdminCommand {
+    /// Downloads the current list of Google Cloud voices to the --gcp-voices-file, then exits. The
+    /// server reads with the new voices when it's restarted
+    RefreshVoices,
 }
 
 #[tokio::main]
 async fn main() {
     let opt = Opt::parse();
 
-    // Check up front that the Google cloud API key was set
-    tts::get_api_key().unwrap();
-
     // Setup logging & RUST_LOG from args
     if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", format!("{},hyper=info,mio=info", opt.log_level))
+        std::env::set_var(
+            "RUST_LOG",
+            format!(
+                "{},hyper=info,mio=info,html5ever=info,selectors=info",
+                opt.log_level
+            ),
+        )
     }
 
     tracing_subscriber::fmt::init();
 
+    // Run the admin command if there is one
+    if let Some(AdminCommand::RefreshVoices) = opt.command {
+        let num_voices = refresh_voices(
+            &get_api_key().unwrap(),
+            &opt.gcp_api_base,
+            &opt.gcp_voices_file,
+        )
+        .await
+        .unwrap();
+        tracing::info!(
+            "Saved {num_voices} usable voices to {:?}",
+            opt.gcp_voices_file
+        );
+        return;
+    }
+
+    // Set up the TTS engine. This checks up front that the engine is properly configured, e.g.,
+    // that the Google Cloud API key was set
+    let audio_format = AudioFormat {
+        codec: opt.audio_codec,
+        bitrate_kbps: opt.audio_bitrate,
+    };
+    let tts_engine: SharedTtsEngine = match opt.tts_engine {
+        TtsEngineKind::Gcp => Arc::new(
+            GcpTts::new(
+                get_api_key().unwrap(),
+                &opt.gcp_api_base,
+                load_voices(&opt.gcp_voices_file),
+                audio_format,
+            )
+            .unwrap(),
+        ),
+        TtsEngineKind::EspeakNg => Arc::new(
+            LocalTts::new(
+                LocalSynth::EspeakNg,
+                opt.tts_command.clone(),
+                opt.ffmpeg_command.clone(),
+                audio_format,
+            )
+            .unwrap(),
+        ),
+        TtsEngineKind::Piper => Arc::new(
+            LocalTts::new(
+                LocalSynth::Piper {
+                    voice_dir: opt.piper_voice_dir.clone(),
+                },
+                opt.tts_command.clone(),
+                opt.ffmpeg_command.clone(),
+                audio_format,
+            )
+            .unwrap(),
+        ),
+    };
+
+    let chunk_cache =
+        ChunkCache::new(&opt.chunk_cache_dir, opt.chunk_cache_max_mb * 1024 * 1024).unwrap();
+    let usage_log = UsageLog::open(&opt.usage_file).unwrap();
+    let lexicon = Lexicon::open(&opt.lexicon_file).unwrap();
+    let section_cue = opt.section_cue.map(|kind| SectionCue {
+        kind,
+        pause: Duration::from_millis(opt.section_pause_ms),
+    });
+    let tts_ctx = TtsContext::new(
+        chunk_cache,
+        usage_log.clone(),
+        Normalizer::new(&opt.skip_normalization),
+        lexicon.clone(),
+        opt.intro.clone(),
+        opt.max_concurrent_chunks,
+        section_cue,
+    );
+    // Set up the article extractors, and make sure at least one of them works
+    let extractors = Extractors::new(
+        opt.extractors
+            .iter()
+            .map(|kind| -> Box<dyn Extractor> {
+                match kind {
+                    ExtractorKind::Readability => Box::new(Readability),
+                    ExtractorKind::Trafilatura => Box::new(Trafilatura::new(
+                        opt.trafilatura_command.clone(),
+                        opt.trafilatura_env.clone(),
+                    )),
+                }
+            })
+            .collect(),
+    );
+    extractors.self_test().await.unwrap();
+
+    let tier_prices = TierPrices {
+        standard: opt.price_standard,
+        wavenet: opt.price_wavenet,
+        neural2: opt.price_neural2,
+    };
+
+    tracing::info!(
+        "Using the {:?} TTS engine with {} voices, outputting {}",
+        opt.tts_engine,
+        tts_engine.list_voices().len(),
+        tts_engine.output_codec(),
+    );
+
     // A generic error handler that just returns 500
     let ret_500 = |_| ready(StatusCode::INTERNAL_SERVER_ERROR);
 
@@ -99,7 +376,18 @@ async fn main() {
 
     // Set up /api/
     let app = list_articles::setup(app, &opt.audio_blob_dir);
-    let app = add_article::setup(app, opt.max_chars_per_min, &opt.audio_blob_dir);
+    let app = list_voices::setup(app, tts_engine.clone());
+    let app = usage::setup(app, usage_log, tier_prices);
+    let app = lexicon::setup(app, lexicon);
+    let app = add_article::setup(
+        app,
+        opt.max_chars_per_min,
+        opt.max_upload_mb * 1024 * 1024,
+        &opt.audio_blob_dir,
+        extractors,
+        tts_engine,
+        tts_ctx,
+    );
 
     // Make a /healthz endpoint for Docker health checks
     let app = app.route("/healthz", get(|| async { "ok" }));
@@ -0,0 +1,456 @@
+//! Handles MP3 files. TTS engines return one MP3 stream per chunk of text. Concatenating them
+//! plays, but any Xing/Info header a chunk starts with ends up in the middle of the file, and
+//! nothing says how long the file is as a whole. Players then estimate the duration from the first
+//! frame's bitrate, and seek inaccurately. So we walk the frames of every chunk and write them out
+//! as one stream, behind a single Xing/Info header that describes all of it.
+//!
+//! Encoders pad every stream with silence at both ends: the encoder delay at the start, and padding
+//! at the end. A LAME tag in the Xing/Info header says how long these are, so that players can
+//! skip them. When joining chunks, the frames at the end of a chunk that are nothing but padding
+//! are dropped. What's left of the padding, and the delay of every chunk but the first, is less
+//! than a frame, and stays in the joined stream, since only whole frames can be dropped without
+//! re-encoding. The header gets a LAME tag with the delay of the first chunk and the padding of the
+//! last.
+//!
+//! The frames of other audio can be spliced in between chunks, as long as it's in the same format.
+//! That's how the cues between the sections of an article are made.
+
+use std::{
+    io::{Cursor, ErrorKind},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Error as AnyError};
+use byteorder::{BigEndian, ByteOrder};
+use symphonia_bundle_mp3::MpaReader;
+use symphonia_core::{
+    checksum::Crc16AnsiLe,
+    errors::Error as SymphoniaError,
+    formats::{FormatOptions, FormatReader},
+    io::{MediaSourceStream, Monitor},
+};
+
+/// The length of an MPEG audio frame header
+const HEADER_LEN: usize = 4;
+
+/// The length of the Xing/Info tag we write: the ID, the flags, the frame count, the byte count,
+/// the 100-entry seek table, the quality, and the LAME tag
+const TAG_LEN: usize = 4 + 4 + 4 + 4 + 100 + 4 + LAME_TAG_LEN;
+
+/// The Xing tag flags saying that the frame count, byte count, seek table, and quality are present
+const TAG_FLAGS: u32 = 0x1 | 0x2 | 0x4 | 0x8;
+
+/// The length of the LAME tag, which follows the Xing/Info tag
+const LAME_TAG_LEN: usize = 36;
+
+/// The encoder name in the LAME tag. Players only read the delay and padding if it starts with
+/// "LAME"
+const LAME_ENCODER: &[u8; 9] = b"LAME3.100";
+
+/// The number of samples an MP3 decoder outputs before the first sample of audio. The delay and
+/// padding in a LAME tag leave this out
+const DECODER_DELAY: u32 = 529;
+
+/// The largest delay or padding a LAME tag can hold. They're 12-bit fields
+const MAX_LAME_TRIM: u32 = 0xFFF;
+
+/// The bits of a frame header that have to match for frames to be in the same stream. These are
+/// the sync word, MPEG version, layer, sample rate, and channel mode
+const FORMAT_MASK: u32 = 0xFFFE_0CC0;
+
+/// Layer III bitrates in kbps, indexed by the header's bitrate index. Index 0 is "free format"
+const MPEG1_BITRATES: [u32; 15] = [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+];
+const MPEG2_BITRATES: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+/// Sample rates in Hz, indexed by the header's version bits, then its sample rate index. Version
+/// bits 0b01 are reserved
+const SAMPLE_RATES: [[u32; 3]; 4] = [
+    [11025, 12000, 8000],
+    [0, 0, 0],
+    [22050, 24000, 16000],
+    [44100, 48000, 32000],
+];
+
+/// The earcon that can be played between the sections of an article: a two-note chime, 0.58s
+/// long, in MPEG-1 Layer III at 64kbps, 48kHz, mono. It's made by `gen_chime.py`
+pub(crate) const CHIME: &[u8] = include_bytes!("../assets/chime.mp3");
+
+/// A single MPEG audio frame, header included
+struct Frame {
+    data: Box<[u8]>,
+    /// The number of samples this frame decodes to
+    num_samples: u64,
+}
+
+impl Frame {
+    fn header(&self) -> u32 {
+        BigEndian::read_u32(&self.data[..HEADER_LEN])
+    }
+
+    fn bitrate_idx(&self) -> usize {
+        ((self.header() >> 12) & 0xF) as usize
+    }
+}
+
+/// Returns whether the frame header is MPEG-1, as opposed to MPEG-2 or 2.5
+fn is_mpeg1(header: u32) -> bool {
+    (header >> 19) & 0b11 == 0b11
+}
+
+/// Returns the sample rate of the frame header, in Hz
+fn sample_rate(header: u32) -> u32 {
+    SAMPLE_RATES[((header >> 19) & 0b11) as usize][((header >> 10) & 0b11) as usize]
+}
+
+/// Returns the size of a Layer III frame with the given header and bitrate index, without padding.
+/// This is 144 * bitrate / sample rate for MPEG-1, and half that for MPEG-2 and 2.5
+fn frame_size(header: u32, bitrate_idx: usize) -> usize {
+    let (bitrates, size_factor) = if is_mpeg1(header) {
+        (&MPEG1_BITRATES, 144_000)
+    } else {
+        (&MPEG2_BITRATES, 72_000)
+    };
+    (size_factor * bitrates[bitrate_idx] / sample_rate(header)) as usize
+}
+
+/// The audio frames of an MP3 stream, and how much of what they decode to isn't audio
+struct Stream {
+    frames: Vec<Frame>,
+    /// The number of samples at the start that aren't audio, decoder delay included. This is 0 if
+    /// the stream has no LAME tag
+    delay: u32,
+    /// The number of samples at the end that aren't audio. This is 0 if the stream has no LAME tag
+    padding: u32,
+}
+
+/// Reads the audio frames of the given MP3 stream. ID3 tags, junk, and Xing/Info/VBRI header
+/// frames are skipped. Fails if the stream can't be read, or ends in the middle of a frame.
+fn read_stream(bytes: &[u8]) -> Result<Stream, AnyError> {
+    let src = MediaSourceStream::new(Box::new(Cursor::new(bytes.to_vec())), Default::default());
+    // The reader consumes a header frame at the start of the stream, and discards any others
+    let mut reader = MpaReader::try_new(src, &FormatOptions::default())?;
+    let params = &reader.tracks()[0].codec_params;
+    let (delay, padding) = (params.delay.unwrap_or(0), params.padding.unwrap_or(0));
+
+    let mut frames = Vec::new();
+    loop {
+        match reader.next_packet() {
+            Ok(packet) => frames.push(Frame {
+                num_samples: packet.dur,
+                data: packet.data,
+            }),
+            Err(SymphoniaError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    // The reader also stops at a frame that's cut short. The stream should end with the last
+    // frame, or an ID3v1 tag after it
+    let end = match bytes.len().checked_sub(128) {
+        Some(tag_start) if bytes[tag_start..].starts_with(b"TAG") => &bytes[..tag_start],
+        _ => bytes,
+    };
+    if frames.last().is_some_and(|f| !end.ends_with(&f.data)) {
+        bail!("MP3 stream ends in the middle of a frame");
+    }
+
+    Ok(Stream {
+        frames,
+        delay,
+        padding,
+    })
+}
+
+/// Returns the audio frames of the given MP3 stream. See [`read_stream`].
+fn read_frames(bytes: &[u8]) -> Result<Vec<Frame>, AnyError> {
+    Ok(read_stream(bytes)?.frames)
+}
+
+/// Makes a frame holding a Xing/Info tag that describes the given audio frames, and a LAME tag
+/// saying how many samples at the start and end aren't audio. `delay` includes the decoder delay.
+/// The frame has the same format as the audio, and decodes to silence if a player doesn't know to
+/// skip it.
+fn make_tag_frame(frames: &[Frame], delay: u32, padding: u32) -> Result<Vec<u8>, AnyError> {
+    let first = &frames[0];
+    let header = first.header();
+    let is_mono = (header >> 6) & 0b11 == 0b11;
+    if (header >> 17) & 0b11 != 0b01 {
+        bail!("Only MPEG Layer III audio can have a Xing header");
+    }
+
+    // The tag goes right after the side info, whose length depends on the version and channels
+    let side_info_len = match (is_mpeg1(header), is_mono) {
+        (true, true) => 17,
+        (true, false) => 32,
+        (false, true) => 9,
+        (false, false) => 17,
+    };
+    let tag_offset = HEADER_LEN + side_info_len;
+
+    // Use the audio's bitrate if the tag fits in a frame that size. Otherwise use the smallest
+    // bitrate it does fit in
+    let (bitrate_idx, frame_size) = (first.bitrate_idx().max(1)..MPEG1_BITRATES.len())
+        .map(|idx| (idx, frame_size(header, idx)))
+        .find(|&(_, size)| size >= tag_offset + TAG_LEN)
+        .ok_or(anyhow!("MP3 frames are too small to hold a Xing header"))?;
+
+    let total_bytes = frame_size + frames.iter().map(|f| f.data.len()).sum::<usize>();
+    let total_samples: u64 = frames.iter().map(|f| f.num_samples).sum();
+
+    // Entry i of the seek table is the position of the frame playing i% of the way into the
+    // audio, as a fraction of the file size, scaled to 0..256
+    let mut toc = [0u8; 100];
+    let mut frame_iter = frames.iter().peekable();
+    let (mut sample_pos, mut byte_pos) = (0, frame_size as u64);
+    for (i, entry) in toc.iter_mut().enumerate() {
+        let target = i as u64 * total_samples / 100;
+        while let Some(f) = frame_iter.peek() {
+            if sample_pos + f.num_samples > target {
+                break;
+            }
+            sample_pos += f.num_samples;
+            byte_pos += f.data.len() as u64;
+            frame_iter.next();
+        }
+        *entry = (byte_pos * 256 / total_bytes as u64).min(255) as u8;
+    }
+
+    // Copy the audio's header, with our bitrate, no padding, and no CRC
+    let mut frame = vec![0u8; frame_size];
+    let tag_header = (header & !0xF200) | (bitrate_idx as u32) << 12 | 0x1_0000;
+    BigEndian::write_u32(&mut frame[..HEADER_LEN], tag_header);
+
+    // Constant bitrate streams get an Info tag rather than a Xing tag
+    let is_cbr = frames
+        .iter()
+        .all(|f| f.bitrate_idx() == first.bitrate_idx());
+    let tag = &mut frame[tag_offset..tag_offset + TAG_LEN];
+    tag[..4].copy_from_slice(if is_cbr { b"Info" } else { b"Xing" });
+    BigEndian::write_u32(&mut tag[4..8], TAG_FLAGS);
+    BigEndian::write_u32(&mut tag[8..12], frames.len() as u32);
+    BigEndian::write_u32(&mut tag[12..16], total_bytes as u32);
+    tag[16..116].copy_from_slice(&toc);
+    // The quality is unknown. Leave it 0
+
+    // The LAME tag. Only the encoder, the VBR method, the delay and padding, the length, and the
+    // checksums are filled in
+    let lame = &mut tag[120..];
+    lame[..9].copy_from_slice(LAME_ENCODER);
+    lame[9] = if is_cbr { 1 } else { 0 };
+    let delay = delay.saturating_sub(DECODER_DELAY).min(MAX_LAME_TRIM);
+    let padding = (padding + DECODER_DELAY).min(MAX_LAME_TRIM);
+    BigEndian::write_u24(&mut lame[21..24], delay << 12 | padding);
+    BigEndian::write_u32(&mut lame[28..32], total_bytes as u32);
+    let mut music_crc = Crc16AnsiLe::new(0);
+    for f in frames {
+        music_crc.process_buf_bytes(&f.data);
+    }
+    BigEndian::write_u16(&mut lame[32..34], music_crc.crc());
+    // The tag's checksum covers the whole frame up to it
+    let crc_offset = tag_offset + TAG_LEN - 2;
+    let mut tag_crc = Crc16AnsiLe::new(0);
+    tag_crc.process_buf_bytes(&frame[..crc_offset]);
+    BigEndian::write_u16(&mut frame[crc_offset..crc_offset + 2], tag_crc.crc());
+
+    Ok(frame)
+}
+
+/// Joins the given MP3 streams into one. The audio frames of every stream are kept, except for the
+/// ones that are all padding, and their tags and headers are replaced with a single Xing/Info
+/// header at the front. All the streams must have the same sample rate and channel count.
+pub(crate) fn concat(chunks: &[&[u8]]) -> Result<Vec<u8>, AnyError> {
+    let mut frames = Vec::new();
+    let (mut delay, mut padding) = (None, 0);
+    for chunk in chunks {
+        let mut stream = read_stream(chunk)?;
+
+        // Drop the frames at the end that are nothing but padding
+        let mut padding_left = u64::from(stream.padding);
+        while let Some(last) = stream.frames.last() {
+            if last.num_samples > padding_left {
+                break;
+            }
+            padding_left -= last.num_samples;
+            stream.frames.pop();
+        }
+
+        if !stream.frames.is_empty() {
+            delay.get_or_insert(stream.delay);
+            padding = padding_left as u32;
+        }
+        frames.extend(stream.frames);
+    }
+
+    let first = frames.first().ok_or(anyhow!("No MP3 frames to concat"))?;
+    let format = first.header() & FORMAT_MASK;
+    if frames.iter().any(|f| f.header() & FORMAT_MASK != format) {
+        bail!("MP3 chunks differ in sample rate or channel count");
+    }
+
+    let mut out = make_tag_frame(&frames, delay.unwrap_or(0), padding)?;
+    for frame in &frames {
+        out.extend_from_slice(&frame.data);
+    }
+
+    Ok(out)
+}
+
+/// Returns whether the two MP3 streams have the same sample rate and channel count, i.e., whether
+/// they can be joined with [`concat`]
+pub(crate) fn same_format(a: &[u8], b: &[u8]) -> Result<bool, AnyError> {
+    let format = |bytes| -> Result<u32, AnyError> {
+        let frames = read_frames(bytes)?;
+        let first = frames.first().ok_or(anyhow!("No MP3 frames"))?;
+        Ok(first.header() & FORMAT_MASK)
+    };
+    Ok(format(a)? == format(b)?)
+}
+
+/// Makes an MP3 stream of silence that lasts at least the given duration, in the same format as
+/// the given stream. The silent frames have the stream's header, minus any CRC and padding, and
+/// empty side info, so they decode to nothing at all.
+pub(crate) fn silence_like(stream: &[u8], duration: Duration) -> Result<Vec<u8>, AnyError> {
+    let frames = read_frames(stream)?;
+    let first = frames.first().ok_or(anyhow!("No MP3 frames to imitate"))?;
+    if first.bitrate_idx() == 0 {
+        bail!("Can't make silence in free format MP3");
+    }
+    let header = (first.header() & !0x200) | 0x1_0000;
+
+    let samples_per_frame = if is_mpeg1(header) { 1152 } else { 576 };
+    let num_samples = (duration.as_secs_f64() * sample_rate(header) as f64).ceil() as usize;
+    let num_frames = num_samples.div_ceil(samples_per_frame);
+
+    let mut frame = vec![0u8; frame_size(header, first.bitrate_idx())];
+    BigEndian::write_u32(&mut frame[..HEADER_LEN], header);
+    Ok(frame.repeat(num_frames))
+}
+
+#[test]
+fn mp3_assembly() {
+    use crate::tts::mock::{silent_mp3_frames, MP3_FRAME_SIZE};
+
+    // Returns the number of samples the header says there are, and the number of audio frames
+    fn read_back(bytes: &[u8]) -> (Option<u64>, usize) {
+        let src = MediaSourceStream::new(Box::new(Cursor::new(bytes.to_vec())), Default::default());
+        let mut reader = MpaReader::try_new(src, &FormatOptions::default()).unwrap();
+        let n_frames = reader.tracks()[0].codec_params.n_frames;
+        let mut num_packets = 0;
+        while reader.next_packet().is_ok() {
+            num_packets += 1;
+        }
+        (n_frames, num_packets)
+    }
+
+    // Join 3 streams. The result is the audio frames of all of them, plus one frame for the header
+    let chunks = [
+        silent_mp3_frames(10),
+        silent_mp3_frames(250),
+        silent_mp3_frames(1),
+    ];
+    let joined = concat(&chunks.iter().map(Vec::as_slice).collect::<Vec<_>>()).unwrap();
+    assert_eq!(joined.len(), 262 * MP3_FRAME_SIZE);
+    assert_eq!(read_back(&joined), (Some(261 * 1152), 261));
+
+    // The header is an Info tag, since the bitrate is constant. It counts the bytes of the whole
+    // file, and its seek table is increasing
+    let tag = &joined[HEADER_LEN + 17..];
+    assert_eq!(&tag[..4], b"Info");
+    assert_eq!(BigEndian::read_u32(&tag[12..16]) as usize, joined.len());
+    let toc = &tag[16..116];
+    assert!(toc.windows(2).all(|w| w[0] <= w[1]));
+    assert_eq!(toc[50], 128);
+
+    // Joining an assembled stream drops its old header
+    let rejoined = concat(&[&joined, &chunks[0]]).unwrap();
+    assert_eq!(rejoined.len(), 272 * MP3_FRAME_SIZE);
+    assert_eq!(read_back(&rejoined), (Some(271 * 1152), 271));
+
+    // 32kbps frames are too small for the tag. The header frame gets a higher bitrate
+    let mut small_frame = [0u8; 96];
+    small_frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x14, 0xC4]);
+    let small = small_frame.repeat(20);
+    let joined = concat(&[&small]).unwrap();
+    assert!(joined.len() > small.len() + small_frame.len());
+    assert_eq!(read_back(&joined), (Some(20 * 1152), 20));
+
+    // A chunk that's cut off in the middle of a frame is an error, not a shorter chunk
+    let cut = &chunks[1][..chunks[1].len() - 50];
+    assert!(concat(&[&chunks[0], cut]).is_err());
+}
+
+#[test]
+fn mp3_gapless_joins() {
+    use crate::tts::mock::{silent_mp3_frames, MP3_FRAME_SIZE};
+
+    // Makes a chunk of audio frames whose LAME tag gives the delay and padding
+    let tagged_chunk = |num_frames: usize, delay: u32, padding: u32| {
+        let audio = silent_mp3_frames(num_frames);
+        let frames = read_frames(&audio).unwrap();
+        [make_tag_frame(&frames, delay, padding).unwrap(), audio].concat()
+    };
+    // Returns the delay and padding the header of the stream gives
+    let trim = |bytes: &[u8]| {
+        let stream = read_stream(bytes).unwrap();
+        (stream.delay, stream.padding)
+    };
+
+    // The delay includes the decoder delay. The tag gets it right
+    let first = tagged_chunk(10, 1105, 1252);
+    assert_eq!(trim(&first), (1105, 1252));
+
+    // The first chunk's last frame is all padding, so it's dropped. The joined stream starts with
+    // the first chunk's delay, and ends with the second chunk's padding
+    let second = tagged_chunk(5, 1105, 700);
+    let joined = concat(&[&first, &second]).unwrap();
+    assert_eq!(joined.len(), (1 + 9 + 5) * MP3_FRAME_SIZE);
+    assert_eq!(trim(&joined), (1105, 700));
+
+    // The padding left after dropping whole frames ends up in the tag
+    let joined = concat(&[&second, &first]).unwrap();
+    assert_eq!(joined.len(), (1 + 5 + 9) * MP3_FRAME_SIZE);
+    assert_eq!(trim(&joined), (1105, 100));
+}
+
+#[test]
+fn cue_audio() {
+    use crate::tts::mock::{silent_mp3_frames, MP3_FRAME_SIZE};
+    use symphonia_bundle_mp3::MpaDecoder;
+    use symphonia_core::{
+        audio::SampleBuffer,
+        codecs::{CodecParameters, Decoder, DecoderOptions, CODEC_TYPE_MP3},
+    };
+
+    // Silence is a whole number of frames. A second at 48kHz is 41.7 frames of 1152 samples
+    let silence = silence_like(&silent_mp3_frames(3), Duration::from_secs(1)).unwrap();
+    assert_eq!(silence.len(), 42 * MP3_FRAME_SIZE);
+    assert!(same_format(&silence, &silent_mp3_frames(1)).unwrap());
+
+    // The silent frames take the stream's bitrate, but not its padding. These are padded 32kbps
+    // frames, which are 96 bytes without the padding byte
+    let mut padded_frame = [0u8; 97];
+    padded_frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x16, 0xC4]);
+    let silence = silence_like(&padded_frame.repeat(2), Duration::from_millis(100)).unwrap();
+    assert_eq!(silence.len(), 5 * 96);
+    assert_eq!(&silence[..4], &[0xFF, 0xFB, 0x14, 0xC4]);
+
+    // The chime can be joined with what the mock engine outputs, and it's audible
+    assert!(same_format(CHIME, &silent_mp3_frames(1)).unwrap());
+    let mut decoder = MpaDecoder::try_new(
+        CodecParameters::default().for_codec(CODEC_TYPE_MP3),
+        &DecoderOptions::default(),
+    )
+    .unwrap();
+    let src = MediaSourceStream::new(Box::new(Cursor::new(CHIME.to_vec())), Default::default());
+    let mut reader = MpaReader::try_new(src, &FormatOptions::default()).unwrap();
+    let mut peak = 0f32;
+    while let Ok(packet) = reader.next_packet() {
+        let decoded = decoder.decode(&packet).unwrap();
+        let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+        samples.copy_interleaved_ref(decoded);
+        peak = samples.samples().iter().fold(peak, |p, s| p.max(s.abs()));
+    }
+    assert!(peak > 0.1 && peak < 0.5);
+}
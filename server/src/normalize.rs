@@ -0,0 +1,367 @@
+//! Cleans up article text before it's synthesized. Extracted text is full of things that read
+//! badly aloud: bare URLs get spelled out, footnote markers like "[12]" and emoji get read, and
+//! separator lines like "* * *" get read as punctuation. Each of these is handled by a rule, and
+//! every rule can be turned off with `--skip-normalization`.
+
+use std::sync::LazyLock;
+
+use clap::ValueEnum;
+use regex::{Captures, Regex};
+use whatlang::Lang;
+
+/// A normalization rule
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub(crate) enum NormalizeRule {
+    /// Remove lines that are just a repeated separator, like "---" or "* * *"
+    Separators,
+    /// Replace URLs with their domain
+    Urls,
+    /// Remove citation markers like "[12]", "[3–5]", and "[citation needed]"
+    Citations,
+    /// Remove emoji
+    Emoji,
+    /// Replace curly quotes with straight ones
+    Quotes,
+    /// Expand common abbreviations, like "e.g.", in the article's language
+    Abbreviations,
+    /// Collapse runs of whitespace, and remove zero-width characters
+    Whitespace,
+}
+
+impl NormalizeRule {
+    /// Every rule, in the order they're applied. Whitespace goes last, since the other rules leave
+    /// gaps behind.
+    const ALL: [NormalizeRule; 7] = [
+        NormalizeRule::Separators,
+        NormalizeRule::Urls,
+        NormalizeRule::Citations,
+        NormalizeRule::Emoji,
+        NormalizeRule::Quotes,
+        NormalizeRule::Abbreviations,
+        NormalizeRule::Whitespace,
+    ];
+
+    /// Applies this rule to the text of an article in the given language
+    fn apply(self, text: &str, lang: Lang) -> String {
+        match self {
+            NormalizeRule::Separators => remove_separators(text),
+            NormalizeRule::Urls => collapse_urls(text),
+            NormalizeRule::Citations => remove_citations(text),
+            NormalizeRule::Emoji => remove_emoji(text),
+            NormalizeRule::Quotes => straighten_quotes(text),
+            NormalizeRule::Abbreviations => expand_abbreviations(text, lang),
+            NormalizeRule::Whitespace => normalize_whitespace(text),
+        }
+    }
+}
+
+/// Applies the enabled normalization rules to article text
+#[derive(Clone, Debug)]
+pub(crate) struct Normalizer {
+    rules: Vec<NormalizeRule>,
+}
+
+impl Normalizer {
+    /// Makes a normalizer that applies every rule except the given ones
+    pub(crate) fn new(skipped: &[NormalizeRule]) -> Self {
+        let rules = NormalizeRule::ALL
+            .into_iter()
+            .filter(|r| !skipped.contains(r))
+            .collect();
+        Normalizer { rules }
+    }
+
+    /// Normalizes the text of an article in the given language
+    pub(crate) fn normalize(&self, text: &str, lang: Lang) -> String {
+        self.rules
+            .iter()
+            .fold(text.to_string(), |text, rule| rule.apply(&text, lang))
+    }
+}
+
+impl Default for Normalizer {
+    fn default() -> Self {
+        Normalizer::new(&[])
+    }
+}
+
+/// Removes the lines that consist of at least 3 separator characters and nothing else
+fn remove_separators(text: &str) -> String {
+    text.lines()
+        .map(|line| {
+            let mut chars = line.chars().filter(|c| !c.is_whitespace()).peekable();
+            let first = chars.peek().copied();
+            let is_separator = chars.clone().count() >= 3
+                && matches!(
+                    first,
+                    Some('-' | '*' | '_' | '=' | '~' | '#' | '•' | '·' | '.')
+                )
+                && chars.all(|c| Some(c) == first);
+            if is_separator {
+                ""
+            } else {
+                line
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Matches http(s) URLs and URLs starting with "www."
+static URL_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\b(?:https?://|www\.)[^\s<>"'()\[\]]+"#).unwrap());
+
+/// Replaces every URL with its domain, minus any "www.". Punctuation at the end of a URL is taken
+/// to be part of the surrounding sentence, and kept.
+fn collapse_urls(text: &str) -> String {
+    URL_RE
+        .replace_all(text, |caps: &Captures| {
+            let matched = &caps[0];
+            let url = matched.trim_end_matches(['.', ',', ';', ':', '!', '?']);
+            let trailing = &matched[url.len()..];
+
+            let parsed = if url.starts_with("www.") {
+                reqwest::Url::parse(&format!("http://{url}"))
+            } else {
+                reqwest::Url::parse(url)
+            };
+            match parsed.ok().as_ref().and_then(|u| u.host_str()) {
+                Some(host) => format!("{}{trailing}", host.trim_start_matches("www.")),
+                None => matched.to_string(),
+            }
+        })
+        .into_owned()
+}
+
+/// Matches citation markers, along with the whitespace before them
+static CITATION_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\s*\[(?:\d+(?:\s*[,–-]\s*\d+)*|citation needed|edit)\]").unwrap()
+});
+
+/// Removes citation markers like "[12]", "[1, 4]", "[3–5]", and "[citation needed]"
+fn remove_citations(text: &str) -> String {
+    CITATION_RE.replace_all(text, "").into_owned()
+}
+
+/// Returns whether the given character is an emoji, or a modifier that only appears in emoji
+fn is_emoji(c: char) -> bool {
+    matches!(c,
+        '\u{1F000}'..='\u{1FAFF}' // Pictographs, emoticons, transport, flags, skin tones, etc.
+        | '\u{2600}'..='\u{27BF}' // Miscellaneous symbols and dingbats
+        | '\u{2B50}' | '\u{2B55}' // Star and circle
+        | '\u{FE0F}' // Emoji presentation selector
+        | '\u{200D}' // Zero width joiner
+        | '\u{20E3}' // Keycap
+        | '\u{E0020}'..='\u{E007F}' // Tags, used in subdivision flags
+    )
+}
+
+/// Removes emoji
+fn remove_emoji(text: &str) -> String {
+    text.chars().filter(|&c| !is_emoji(c)).collect()
+}
+
+/// Replaces curly single and double quotes with straight ones
+fn straighten_quotes(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}' => '\'',
+            '\u{201C}' | '\u{201D}' | '\u{201E}' | '\u{201F}' => '"',
+            c => c,
+        })
+        .collect()
+}
+
+/// An abbreviation, its expansion, and whether it can end a sentence. "etc." can, but "e.g." is
+/// always followed by something.
+type Abbreviation = (&'static str, &'static str, bool);
+
+/// Returns the common abbreviations of the given language
+fn abbreviations(lang: Lang) -> &'static [Abbreviation] {
+    match lang {
+        Lang::Eng => &[
+            ("e.g.", "for example", false),
+            ("i.e.", "that is", false),
+            ("etc.", "et cetera", true),
+            ("vs.", "versus", false),
+            ("approx.", "approximately", false),
+        ],
+        Lang::Deu => &[
+            ("z.B.", "zum Beispiel", false),
+            ("z. B.", "zum Beispiel", false),
+            ("d.h.", "das heißt", false),
+            ("d. h.", "das heißt", false),
+            ("usw.", "und so weiter", true),
+            ("bzw.", "beziehungsweise", false),
+        ],
+        Lang::Fra => &[
+            ("p. ex.", "par exemple", false),
+            ("c.-à-d.", "c'est-à-dire", false),
+            ("etc.", "et cetera", true),
+        ],
+        Lang::Spa => &[("p. ej.", "por ejemplo", false), ("etc.", "etcétera", true)],
+        _ => &[],
+    }
+}
+
+/// Expands the common abbreviations of the given language. An abbreviation that starts a sentence
+/// gets a capitalized expansion. One that can end a sentence keeps its period if it looks like it
+/// does.
+fn expand_abbreviations(text: &str, lang: Lang) -> String {
+    let abbrevs = abbreviations(lang);
+    if abbrevs.is_empty() {
+        return text.to_string();
+    }
+
+    // Match any of the abbreviations as a whole word, ignoring the case of the first letter
+    let alternatives = abbrevs
+        .iter()
+        .map(|(abbrev, _, _)| {
+            let mut chars = abbrev.chars();
+            let first = chars.next().unwrap();
+            format!(
+                "[{first}{}]{}",
+                first.to_uppercase(),
+                regex::escape(chars.as_str())
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("|");
+    let re = Regex::new(&format!(r"\b(?:{alternatives})")).unwrap();
+
+    let mut out = String::with_capacity(text.len());
+    let mut last_end = 0;
+    for m in re.find_iter(text) {
+        let matched = m.as_str();
+        let (_, expansion, can_end_sentence) = abbrevs
+            .iter()
+            .find(|(abbrev, _, _)| abbrev.to_lowercase() == matched.to_lowercase())
+            .unwrap();
+
+        out.push_str(&text[last_end..m.start()]);
+        if matched.starts_with(char::is_uppercase) {
+            let mut chars = expansion.chars();
+            out.extend(chars.next().unwrap().to_uppercase());
+            out.push_str(chars.as_str());
+        } else {
+            out.push_str(expansion);
+        }
+
+        // The period ended the sentence if the line ends here, or the next word is capitalized
+        let rest = &text[m.end()..];
+        let next_word = rest.trim_start_matches([' ', '\t']);
+        let ends_sentence = next_word.is_empty()
+            || next_word.starts_with('\n')
+            || (next_word.len() < rest.len() && next_word.starts_with(char::is_uppercase));
+        if *can_end_sentence && ends_sentence {
+            out.push('.');
+        }
+
+        last_end = m.end();
+    }
+    out.push_str(&text[last_end..]);
+
+    out
+}
+
+/// Collapses runs of spaces within a line, trims every line, removes zero-width characters and
+/// soft hyphens, and collapses runs of blank lines into one
+fn normalize_whitespace(text: &str) -> String {
+    let text: String = text
+        .chars()
+        .filter(|c| {
+            !matches!(
+                c,
+                '\u{200B}' | '\u{200C}' | '\u{2060}' | '\u{FEFF}' | '\u{AD}'
+            )
+        })
+        .collect();
+
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        let prev_blank = lines.last().map(String::is_empty);
+        if line.is_empty() && prev_blank != Some(false) {
+            continue;
+        }
+        lines.push(line);
+    }
+    while lines.last().is_some_and(String::is_empty) {
+        lines.pop();
+    }
+
+    lines.join("\n")
+}
+
+#[test]
+fn normalization_rules() {
+    // Separator lines are removed, but short runs and text with dashes are not
+    assert_eq!(
+        remove_separators("One\n---\n* * *\n=====\nTwo -- three\n**\n#"),
+        "One\n\n\n\nTwo -- three\n**\n#"
+    );
+
+    // URLs become domains, and keep the punctuation after them
+    assert_eq!(
+        collapse_urls(
+            "See https://www.example.com/a/b?c=d#e, or www.rust-lang.org. Mail me@example.com."
+        ),
+        "See example.com, or rust-lang.org. Mail me@example.com."
+    );
+
+    // Citations are removed, along with the space before them
+    assert_eq!(
+        remove_citations("It is true [1][2, 3]. It is false [4–6] [citation needed]. [sic]"),
+        "It is true. It is false. [sic]"
+    );
+
+    // Emoji are removed, including composite ones like flags and families
+    assert_eq!(
+        remove_emoji("Ship it 🚀🇺🇸! Family: 👨‍👩‍👧. Done ✅ ❤️"),
+        "Ship it ! Family: . Done  "
+    );
+
+    assert_eq!(
+        straighten_quotes("“Don’t,” she said. „Nein“"),
+        "\"Don't,\" she said. \"Nein\""
+    );
+
+    // Abbreviations are expanded in the right language, and are capitalized and keep their
+    // period where they start and end sentences
+    assert_eq!(
+        expand_abbreviations(
+            "Fruit, e.g. apples, pears, etc. E.g. Pears. Cats vs. dogs, etc.\nZ.B. no.",
+            Lang::Eng
+        ),
+        "Fruit, for example apples, pears, et cetera. For example Pears. Cats versus dogs, \
+         et cetera.\nZ.B. no."
+    );
+    assert_eq!(
+        expand_abbreviations("Obst, z. B. Äpfel usw. und Birnen", Lang::Deu),
+        "Obst, zum Beispiel Äpfel und so weiter und Birnen"
+    );
+    assert_eq!(expand_abbreviations("e.g. this", Lang::Jpn), "e.g. this");
+
+    // Whitespace is collapsed, and so are blank lines
+    assert_eq!(
+        normalize_whitespace("  Too   many\u{200B}\tspaces \n\n\n\n- Item\u{00A0} two\n\n"),
+        "Too many spaces\n\n- Item two"
+    );
+}
+
+#[test]
+fn normalization_pipeline() {
+    let text = "Title. Read “this” at https://example.com/post [1] 🎉\n\n* * *\n\nE.g. now.";
+
+    assert_eq!(
+        Normalizer::default().normalize(text, Lang::Eng),
+        "Title. Read \"this\" at example.com\n\nFor example now."
+    );
+
+    // Skipped rules are skipped
+    let normalizer = Normalizer::new(&[NormalizeRule::Urls, NormalizeRule::Abbreviations]);
+    assert_eq!(
+        normalizer.normalize(text, Lang::Eng),
+        "Title. Read \"this\" at https://example.com/post\n\nE.g. now."
+    );
+}
@@ -0,0 +1,292 @@
+//! Handles Ogg Opus files. TTS engines return one Ogg Opus stream per chunk of text. Unlike MP3,
+//! these can't just be concatenated, since every stream has its own headers and granule positions.
+//! So we remux the chunks into a single stream. We also read durations from granule positions, and
+//! store article metadata in the stream's Vorbis comments.
+
+use std::{io::Cursor, time::Duration};
+
+use anyhow::{anyhow, bail, Error as AnyError};
+use byteorder::{ByteOrder, LittleEndian};
+use ogg::{Packet, PacketReader, PacketWriteEndInfo, PacketWriter};
+
+/// Opus granule positions are always in units of 48kHz samples, regardless of the input rate
+const GRANULE_RATE: f64 = 48000.0;
+
+/// The magic signatures of the two Ogg Opus header packets
+const OPUS_HEAD_MAGIC: &[u8] = b"OpusHead";
+const OPUS_TAGS_MAGIC: &[u8] = b"OpusTags";
+
+/// The vendor string we put in the Vorbis comments we write
+const VENDOR: &str = "readtomyshoe";
+
+/// The packets of a single Ogg Opus stream
+struct OpusStream {
+    /// The serial number of the stream
+    serial: u32,
+    /// The OpusHead packet
+    head: Packet,
+    /// The audio packets, i.e., everything after the OpusTags packet
+    audio: Vec<Packet>,
+}
+
+impl OpusStream {
+    /// The number of samples to skip at the start of the decoded stream
+    fn pre_skip(&self) -> u64 {
+        // The pre-skip is a little-endian u16 at offset 10 of the OpusHead packet
+        LittleEndian::read_u16(&self.head.data[10..12]) as u64
+    }
+
+    /// The granule position of the end of the stream
+    fn final_granule(&self) -> u64 {
+        self.audio.last().map(Packet::absgp_page).unwrap_or(0)
+    }
+
+    /// The playback duration of the stream
+    fn duration(&self) -> Duration {
+        let num_samples = self.final_granule().saturating_sub(self.pre_skip());
+        Duration::from_secs_f64(num_samples as f64 / GRANULE_RATE)
+    }
+}
+
+/// Parses a single Ogg Opus stream, discarding its Vorbis comments
+fn parse_stream(bytes: &[u8]) -> Result<OpusStream, AnyError> {
+    let mut reader = PacketReader::new(Cursor::new(bytes));
+    let mut next_packet = || {
+        reader
+            .read_packet()
+            .map_err(|e| anyhow!("Invalid Ogg stream: {e}"))
+    };
+
+    let head = next_packet()?.ok_or(anyhow!("Empty Ogg stream"))?;
+    if !head.data.starts_with(OPUS_HEAD_MAGIC) || head.data.len() < 19 {
+        bail!("Ogg stream is not Opus");
+    }
+    let tags = next_packet()?.ok_or(anyhow!("Ogg Opus stream has no tags"))?;
+    if !tags.data.starts_with(OPUS_TAGS_MAGIC) {
+        bail!("Ogg Opus stream has invalid tags");
+    }
+
+    let mut audio = Vec::new();
+    while let Some(packet) = next_packet()? {
+        // We don't handle multiplexed or chained streams. No TTS engine makes these
+        if packet.stream_serial() != head.stream_serial() {
+            bail!("Ogg file has more than one stream");
+        }
+        audio.push(packet);
+    }
+
+    Ok(OpusStream {
+        serial: head.stream_serial(),
+        head,
+        audio,
+    })
+}
+
+/// Writes a single Ogg Opus stream with the given header and Vorbis comments. Each element of
+/// `audio` is a list of audio packets and the amount to add to their granule positions.
+fn write_stream(
+    serial: u32,
+    head: &Packet,
+    comments: &[(&str, String)],
+    audio: &[(&[Packet], u64)],
+) -> Result<Vec<u8>, AnyError> {
+    let mut writer = PacketWriter::new(Vec::new());
+
+    // The header packets each get their own page, with granule position 0
+    writer.write_packet(
+        head.data.clone().into(),
+        serial,
+        PacketWriteEndInfo::EndPage,
+        0,
+    )?;
+    writer.write_packet(
+        encode_comments(comments).into(),
+        serial,
+        PacketWriteEndInfo::EndPage,
+        0,
+    )?;
+
+    // Write the audio packets. Keep the page boundaries the same as in the originals, so that the
+    // page granule positions stay correct
+    let num_parts = audio.len();
+    for (part_idx, &(packets, granule_offset)) in audio.iter().enumerate() {
+        for (i, packet) in packets.iter().enumerate() {
+            let is_last = part_idx + 1 == num_parts && i + 1 == packets.len();
+            let end_info = if is_last {
+                PacketWriteEndInfo::EndStream
+            } else if packet.last_in_page() || i + 1 == packets.len() {
+                PacketWriteEndInfo::EndPage
+            } else {
+                PacketWriteEndInfo::NormalPacket
+            };
+            writer.write_packet(
+                packet.data.clone().into(),
+                serial,
+                end_info,
+                packet.absgp_page() + granule_offset,
+            )?;
+        }
+    }
+
+    Ok(writer.into_inner())
+}
+
+/// Serializes the Vorbis comments into an OpusTags packet
+fn encode_comments(comments: &[(&str, String)]) -> Vec<u8> {
+    // Strings are prefixed with their little-endian u32 length
+    fn push_str(buf: &mut Vec<u8>, s: &str) {
+        let mut len = [0u8; 4];
+        LittleEndian::write_u32(&mut len, s.len() as u32);
+        buf.extend_from_slice(&len);
+        buf.extend_from_slice(s.as_bytes());
+    }
+
+    let mut buf = OPUS_TAGS_MAGIC.to_vec();
+    push_str(&mut buf, VENDOR);
+    let mut count = [0u8; 4];
+    LittleEndian::write_u32(&mut count, comments.len() as u32);
+    buf.extend_from_slice(&count);
+    for (key, val) in comments {
+        push_str(&mut buf, &format!("{key}={val}"));
+    }
+
+    buf
+}
+
+/// Reads a little-endian u32 off the front of `buf`
+fn read_u32(buf: &mut &[u8]) -> Result<u32, AnyError> {
+    if buf.len() < 4 {
+        bail!("OpusTags packet is truncated");
+    }
+    let n = LittleEndian::read_u32(buf);
+    *buf = &buf[4..];
+    Ok(n)
+}
+
+/// Reads a length-prefixed string off the front of `buf`
+fn read_str<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8], AnyError> {
+    let len = read_u32(buf)? as usize;
+    if buf.len() < len {
+        bail!("OpusTags packet is truncated");
+    }
+    let (s, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(s)
+}
+
+/// Parses an OpusTags packet into its Vorbis comments. Keys are uppercased.
+fn decode_comments(packet: &[u8]) -> Result<Vec<(String, String)>, AnyError> {
+    let mut rest = packet
+        .strip_prefix(OPUS_TAGS_MAGIC)
+        .ok_or(anyhow!("Not an OpusTags packet"))?;
+
+    // Skip the vendor string, then read the comments
+    read_str(&mut rest)?;
+    let count = read_u32(&mut rest)?;
+    let mut comments = Vec::new();
+    for _ in 0..count {
+        let comment = String::from_utf8_lossy(read_str(&mut rest)?).to_string();
+        if let Some((key, val)) = comment.split_once('=') {
+            comments.push((key.to_uppercase(), val.to_string()));
+        }
+    }
+
+    Ok(comments)
+}
+
+/// Joins the given Ogg Opus streams into one. The headers of the first stream are kept, and the
+/// rest of the streams' audio packets are appended, with their granule positions shifted to follow
+/// the previous stream's.
+///
+/// Only the first stream's pre-skip is honored by decoders. So each subsequent stream's encoder
+/// delay (a few milliseconds of silence) is played rather than skipped.
+pub(crate) fn concat(chunks: &[&[u8]]) -> Result<Vec<u8>, AnyError> {
+    let streams = chunks
+        .iter()
+        .map(|c| parse_stream(c))
+        .collect::<Result<Vec<_>, _>>()?;
+    let first = streams
+        .first()
+        .ok_or(anyhow!("No Opus streams to concat"))?;
+
+    let mut granule_offset = 0;
+    let mut audio = Vec::new();
+    for stream in &streams {
+        audio.push((stream.audio.as_slice(), granule_offset));
+        granule_offset += stream.final_granule();
+    }
+
+    write_stream(first.serial, &first.head, &[], &audio)
+}
+
+/// Returns the playback duration of the given Ogg Opus file
+pub(crate) fn duration(bytes: &[u8]) -> Result<Duration, AnyError> {
+    parse_stream(bytes).map(|s| s.duration())
+}
+
+/// Returns the Vorbis comments of the given Ogg Opus file. Keys are uppercased.
+pub(crate) fn read_comments(bytes: &[u8]) -> Result<Vec<(String, String)>, AnyError> {
+    let mut reader = PacketReader::new(Cursor::new(bytes));
+    reader.read_packet()?;
+    let tags = reader
+        .read_packet()?
+        .ok_or(anyhow!("Ogg Opus stream has no tags"))?;
+    decode_comments(&tags.data)
+}
+
+/// Replaces the Vorbis comments of the given Ogg Opus file, and returns the new file
+pub(crate) fn write_comments(
+    bytes: &[u8],
+    comments: &[(&str, String)],
+) -> Result<Vec<u8>, AnyError> {
+    let stream = parse_stream(bytes)?;
+    write_stream(
+        stream.serial,
+        &stream.head,
+        comments,
+        &[(stream.audio.as_slice(), 0)],
+    )
+}
+
+#[test]
+fn opus_remuxing() {
+    use crate::tts::mock::{silent_ogg_opus, OPUS_FRAME_SAMPLES};
+
+    // Make 3 streams of different lengths and join them. The result has the length of all of them
+    // together, minus the first stream's pre-skip
+    let chunks = [
+        silent_ogg_opus(10),
+        silent_ogg_opus(250),
+        silent_ogg_opus(1),
+    ];
+    let joined = concat(&chunks.iter().map(Vec::as_slice).collect::<Vec<_>>()).unwrap();
+    let stream = parse_stream(&joined).unwrap();
+    assert_eq!(stream.audio.len(), 261);
+    assert_eq!(stream.final_granule(), 261 * OPUS_FRAME_SAMPLES);
+    let num_samples = 261 * OPUS_FRAME_SAMPLES - stream.pre_skip();
+    assert_eq!(
+        duration(&joined).unwrap(),
+        Duration::from_secs_f64(num_samples as f64 / GRANULE_RATE)
+    );
+
+    // Granule positions never decrease
+    let granules: Vec<u64> = stream.audio.iter().map(Packet::absgp_page).collect();
+    assert!(granules.windows(2).all(|w| w[0] <= w[1]));
+
+    // Write some comments. They're readable, and the audio doesn't change
+    let comments = [
+        ("TITLE", "Un cœur simple".to_string()),
+        ("ARTIST", "https://example.com/?a=b".to_string()),
+    ];
+    let tagged = write_comments(&joined, &comments).unwrap();
+    assert_eq!(
+        read_comments(&tagged).unwrap(),
+        vec![
+            ("TITLE".to_string(), "Un cœur simple".to_string()),
+            ("ARTIST".to_string(), "https://example.com/?a=b".to_string()),
+        ]
+    );
+    let tagged_stream = parse_stream(&tagged).unwrap();
+    assert_eq!(tagged_stream.final_granule(), stream.final_granule());
+    assert_eq!(duration(&tagged).unwrap(), duration(&joined).unwrap());
+}
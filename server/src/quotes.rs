@@ -0,0 +1,160 @@
+//! Finds the quotations in article text, so that they can be read by a second voice. Interviews
+//! and long quotes are easier to follow when the quoted person doesn't sound like the narrator.
+
+use crate::tts::ssml::{HINT_CLOSE, HINT_OPEN};
+
+/// The pairs of opening and closing quotation marks we recognize
+const QUOTE_MARKS: &[(char, char)] =
+    &[('"', '"'), ('“', '”'), ('«', '»'), ('„', '“'), ('「', '」')];
+
+/// Quotations with fewer words than this are read by the narrator. Those are usually scare quotes
+/// or titles, like the "so-called" experts, and switching voices for them is jarring.
+const MIN_QUOTE_WORDS: usize = 3;
+
+/// A run of article text, and whether it's quoted
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct TextRun {
+    pub(crate) text: String,
+    pub(crate) quoted: bool,
+}
+
+/// Splits the text into alternating runs of narration and quotation. Block quotes (lines that
+/// start with `>`) and quoted passages of at least `MIN_QUOTE_WORDS` words are quotations. A
+/// quotation's marks go with it. Headings are never split up, and neither are pronunciation hints.
+/// Text with nothing to read, like the full stop after a quotation, joins the run before it, so
+/// every run has something to say. Joining the runs gives back the original text.
+pub(crate) fn split_quotations(text: &str) -> Vec<TextRun> {
+    let mut runs: Vec<TextRun> = Vec::new();
+    // Text with nothing to read that comes before the first run
+    let mut leading = String::new();
+    let mut push = |piece: &str, quoted: bool| {
+        let has_words = piece.chars().any(char::is_alphanumeric);
+        match runs.last_mut() {
+            Some(run) if run.quoted == quoted || !has_words => run.text.push_str(piece),
+            _ if !has_words => leading.push_str(piece),
+            _ => runs.push(TextRun {
+                text: std::mem::take(&mut leading) + piece,
+                quoted,
+            }),
+        }
+    };
+
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with('>') {
+            push(line, true);
+        } else if trimmed.starts_with('#') {
+            push(line, false);
+        } else {
+            let mut start = 0;
+            for (quote_start, quote_end) in find_quotations(line) {
+                push(&line[start..quote_start], false);
+                push(&line[quote_start..quote_end], true);
+                start = quote_end;
+            }
+            push(&line[start..], false);
+        }
+    }
+
+    if runs.is_empty() && !leading.is_empty() {
+        runs.push(TextRun {
+            text: leading,
+            quoted: false,
+        });
+    }
+    runs
+}
+
+/// Returns the byte ranges of the quotations in the line, including their quotation marks.
+/// Quotation marks inside pronunciation hints are ignored.
+fn find_quotations(line: &str) -> Vec<(usize, usize)> {
+    let mut quotations = Vec::new();
+    // The start of the quotation we're in, and the mark that closes it
+    let mut open: Option<(usize, char)> = None;
+    let mut in_hint = false;
+
+    for (i, c) in line.char_indices() {
+        match c {
+            HINT_OPEN => in_hint = true,
+            HINT_CLOSE => in_hint = false,
+            _ if in_hint => (),
+            _ => match open {
+                Some((start, close)) if c == close => {
+                    let end = i + c.len_utf8();
+                    if line[start..end].split_whitespace().count() >= MIN_QUOTE_WORDS {
+                        quotations.push((start, end));
+                    }
+                    open = None;
+                }
+                Some(_) => (),
+                None => {
+                    open = QUOTE_MARKS
+                        .iter()
+                        .find(|(o, _)| *o == c)
+                        .map(|&(_, close)| (i, close));
+                }
+            },
+        }
+    }
+
+    quotations
+}
+
+#[test]
+fn quotation_splitting() {
+    use crate::tts::ssml::HINT_SEP;
+
+    let runs = |text: &str| -> Vec<(bool, String)> {
+        split_quotations(text)
+            .into_iter()
+            .map(|r| (r.quoted, r.text))
+            .collect()
+    };
+
+    // Long quotations get their own runs, along with their marks. The full stop after one joins
+    // it. Scare quotes and headings stay with the narration
+    let text = "# The \"Big\" Interview\n\
+        She said, \"We will keep going until it's done\", and left.\n\
+        “Nobody expected this to work.”\n\
+        The \"so-called\" experts disagreed.";
+    assert_eq!(
+        runs(text),
+        vec![
+            (false, "# The \"Big\" Interview\nShe said, ".to_string()),
+            (true, "\"We will keep going until it's done\"".to_string()),
+            (false, ", and left.\n".to_string()),
+            (true, "“Nobody expected this to work.”\n".to_string()),
+            (false, "The \"so-called\" experts disagreed.".to_string()),
+        ]
+    );
+
+    // Block quotes are quotations, including the blank lines between them
+    let text = "Before.\n> First quoted line.\n\n> Second quoted line.\nAfter.";
+    assert_eq!(
+        runs(text),
+        vec![
+            (false, "Before.\n".to_string()),
+            (
+                true,
+                "> First quoted line.\n\n> Second quoted line.\n".to_string()
+            ),
+            (false, "After.".to_string()),
+        ]
+    );
+
+    // German quotation marks work, unclosed quotations are narrated, and joining the runs gives
+    // back the text
+    let text = "Er sagte: „Das ist nicht mein Problem“. Sie sagte: \"Aber es ist";
+    let split = split_quotations(text);
+    assert_eq!(split.len(), 3);
+    assert_eq!(split[1].text, "„Das ist nicht mein Problem“");
+    assert_eq!(
+        split.iter().map(|r| r.text.as_str()).collect::<String>(),
+        text
+    );
+
+    // Quotation marks in pronunciation hints don't count
+    let text = format!("A {HINT_OPEN}\"x{HINT_SEP}sub{HINT_SEP}the x factor{HINT_CLOSE} here\"");
+    assert_eq!(runs(&text), vec![(false, text.clone())]);
+    assert!(split_quotations("").is_empty());
+}
@@ -0,0 +1,274 @@
+//! A content-addressed cache of synthesized chunks. When an article fails partway through, or is
+//! resubmitted with a small edit, most of its chunks are the same as last time. Those are read
+//! from disk rather than paid for again.
+//!
+//! A chunk is keyed by the hash of its request and audio format. Its audio is stored in
+//! `KEY.EXT`, and its timepoints in `KEY.timepoints.json`.
+//!
+//! The cache has a maximum size. When it's exceeded, the least recently used chunks are deleted
+//! until the cache is back under 90% of it.
+
+use crate::{
+    tts::{AudioFormat, SynthesizedChunk, Timepoint, TtsRequest},
+    util::hash_tts_request,
+};
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Error as AnyError};
+use bytes::Bytes;
+use tempfile::NamedTempFile;
+
+/// A directory of synthesized chunks
+#[derive(Clone)]
+pub(crate) struct ChunkCache {
+    dir: Arc<PathBuf>,
+    /// The most bytes the cache can hold
+    max_size: u64,
+    /// The number of bytes the cache holds. This is locked while evicting, so that only one
+    /// eviction runs at a time
+    size: Arc<Mutex<u64>>,
+}
+
+impl ChunkCache {
+    /// Opens the chunk cache in the given directory, creating the directory if it doesn't exist.
+    /// The cache holds at most `max_size` bytes.
+    pub(crate) fn new(dir: impl AsRef<Path>, max_size: u64) -> Result<Self, AnyError> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)
+            .map_err(|e| anyhow!("Could not create chunk cache dir {:?}: {e}", dir))?;
+        let size = list_entries(dir)?.iter().map(|e| e.size).sum();
+        Ok(ChunkCache {
+            dir: Arc::new(dir.to_path_buf()),
+            max_size,
+            size: Arc::new(Mutex::new(size)),
+        })
+    }
+
+    /// Returns the paths of the audio and timepoints of the given request
+    fn paths(&self, req: &TtsRequest, format: AudioFormat) -> (PathBuf, PathBuf) {
+        let key = hash_tts_request(req, format);
+        let audio_path = self.dir.join(format!("{key}.{}", format.codec.extension()));
+        let timepoints_path = self.dir.join(format!("{key}.timepoints.json"));
+        (audio_path, timepoints_path)
+    }
+
+    /// Returns whether the audio of the given request is in the cache
+    pub(crate) fn contains(&self, req: &TtsRequest, format: AudioFormat) -> bool {
+        let (audio_path, timepoints_path) = self.paths(req, format);
+        audio_path.exists() && timepoints_path.exists()
+    }
+
+    /// Returns the cached audio and timepoints of the given request, if there are any
+    pub(crate) fn get(&self, req: &TtsRequest, format: AudioFormat) -> Option<SynthesizedChunk> {
+        let (audio_path, timepoints_path) = self.paths(req, format);
+        let timepoints: Vec<Timepoint> = serde_json::from_slice(&fs::read(timepoints_path).ok()?)
+            .map_err(|e| tracing::warn!("Corrupt timepoints in chunk cache: {e}"))
+            .ok()?;
+        let audio = fs::read(&audio_path).ok()?;
+
+        // Mark the chunk as recently used, so it's evicted last
+        let _ = File::options()
+            .write(true)
+            .open(&audio_path)
+            .and_then(|f| f.set_modified(SystemTime::now()));
+
+        Some(SynthesizedChunk {
+            audio: Bytes::from(audio),
+            timepoints,
+        })
+    }
+
+    /// Saves the audio and timepoints of the given request
+    pub(crate) fn put(
+        &self,
+        req: &TtsRequest,
+        format: AudioFormat,
+        chunk: &SynthesizedChunk,
+    ) -> Result<(), AnyError> {
+        let (audio_path, timepoints_path) = self.paths(req, format);
+
+        // The audio goes last, since `contains` checks for both
+        let timepoints = serde_json::to_vec(&chunk.timepoints)?;
+        let mut added = self.write_file(&timepoints_path, &timepoints)?;
+        added += self.write_file(&audio_path, &chunk.audio)?;
+
+        let mut size = self.size.lock().unwrap();
+        *size += added;
+        if *size > self.max_size {
+            *size = evict(&self.dir, self.max_size / 10 * 9)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the file, and returns the number of bytes written. The contents are written to a
+    /// temp file with a unique name, and then moved into place, so that a `get` never reads a
+    /// partial file, and two articles writing the same chunk at once don't interfere. Since the
+    /// path is the hash of the contents, if it already exists, it's already written.
+    fn write_file(&self, path: &Path, contents: &[u8]) -> Result<u64, AnyError> {
+        let mut tmp = NamedTempFile::new_in(self.dir.as_ref())?;
+        tmp.write_all(contents)?;
+        match tmp.persist_noclobber(path) {
+            Ok(_) => Ok(contents.len() as u64),
+            Err(e) if e.error.kind() == ErrorKind::AlreadyExists => Ok(0),
+            Err(e) => Err(e.error.into()),
+        }
+    }
+}
+
+/// A chunk in the cache
+struct CacheEntry {
+    /// The files the chunk is stored in
+    paths: Vec<PathBuf>,
+    /// The total size of the files
+    size: u64,
+    /// The last time the chunk was written or read
+    last_used: SystemTime,
+}
+
+/// Lists the chunks in the cache directory. Temp files aren't chunks, and are left out
+fn list_entries(dir: &Path) -> Result<Vec<CacheEntry>, AnyError> {
+    let mut entries: HashMap<String, CacheEntry> = HashMap::new();
+    for file in fs::read_dir(dir)? {
+        let file = file?;
+        let name = file.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        // A file might have just been evicted by another thread
+        let meta = match file.metadata() {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        let key = name.split('.').next().unwrap_or_default().to_string();
+        let entry = entries.entry(key).or_insert(CacheEntry {
+            paths: Vec::new(),
+            size: 0,
+            last_used: SystemTime::UNIX_EPOCH,
+        });
+        entry.paths.push(file.path());
+        entry.size += meta.len();
+        entry.last_used = entry.last_used.max(meta.modified()?);
+    }
+    Ok(entries.into_values().collect())
+}
+
+/// Deletes the least recently used chunks in the cache directory until it holds at most `target`
+/// bytes. Returns the number of bytes it holds now.
+fn evict(dir: &Path, target: u64) -> Result<u64, AnyError> {
+    let mut entries = list_entries(dir)?;
+    let mut size: u64 = entries.iter().map(|e| e.size).sum();
+    entries.sort_by_key(|e| e.last_used);
+
+    let mut num_evicted = 0;
+    for entry in entries {
+        if size <= target {
+            break;
+        }
+        for path in &entry.paths {
+            if let Err(e) = fs::remove_file(path) {
+                tracing::warn!("Couldn't evict {:?} from the chunk cache: {e}", path);
+            }
+        }
+        size -= entry.size;
+        num_evicted += 1;
+    }
+    tracing::info!("Evicted {num_evicted} chunks from the chunk cache");
+
+    Ok(size)
+}
+
+#[test]
+fn chunk_caching() {
+    use crate::tts::AudioCodec;
+
+    let dir = tempfile::tempdir().unwrap();
+    let cache = ChunkCache::new(dir.path().join("chunks"), u64::MAX).unwrap();
+    let req = TtsRequest {
+        text: r#"<speak><mark name="0"/>Hello.</speak>"#.to_string(),
+        ssml: true,
+        voice_name: "en-US-Wavenet-C".to_string(),
+    };
+    let format = AudioFormat::default();
+
+    // Nothing is cached at first
+    assert!(!cache.contains(&req, format));
+    assert!(cache.get(&req, format).is_none());
+
+    // Cache a chunk and get it back
+    let chunk = SynthesizedChunk {
+        audio: Bytes::from_static(b"not really audio"),
+        timepoints: vec![Timepoint {
+            mark_name: "0".to_string(),
+            time: 0.5,
+        }],
+    };
+    cache.put(&req, format, &chunk).unwrap();
+    assert!(cache.contains(&req, format));
+    let cached = cache.get(&req, format).unwrap();
+    assert_eq!(cached.audio, chunk.audio);
+    assert_eq!(cached.timepoints.len(), 1);
+    assert_eq!(cached.timepoints[0].mark_name, "0");
+
+    // Changing the voice or the format is a cache miss
+    let other_voice = TtsRequest {
+        voice_name: "en-US-Wavenet-D".to_string(),
+        ..req.clone()
+    };
+    assert!(!cache.contains(&other_voice, format));
+    let other_format = AudioFormat {
+        codec: AudioCodec::OggOpus,
+        bitrate_kbps: 64,
+    };
+    assert!(!cache.contains(&req, other_format));
+
+    // Caching the same chunk again, like two articles at once might, is fine
+    cache.put(&req, format, &chunk).unwrap();
+    assert_eq!(cache.get(&req, format).unwrap().audio, chunk.audio);
+}
+
+#[test]
+fn chunk_eviction() {
+    use std::time::Duration;
+
+    // Room for about 3 chunks. Each takes up its audio and its timepoints, which are `[]`
+    let dir = tempfile::tempdir().unwrap();
+    let cache = ChunkCache::new(dir.path(), 3 * 102).unwrap();
+    let format = AudioFormat::default();
+    let req = |i: usize| TtsRequest {
+        text: format!("Sentence number {i}."),
+        ssml: false,
+        voice_name: "en-US-Wavenet-C".to_string(),
+    };
+    let chunk = SynthesizedChunk {
+        audio: Bytes::from(vec![0u8; 100]),
+        timepoints: Vec::new(),
+    };
+
+    // Cache 3 chunks, and use the first one again. Modification times can be coarse, so wait
+    // between uses
+    for i in 0..3 {
+        cache.put(&req(i), format, &chunk).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert!(cache.get(&req(0), format).is_some());
+    std::thread::sleep(Duration::from_millis(20));
+
+    // The fourth chunk overflows the cache. The least recently used chunks go, until it's under
+    // 90% full
+    cache.put(&req(3), format, &chunk).unwrap();
+    let cached: Vec<bool> = (0..4).map(|i| cache.contains(&req(i), format)).collect();
+    assert_eq!(cached, [true, false, false, true]);
+    assert_eq!(*cache.size.lock().unwrap(), 2 * 102);
+
+    // Reopening the cache counts what's in it
+    let reopened = ChunkCache::new(dir.path(), 3 * 102).unwrap();
+    assert_eq!(*reopened.size.lock().unwrap(), 2 * 102);
+}
@@ -0,0 +1,608 @@
+//! Implements a barebones client to the Google Cloud TTS service
+
+use std::{fs, io::ErrorKind, path::Path};
+
+use crate::{
+    lang::{gcp_voices, gcp_voices_from_list},
+    tts::{
+        do_with_retry, AudioCodec, AudioFormat, SynthesizedChunk, Timepoint, TtsEngine, TtsRequest,
+        VoiceInfo, VoiceType,
+    },
+};
+
+use anyhow::{anyhow, bail, Context, Error as AnyError};
+use bytes::Bytes;
+use futures::future::{BoxFuture, FutureExt};
+use serde::Deserialize;
+
+/// Path to the file that holds the Google Cloud API key
+const API_KEY_FILE: &str = "gcp_api.key";
+
+// See https://cloud.google.com/text-to-speech/quotas
+const MAX_CHARS_PER_REQUEST: usize = 5000;
+
+#[derive(Deserialize)]
+struct AudioResponse<'a> {
+    #[serde(borrow, rename = "audioContent")]
+    audio_content: &'a str,
+    /// Only present if timepointing was enabled in the request
+    #[serde(default)]
+    timepoints: Vec<GcpTimepoint>,
+}
+
+#[derive(Deserialize)]
+struct GcpTimepoint {
+    #[serde(rename = "markName")]
+    mark_name: String,
+    #[serde(rename = "timeSeconds")]
+    time_seconds: f64,
+}
+
+/// The response of the `voices` endpoint. This is also the format of the voices file
+#[derive(Deserialize)]
+struct VoiceListResponse {
+    voices: Vec<ListedVoice>,
+}
+
+#[derive(Deserialize)]
+struct ListedVoice {
+    /// The BCP-47 tags of the locales this voice speaks. The first is the voice's own locale
+    #[serde(rename = "languageCodes")]
+    language_codes: Vec<String>,
+    name: String,
+    /// MALE, FEMALE, or NEUTRAL
+    #[serde(rename = "ssmlGender", default)]
+    ssml_gender: String,
+}
+
+/// The description of a Google Cloud TTS reading voice
+#[derive(Clone, Copy)]
+pub(crate) struct GcpVoice {
+    /// The unique voice identifier
+    pub(crate) id: &'static str,
+    // The English description of this voice. E.g., "Portuguese (Brazil)"
+    pub(crate) english_desc: &'static str,
+    /// The type of voice this is (high/low)
+    pub(crate) ty: VoiceType,
+}
+
+/// A TTS engine that makes calls to the Google Cloud TTS API
+pub(crate) struct GcpTts {
+    /// The Google Cloud API key
+    api_key: String,
+    /// The base URL of the TTS API, e.g., `https://texttospeech.googleapis.com/v1beta1`
+    api_base: String,
+    /// The format of the audio we ask for
+    format: AudioFormat,
+    /// The `audioEncoding` value that gets us that codec
+    audio_encoding: &'static str,
+    /// The voices we can read with, in decreasing order of preference
+    voices: Vec<VoiceInfo>,
+}
+
+impl GcpTts {
+    /// Makes a new Google Cloud TTS client that talks to the API at the given base URL, reads with
+    /// the given voices, and outputs the given format. Google Cloud only outputs MP3 at 32 or
+    /// 64kbps, so any other MP3 bitrate is an error. The bitrate of Opus output isn't
+    /// configurable, so it's ignored.
+    pub(crate) fn new(
+        api_key: String,
+        api_base: &str,
+        voices: Vec<VoiceInfo>,
+        format: AudioFormat,
+    ) -> Result<Self, AnyError> {
+        let audio_encoding = match (format.codec, format.bitrate_kbps) {
+            (AudioCodec::Mp3, 64) => "MP3_64_KBPS",
+            (AudioCodec::Mp3, 32) => "MP3",
+            (AudioCodec::Mp3, b) => bail!("Google Cloud can't output {b}kbps MP3. Use 32 or 64"),
+            (AudioCodec::OggOpus, _) => "OGG_OPUS",
+        };
+
+        Ok(GcpTts {
+            api_key,
+            api_base: api_base.trim_end_matches('/').to_string(),
+            format,
+            audio_encoding,
+            voices,
+        })
+    }
+}
+
+impl TtsEngine for GcpTts {
+    fn max_request_size(&self) -> usize {
+        MAX_CHARS_PER_REQUEST
+    }
+
+    fn output_format(&self) -> AudioFormat {
+        self.format
+    }
+
+    fn supports_ssml(&self) -> bool {
+        true
+    }
+
+    fn list_voices(&self) -> Vec<VoiceInfo> {
+        self.voices.clone()
+    }
+
+    fn synthesize<'a>(
+        &'a self,
+        req: &'a TtsRequest,
+    ) -> BoxFuture<'a, Result<SynthesizedChunk, AnyError>> {
+        tts_single(&self.api_key, &self.api_base, self.audio_encoding, req).boxed()
+    }
+}
+
+/// Returns the locale of the given voice, e.g., `en-GB` for `en-GB-Wavenet-B`. Voice IDs are the
+/// locale followed by the tier and a letter.
+pub(crate) fn voice_locale(voice_name: &str) -> &str {
+    voice_name.rsplitn(3, '-').last().unwrap_or(voice_name)
+}
+
+/// Serializes the request into the JSON body that the `text:synthesize` endpoint expects, asking
+/// for audio in the given encoding. SSML requests ask for the timepoints of their marks.
+fn request_to_json(req: &TtsRequest, audio_encoding: &str) -> serde_json::Value {
+    let (input, timepoint_types) = if req.ssml {
+        (serde_json::json!({ "ssml": req.text }), vec!["SSML_MARK"])
+    } else {
+        (serde_json::json!({ "text": req.text }), vec![])
+    };
+
+    serde_json::json!({
+        "input": input,
+        "enableTimePointing": timepoint_types,
+        "voice":{
+            "languageCode": voice_locale(&req.voice_name),
+            "name": req.voice_name,
+        },
+        "audioConfig":{
+            "audioEncoding": audio_encoding,
+            "sampleRateHertz": 48000
+        }
+    })
+}
+
+pub(crate) fn get_api_key() -> Result<String, AnyError> {
+    std::fs::read_to_string(API_KEY_FILE).map_err(|e| {
+        anyhow!(
+            "Could not open API key file {API_KEY_FILE}. \
+            Read the README for info about how to get an API key. {:?}",
+            e,
+        )
+    })
+}
+
+/// Redacts the API key out of the TTS error message
+fn redact_error(mut e: reqwest::Error) -> reqwest::Error {
+    // The API key is specified by `?key=...`. Overwriting the query section of the URL will make
+    // this go away
+    e.url_mut().map(|u| u.set_query(Some("key=REDACTED")));
+    e
+}
+
+/// Parses a response of the `voices` endpoint into the voices we can read with. It's an error if
+/// there are none.
+fn parse_voice_list(json: &[u8]) -> Result<Vec<VoiceInfo>, AnyError> {
+    let list: VoiceListResponse =
+        serde_json::from_slice(json).with_context(|| "Invalid voice list")?;
+    let listed: Vec<(String, String, VoiceType)> = list
+        .voices
+        .into_iter()
+        .filter_map(|v| {
+            let locale = v.language_codes.into_iter().next()?;
+            let ty = if v.ssml_gender == "MALE" {
+                VoiceType::LowPitch
+            } else {
+                VoiceType::HighPitch
+            };
+            Some((locale, v.name, ty))
+        })
+        .collect();
+
+    let voices = gcp_voices_from_list(&listed);
+    if voices.is_empty() {
+        bail!("The voice list has no voices in languages we support");
+    }
+    Ok(voices)
+}
+
+/// Loads the voices from the given voices file. If the file doesn't exist or is invalid, returns
+/// the compiled-in voices.
+pub(crate) fn load_voices(path: &Path) -> Vec<VoiceInfo> {
+    let voices = fs::read(path)
+        .map_err(AnyError::from)
+        .and_then(|json| parse_voice_list(&json));
+    match voices {
+        Ok(voices) => {
+            tracing::info!("Loaded {} voices from {:?}", voices.len(), path);
+            voices
+        }
+        Err(e) => {
+            if e.downcast_ref::<std::io::Error>().map(|e| e.kind()) == Some(ErrorKind::NotFound) {
+                tracing::info!("No voices file at {:?}. Using the built-in voices", path);
+            } else {
+                tracing::warn!(
+                    "Couldn't load voices from {:?}: {e:#}. Using the built-in voices",
+                    path
+                );
+            }
+            gcp_voices()
+        }
+    }
+}
+
+/// Fetches the voice list from the API at the given base URL, and saves it to the given voices
+/// file. The file is only replaced if the new list is valid. Returns the number of voices we can
+/// read with.
+pub(crate) async fn refresh_voices(
+    api_key: &str,
+    api_base: &str,
+    path: &Path,
+) -> Result<usize, AnyError> {
+    let endpoint = format!("{}/voices", api_base.trim_end_matches('/'));
+    let url = reqwest::Url::parse_with_params(&endpoint, &[("key", api_key)])?;
+    let json = reqwest::get(url)
+        .await
+        .map_err(redact_error)
+        .with_context(|| "Couldn't make voice list request")?
+        .error_for_status()
+        .map_err(redact_error)
+        .with_context(|| "Voice list request failed")?
+        .bytes()
+        .await?;
+    let num_voices = parse_voice_list(&json)?.len();
+
+    // Write to a temp file and rename it, so that the server never reads half a file
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, &json)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(num_voices)
+}
+
+/// Speaks text string of length at most MAX_CHARS_PER_REQUEST. Returns an error if length exceeds,
+/// or an error occurs in the Google Cloud API call.
+async fn tts_single(
+    api_key: &str,
+    api_base: &str,
+    audio_encoding: &str,
+    req: &TtsRequest,
+) -> Result<SynthesizedChunk, AnyError> {
+    let payload = request_to_json(req, audio_encoding);
+
+    // The Google API has a hard upper limit on characters per request. The text breaking before
+    // this point should ensure this limit is never exceeded
+    if req.text.len() > MAX_CHARS_PER_REQUEST {
+        bail!("TTS request is too long");
+    }
+
+    // Do the HTTP request. Try it at most 5 times before failing
+    let client = reqwest::Client::new();
+    let endpoint = format!("{api_base}/text:synthesize");
+    let url = reqwest::Url::parse_with_params(&endpoint, &[("key", api_key)])?;
+    let res = do_with_retry(5, || async {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+        let payload_id = {
+            let mut h = DefaultHasher::new();
+            format!("{}", payload).hash(&mut h);
+            h.finish()
+        };
+        tracing::debug!("Running TTS chunk with payload {}", payload_id);
+        let res = client
+            .post(url.clone())
+            .json(&payload)
+            .send()
+            .await
+            .with_context(|| "Couldn't make TTS request")?
+            .error_for_status()
+            .map_err(redact_error)
+            .with_context(|| "TTS request failed")?;
+        Result::<_, AnyError>::Ok(res)
+    })
+    .await?;
+
+    // The resulting JSON response has our audio data
+    let res_bytes = res.bytes().await?;
+    let audio_response: AudioResponse = serde_json::from_slice(&res_bytes)?;
+    let audio_blob = Bytes::from(base64::decode(audio_response.audio_content)?);
+    let timepoints = audio_response
+        .timepoints
+        .into_iter()
+        .map(|tp| Timepoint {
+            mark_name: tp.mark_name,
+            time: tp.time_seconds,
+        })
+        .collect();
+
+    Ok(SynthesizedChunk {
+        audio: audio_blob,
+        timepoints,
+    })
+}
+
+#[tokio::test]
+async fn mock_synthesis() {
+    use crate::{
+        tts::{
+            cache::ChunkCache,
+            mock::{MockTtsServer, BYTES_PER_FRAME, MP3_FRAME_SIZE},
+            plan_requests,
+            ssml::{billed_len, parse_blocks, render_ssml_chunks},
+            tts, TtsProgress, VoiceTier, VoicedSegment,
+        },
+        usage::{TierPrices, UsageLog},
+    };
+    use core::num::NonZeroUsize;
+    use tokio::sync::watch;
+    use whatlang::Lang;
+
+    // Make the first 2 requests fail. The retry logic should take care of it
+    let server = MockTtsServer::spawn(2);
+    let engine = GcpTts::new(
+        "fake-key".to_string(),
+        &server.api_base,
+        gcp_voices(),
+        AudioFormat::default(),
+    )
+    .unwrap();
+
+    // Make 3 paragraphs that each fit in a single request, but don't fit together
+    let paragraph = "All work and no play makes Jack a dull boy. ".repeat(60);
+    assert!(2 * paragraph.len() > MAX_CHARS_PER_REQUEST);
+    let text = [paragraph.as_str(); 3].join("\n");
+
+    let segment = VoicedSegment {
+        text: text.clone(),
+        voice_name: "en-US-Wavenet-C".to_string(),
+        lang: Lang::Eng,
+    };
+    let plan = plan_requests(&engine, &[segment]).unwrap();
+    let tts_dir = tempfile::tempdir().unwrap();
+    let cache = ChunkCache::new(tts_dir.path().join("chunks"), u64::MAX).unwrap();
+    let usage = UsageLog::open(tts_dir.path().join("usage.json")).unwrap();
+    let (progress_tx, progress_rx) = watch::channel(TtsProgress::default());
+    let max_concurrency = NonZeroUsize::new(2).unwrap();
+    let output = tts(
+        &engine,
+        &plan,
+        &cache,
+        &usage,
+        max_concurrency,
+        None,
+        &progress_tx,
+    )
+    .await
+    .unwrap();
+    let audio = output.audio.clone();
+
+    // There were 3 chunks and 2 failures. No more than 2 requests were made at once, and the
+    // progress shows every chunk as done
+    assert_eq!(server.num_requests(), 5);
+    assert!(server.max_in_flight() <= 2);
+    assert_eq!(
+        *progress_rx.borrow(),
+        TtsProgress {
+            chunks_done: 3,
+            chunks_total: 3
+        }
+    );
+    // Each chunk was a paragraph in an SSML document, and was turned into a fixed number of
+    // frames. The frames were all concatenated, behind a single header frame
+    let chunks = render_ssml_chunks(&parse_blocks(&text), Lang::Eng, MAX_CHARS_PER_REQUEST)
+        .unwrap()
+        .chunks;
+    assert_eq!(chunks.len(), 3);
+    let frames_per_chunk: Vec<usize> = chunks.iter().map(|c| c.len() / BYTES_PER_FRAME).collect();
+    assert_eq!(
+        audio.len(),
+        (frames_per_chunk.iter().sum::<usize>() + 1) * MP3_FRAME_SIZE
+    );
+
+    // Every sentence got a timepoint. They're in order, and each paragraph starts where the last
+    // one ended
+    let alignment = &output.alignment.0;
+    assert_eq!(alignment.len(), 3 * 60);
+    assert_eq!(
+        alignment[0].text,
+        "All work and no play makes Jack a dull boy."
+    );
+    assert!(alignment.windows(2).all(|w| w[0].time < w[1].time));
+    let chunk_durations: Vec<f64> = frames_per_chunk
+        .iter()
+        .map(|&n| (n * 1152) as f64 / 48000.0)
+        .collect();
+    assert!((alignment[60].time - chunk_durations[0]).abs() < 1e-6);
+    assert!((alignment[120].time - chunk_durations[0] - chunk_durations[1]).abs() < 1e-6);
+
+    // Every billed character sent, which is all but the marks, was recorded in the usage log. The
+    // voice is a Wavenet voice
+    let prices = TierPrices {
+        standard: 4.0,
+        wavenet: 16.0,
+        neural2: 16.0,
+    };
+    let total_chars: u64 = chunks.iter().map(|c| billed_len(c) as u64).sum();
+    let usage_chars = |usage: &UsageLog| {
+        let report = usage.report(&prices);
+        let month = report.months.values().next().unwrap();
+        month.billed_chars[&VoiceTier::Wavenet]
+    };
+    assert_eq!(usage_chars(&usage), total_chars);
+
+    // Every chunk is cached now. Speaking the text again makes no requests, and gives the same
+    // output
+    assert_eq!(plan.uncached_len(&cache, engine.output_format()), 0);
+    let cached_output = tts(
+        &engine,
+        &plan,
+        &cache,
+        &usage,
+        max_concurrency,
+        None,
+        &progress_tx,
+    )
+    .await
+    .unwrap();
+    assert_eq!(server.num_requests(), 5);
+    assert_eq!(usage_chars(&usage), total_chars);
+    assert_eq!(cached_output.audio, output.audio);
+    assert_eq!(cached_output.alignment.0.len(), alignment.len());
+}
+
+#[tokio::test]
+async fn section_cues() {
+    use crate::{
+        mp3::CHIME,
+        tts::{
+            cache::ChunkCache,
+            mock::{MockTtsServer, MP3_FRAME_SIZE},
+            plan_requests, tts, SectionCue, SectionCueKind, TtsProgress, VoicedSegment,
+        },
+        usage::UsageLog,
+    };
+    use core::{num::NonZeroUsize, time::Duration};
+    use tokio::sync::watch;
+    use whatlang::Lang;
+
+    let server = MockTtsServer::spawn(0);
+    let engine = GcpTts::new(
+        "fake-key".to_string(),
+        &server.api_base,
+        gcp_voices(),
+        AudioFormat::default(),
+    )
+    .unwrap();
+
+    // The article fits in one request, but every section gets its own. The title is the first
+    // section. A section can start in the middle of a segment, or at the start of one
+    let paragraph = "All work and no play makes Jack a dull boy. ".repeat(5);
+    let segments = [
+        VoicedSegment {
+            text: format!("# The Shining\n{paragraph}\n## Part One\n{paragraph}"),
+            voice_name: "en-US-Wavenet-C".to_string(),
+            lang: Lang::Eng,
+        },
+        VoicedSegment {
+            text: format!("## Part *Two*\n{paragraph}"),
+            voice_name: "en-US-Wavenet-D".to_string(),
+            lang: Lang::Eng,
+        },
+    ];
+    let plan = plan_requests(&engine, &segments).unwrap();
+    assert_eq!(plan.requests.len(), 3);
+
+    // Speak the article with and without an earcon between sections
+    let tts_dir = tempfile::tempdir().unwrap();
+    let cache = ChunkCache::new(tts_dir.path().join("chunks"), u64::MAX).unwrap();
+    let usage = UsageLog::open(tts_dir.path().join("usage.json")).unwrap();
+    let (progress_tx, _) = watch::channel(TtsProgress::default());
+    let max_concurrency = NonZeroUsize::new(2).unwrap();
+    let cue = SectionCue {
+        kind: SectionCueKind::Earcon,
+        pause: Duration::from_secs(1),
+    };
+    let speak = |cue| {
+        tts(
+            &engine,
+            &plan,
+            &cache,
+            &usage,
+            max_concurrency,
+            cue,
+            &progress_tx,
+        )
+    };
+    let plain = speak(None).await.unwrap();
+    let cued = speak(Some(cue)).await.unwrap();
+
+    // There are 2 cues: the chime, with half a second of silence on either side. Half a second is
+    // 20.8 frames, rounded up
+    let cue_len = CHIME.len() + 2 * 21 * MP3_FRAME_SIZE;
+    let cue_duration = (CHIME.len() / MP3_FRAME_SIZE + 2 * 21) as f64 * 1152.0 / 48000.0;
+    assert_eq!(cued.audio.len(), plain.audio.len() + 2 * cue_len);
+
+    // Every section starts where its cue does, so it's only pushed back by the cues before it. Its
+    // heading is read right after its cue
+    let headings: Vec<&str> = cued.sections.0.iter().map(|s| s.heading.as_str()).collect();
+    assert_eq!(headings, ["The Shining", "Part One", "Part Two"]);
+    for (i, (cued_section, plain_section)) in
+        cued.sections.0.iter().zip(&plain.sections.0).enumerate()
+    {
+        let cue_offset = i.saturating_sub(1) as f64 * cue_duration;
+        assert!((cued_section.time - plain_section.time - cue_offset).abs() < 1e-6);
+        let heading_start = cued
+            .alignment
+            .0
+            .iter()
+            .find(|tp| tp.text == headings[i])
+            .unwrap()
+            .time;
+        let cue_end = cued_section.time + if i > 0 { cue_duration } else { 0.0 };
+        assert!((heading_start - cue_end).abs() < 1e-6);
+    }
+    assert_eq!(cued.sections.0[0].time, 0.0);
+}
+
+#[tokio::test]
+async fn voice_discovery() {
+    use crate::tts::{mock::MockTtsServer, VoiceQuality, VoiceTier};
+    use whatlang::Lang;
+
+    let server = MockTtsServer::spawn(0);
+    let dir = tempfile::tempdir().unwrap();
+    let voices_path = dir.path().join("gcp_voices.json");
+
+    // With no voices file, we use the compiled-in voices. Same with an invalid one
+    let num_builtin = gcp_voices().len();
+    assert_eq!(load_voices(&voices_path).len(), num_builtin);
+    fs::write(&voices_path, r#"{"voices": []}"#).unwrap();
+    assert_eq!(load_voices(&voices_path).len(), num_builtin);
+
+    // Refreshing replaces the file with the API's list. The voices in languages and tiers we don't
+    // know are left out
+    let num_voices = refresh_voices("fake-key", &server.api_base, &voices_path)
+        .await
+        .unwrap();
+    assert_eq!(num_voices, 5);
+    let voices = load_voices(&voices_path);
+    let ids: Vec<&str> = voices.iter().map(|v| v.id.as_str()).collect();
+    assert_eq!(
+        ids,
+        [
+            "en-US-Wavenet-C",
+            "en-US-Neural2-C",
+            "en-GB-Neural2-B",
+            "tr-TR-Wavenet-E",
+            "en-US-Standard-A",
+        ]
+    );
+
+    // The voices are described like the compiled-in ones
+    let en_gb = &voices[2];
+    assert_eq!(en_gb.lang, Lang::Eng);
+    assert_eq!(en_gb.english_desc, "English (UK)");
+    assert_eq!(en_gb.ty, VoiceType::LowPitch);
+    assert_eq!(en_gb.tier, VoiceTier::Neural2);
+    assert_eq!(voices[3].lang, Lang::Tur);
+    assert_eq!(voices[4].quality, VoiceQuality::Standard);
+
+    // A failed refresh leaves the file alone, and doesn't leak the API key
+    let err = refresh_voices("secret-key", "http://127.0.0.1:1/v1beta1", &voices_path)
+        .await
+        .unwrap_err();
+    assert!(!format!("{err:?}").contains("secret-key"));
+    assert_eq!(load_voices(&voices_path).len(), 5);
+}
+
+#[test]
+fn request_language() {
+    // The language code is the voice's locale, not always English
+    let req = TtsRequest {
+        text: "Merhaba".to_string(),
+        ssml: false,
+        voice_name: "tr-TR-Wavenet-E".to_string(),
+    };
+    let json = request_to_json(&req, "MP3");
+    assert_eq!(json["voice"]["languageCode"], "tr-TR");
+    assert_eq!(voice_locale("cmn-CN-Wavenet-A"), "cmn-CN");
+}
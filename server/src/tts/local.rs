@@ -0,0 +1,297 @@
+//! Implements a TTS engine that runs a locally installed synthesizer (espeak-ng or Piper) as a
+//! subprocess. Neither needs an API key or network access. The synthesizers output WAV, which we
+//! encode to MP3 or Opus with ffmpeg, so that the rest of the server can treat the output the same
+//! as Google Cloud's.
+
+use crate::tts::{
+    AudioCodec, AudioFormat, SynthesizedChunk, TtsEngine, TtsRequest, VoiceInfo, VoiceQuality,
+    VoiceTier, VoiceType,
+};
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use anyhow::{anyhow, bail, Error as AnyError};
+use async_process::Command;
+use bytes::Bytes;
+use futures::{
+    future::{BoxFuture, FutureExt},
+    AsyncWriteExt,
+};
+use whatlang::Lang;
+
+/// Local synthesizers have no hard limit on input size. But smaller chunks mean more of the
+/// article gets synthesized in parallel.
+const MAX_CHARS_PER_REQUEST: usize = 5000;
+
+/// The languages we know how to speak locally. The entries are the language, the espeak-ng voice
+/// for that language, the ISO 639-1 code that prefixes the language's Piper voice names, and the
+/// English description of the language.
+const LOCAL_LANGS: &[(Lang, &str, &str, &str)] = &[
+    (Lang::Eng, "en-us", "en", "English"),
+    (Lang::Fra, "fr-fr", "fr", "French"),
+    (Lang::Spa, "es", "es", "Spanish"),
+    (Lang::Deu, "de", "de", "German"),
+    (Lang::Ita, "it", "it", "Italian"),
+    (Lang::Por, "pt-br", "pt", "Portuguese"),
+    (Lang::Nld, "nl", "nl", "Dutch"),
+    (Lang::Rus, "ru", "ru", "Russian"),
+    (Lang::Ukr, "uk", "uk", "Ukrainian"),
+    (Lang::Pol, "pl", "pl", "Polish"),
+    (Lang::Ces, "cs", "cs", "Czech"),
+    (Lang::Slk, "sk", "sk", "Slovak"),
+    (Lang::Hun, "hu", "hu", "Hungarian"),
+    (Lang::Ron, "ro", "ro", "Romanian"),
+    (Lang::Ell, "el", "el", "Greek"),
+    (Lang::Tur, "tr", "tr", "Turkish"),
+    (Lang::Swe, "sv", "sv", "Swedish"),
+    (Lang::Dan, "da", "da", "Danish"),
+    (Lang::Nob, "nb", "no", "Norwegian"),
+    (Lang::Fin, "fi", "fi", "Finnish"),
+    (Lang::Cat, "ca", "ca", "Catalan"),
+    (Lang::Srp, "sr", "sr", "Serbian"),
+    (Lang::Vie, "vi", "vi", "Vietnamese"),
+    (Lang::Cmn, "cmn", "zh", "Mandarin Chinese"),
+    (Lang::Ara, "ar", "ar", "Arabic"),
+    (Lang::Hin, "hi", "hi", "Hindi"),
+    (Lang::Pes, "fa", "fa", "Persian"),
+    (Lang::Kat, "ka", "ka", "Georgian"),
+];
+
+/// The local synthesizers we know how to run
+#[derive(Clone, Debug)]
+pub(crate) enum LocalSynth {
+    /// espeak-ng. Robotic, but supports nearly every language and is in every package manager
+    EspeakNg,
+    /// Piper. Much more natural than espeak-ng. Each voice is a `.onnx` model file in the given
+    /// directory, named like `en_US-lessac-medium.onnx`
+    Piper { voice_dir: PathBuf },
+}
+
+/// A TTS engine that runs a local synthesizer and encodes its output with ffmpeg
+pub(crate) struct LocalTts {
+    /// Which synthesizer we're running
+    synth: LocalSynth,
+    /// The path to the synthesizer binary
+    synth_cmd: String,
+    /// The path to the ffmpeg binary
+    ffmpeg_cmd: String,
+    /// The format ffmpeg encodes to
+    format: AudioFormat,
+    /// The voices available to the synthesizer
+    voices: Vec<VoiceInfo>,
+}
+
+impl LocalTts {
+    /// Makes a new local TTS engine that outputs audio in the given format. If `synth_cmd` is
+    /// `None`, the synthesizer is looked up in the `PATH`. Errors if the synthesizer has no voices
+    /// we can use.
+    pub(crate) fn new(
+        synth: LocalSynth,
+        synth_cmd: Option<String>,
+        ffmpeg_cmd: String,
+        format: AudioFormat,
+    ) -> Result<Self, AnyError> {
+        let (default_cmd, voices) = match &synth {
+            LocalSynth::EspeakNg => ("espeak-ng", espeak_voices()),
+            LocalSynth::Piper { voice_dir } => ("piper", piper_voices(voice_dir)?),
+        };
+
+        if voices.is_empty() {
+            bail!("Found no voices for the local TTS engine {:?}", synth);
+        }
+
+        Ok(LocalTts {
+            synth,
+            synth_cmd: synth_cmd.unwrap_or(default_cmd.to_string()),
+            ffmpeg_cmd,
+            format,
+            voices,
+        })
+    }
+
+    /// Runs the synthesizer on the given text and returns the resulting WAV file
+    async fn synthesize_wav(&self, req: &TtsRequest) -> Result<Vec<u8>, AnyError> {
+        let mut cmd = Command::new(&self.synth_cmd);
+        match &self.synth {
+            LocalSynth::EspeakNg => {
+                // -m tells espeak-ng to interpret SSML
+                if req.ssml {
+                    cmd.arg("-m");
+                }
+                cmd.arg("-v").arg(&req.voice_name).arg("--stdout")
+            }
+            LocalSynth::Piper { voice_dir } => cmd
+                .arg("--model")
+                .arg(voice_dir.join(&req.voice_name).with_extension("onnx"))
+                .arg("--output_file")
+                .arg("-"),
+        };
+
+        run_with_stdin(&mut cmd, req.text.as_bytes())
+            .await
+            .map_err(|e| anyhow!("Local synthesis with {} failed: {e}", self.synth_cmd))
+    }
+
+    /// Encodes the given WAV file in the engine's output format. This is 48kHz mono, like what
+    /// Google Cloud outputs.
+    async fn encode_wav(&self, wav: &[u8]) -> Result<Vec<u8>, AnyError> {
+        let bitrate = format!("{}k", self.format.bitrate_kbps);
+        let mut cmd = Command::new(&self.ffmpeg_cmd);
+        cmd.args(["-hide_banner", "-loglevel", "error"])
+            .args(["-f", "wav", "-i", "pipe:0"])
+            .args(["-ar", "48000", "-ac", "1"]);
+        match self.format.codec {
+            // Leave out the Xing header and ID3 tag. The chunks get a single header of their own
+            // when they're joined
+            AudioCodec::Mp3 => cmd
+                .args(["-codec:a", "libmp3lame", "-b:a", &bitrate])
+                .args(["-write_xing", "0", "-id3v2_version", "0"])
+                .args(["-f", "mp3", "pipe:1"]),
+            AudioCodec::OggOpus => cmd
+                .args(["-codec:a", "libopus", "-b:a", &bitrate])
+                .args(["-application", "voip"])
+                .args(["-f", "ogg", "pipe:1"]),
+        };
+
+        run_with_stdin(&mut cmd, wav)
+            .await
+            .map_err(|e| anyhow!("{} encoding failed: {e}", self.format.codec))
+    }
+}
+
+impl TtsEngine for LocalTts {
+    fn max_request_size(&self) -> usize {
+        MAX_CHARS_PER_REQUEST
+    }
+
+    fn output_format(&self) -> AudioFormat {
+        self.format
+    }
+
+    fn supports_ssml(&self) -> bool {
+        // Piper only reads plain text
+        matches!(self.synth, LocalSynth::EspeakNg)
+    }
+
+    fn list_voices(&self) -> Vec<VoiceInfo> {
+        self.voices.clone()
+    }
+
+    fn synthesize<'a>(
+        &'a self,
+        req: &'a TtsRequest,
+    ) -> BoxFuture<'a, Result<SynthesizedChunk, AnyError>> {
+        async move {
+            let wav = self.synthesize_wav(req).await?;
+            let audio = self.encode_wav(&wav).await?;
+
+            // The synthesizer CLIs can't tell us when marks are reached
+            Ok(SynthesizedChunk {
+                audio: Bytes::from(audio),
+                timepoints: Vec::new(),
+            })
+        }
+        .boxed()
+    }
+}
+
+/// Runs the given command, feeding it `input` over stdin. Returns the command's stdout.
+async fn run_with_stdin(cmd: &mut Command, input: &[u8]) -> Result<Vec<u8>, AnyError> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("could not start process: {:?}", e))?;
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or(anyhow!("could not get stdin from child process"))?;
+
+    // Write the input and read the output at the same time. Otherwise the child could block on
+    // a full stdout pipe while we block on a full stdin pipe. Dropping stdin closes it, which
+    // tells the child the input is over.
+    let write_input = async move {
+        let res = stdin.write_all(input).await;
+        drop(stdin);
+        res
+    };
+    let (write_res, output) = futures::join!(write_input, child.output());
+    let output = output.map_err(|e| anyhow!("error waiting on process: {:?}", e))?;
+    write_res.map_err(|e| anyhow!("error feeding input to process: {:?}", e))?;
+
+    // See if the command failed
+    if !output.status.success() {
+        bail!(
+            "process exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    Ok(output.stdout)
+}
+
+/// Returns the espeak-ng voices for every language in `LOCAL_LANGS`. The default espeak-ng voices
+/// are low-pitched. The `+f3` variant of each is high-pitched.
+fn espeak_voices() -> Vec<VoiceInfo> {
+    LOCAL_LANGS
+        .iter()
+        .flat_map(|&(lang, espeak_id, _, english_desc)| {
+            let variants = [
+                (format!("{espeak_id}+f3"), VoiceType::HighPitch),
+                (espeak_id.to_string(), VoiceType::LowPitch),
+            ];
+            variants.into_iter().map(move |(id, ty)| VoiceInfo {
+                id,
+                lang,
+                english_desc: english_desc.to_string(),
+                quality: VoiceQuality::Standard,
+                ty,
+                tier: VoiceTier::Local,
+            })
+        })
+        .collect()
+}
+
+/// Returns the Piper voices whose models are in the given directory. Voices in languages that
+/// aren't in `LOCAL_LANGS` are skipped.
+fn piper_voices(voice_dir: &Path) -> Result<Vec<VoiceInfo>, AnyError> {
+    let dir = fs::read_dir(voice_dir)
+        .map_err(|e| anyhow!("Could not list Piper voice dir {:?}: {e}", voice_dir))?;
+
+    let mut voices: Vec<VoiceInfo> = dir
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != "onnx" {
+                return None;
+            }
+
+            // Voices are named like LANG_REGION-NAME-QUALITY, e.g., en_US-lessac-medium
+            let id = path.file_stem()?.to_str()?.to_string();
+            let lang_prefix = id.split(['_', '-']).next()?;
+            let &(lang, _, _, english_desc) = LOCAL_LANGS
+                .iter()
+                .find(|(_, _, piper_prefix, _)| *piper_prefix == lang_prefix)?;
+
+            // Piper doesn't tell us the pitch of its voices. Call them all high-pitched, since
+            // that's what we ask for by default
+            Some(VoiceInfo {
+                id,
+                lang,
+                english_desc: english_desc.to_string(),
+                quality: VoiceQuality::High,
+                ty: VoiceType::HighPitch,
+                tier: VoiceTier::Local,
+            })
+        })
+        .collect();
+
+    // Directory listings aren't in any particular order. Sort them so voice selection is stable
+    voices.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(voices)
+}
@@ -0,0 +1,254 @@
+//! A mock of the Google Cloud `text:synthesize` endpoint. It speaks the same JSON protocol, and
+//! returns silent MP3 frames in `audioContent`. This lets us test the whole add-article flow
+//! without network access or an API key. It also mocks the `voices` endpoint, with a short list
+//! of voices.
+
+use crate::tts::gcp::voice_locale;
+
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::Extension,
+    http::StatusCode,
+    http::Uri,
+    routing::{get, post},
+    Json, Router,
+};
+use ogg::{PacketWriteEndInfo, PacketWriter};
+
+/// The header of an MPEG-1 Layer III frame at 64kbps, 48kHz, mono. This is the format we ask
+/// Google Cloud for.
+const SILENT_FRAME_HEADER: [u8; 4] = [0xFF, 0xFB, 0x54, 0xC4];
+
+/// The size of a 64kbps 48kHz MP3 frame is 144 * 64000 / 48000 bytes
+pub(crate) const MP3_FRAME_SIZE: usize = 192;
+
+/// The mock returns one frame of audio for every this many bytes of text
+pub(crate) const BYTES_PER_FRAME: usize = 100;
+
+/// The number of samples in the Opus frames the mock returns. This is 20ms at 48kHz
+pub(crate) const OPUS_FRAME_SAMPLES: u64 = 960;
+
+/// The pre-skip the mock puts in its Opus headers. This is what libopus uses
+const OPUS_PRE_SKIP: u16 = 312;
+
+/// The voices the mock's `voices` endpoint lists. They're in no particular order, and include a
+/// voice in a language we don't detect, and one in a tier we don't know.
+pub(crate) const MOCK_VOICE_LIST: &str = r#"{"voices": [
+    {"languageCodes": ["en-GB"], "name": "en-GB-Neural2-B", "ssmlGender": "MALE"},
+    {"languageCodes": ["en-US"], "name": "en-US-Standard-A", "ssmlGender": "MALE"},
+    {"languageCodes": ["en-US"], "name": "en-US-Neural2-C", "ssmlGender": "FEMALE"},
+    {"languageCodes": ["en-US"], "name": "en-US-Wavenet-C", "ssmlGender": "FEMALE"},
+    {"languageCodes": ["en-US"], "name": "en-US-Studio-O", "ssmlGender": "FEMALE"},
+    {"languageCodes": ["tr-TR"], "name": "tr-TR-Wavenet-E", "ssmlGender": "MALE"},
+    {"languageCodes": ["xx-XX"], "name": "xx-XX-Standard-A", "ssmlGender": "FEMALE"}
+]}"#;
+
+/// Returns `n` frames of silent MP3 audio. A frame whose side info is all zeros has no samples
+/// in it, so it decodes to silence.
+pub(crate) fn silent_mp3_frames(n: usize) -> Vec<u8> {
+    let mut frame = [0u8; MP3_FRAME_SIZE];
+    frame[..4].copy_from_slice(&SILENT_FRAME_HEADER);
+    frame.repeat(n)
+}
+
+/// Returns an Ogg Opus stream with `n` frames of silence. Every frame is a zero-length packet,
+/// which decoders treat as silence.
+pub(crate) fn silent_ogg_opus(n: usize) -> Vec<u8> {
+    let serial = 0x5254_4d53;
+    let mut writer = PacketWriter::new(Vec::new());
+
+    // OpusHead: version 1, mono, our pre-skip, 48kHz input, no gain, mapping family 0
+    let mut head = b"OpusHead".to_vec();
+    head.extend_from_slice(&[1, 1]);
+    head.extend_from_slice(&OPUS_PRE_SKIP.to_le_bytes());
+    head.extend_from_slice(&48000u32.to_le_bytes());
+    head.extend_from_slice(&[0, 0, 0]);
+    writer
+        .write_packet(head.into(), serial, PacketWriteEndInfo::EndPage, 0)
+        .unwrap();
+
+    // OpusTags: a vendor string and no comments
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend_from_slice(&4u32.to_le_bytes());
+    tags.extend_from_slice(b"mock");
+    tags.extend_from_slice(&0u32.to_le_bytes());
+    writer
+        .write_packet(tags.into(), serial, PacketWriteEndInfo::EndPage, 0)
+        .unwrap();
+
+    // The audio. The TOC byte 0xF8 means one 20ms fullband CELT frame, mono. Put 50 frames (1s) on
+    // every page
+    for i in 0..n {
+        let end_info = if i + 1 == n {
+            PacketWriteEndInfo::EndStream
+        } else if i % 50 == 49 {
+            PacketWriteEndInfo::EndPage
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+        let granule = (i as u64 + 1) * OPUS_FRAME_SAMPLES;
+        writer
+            .write_packet(vec![0xF8].into(), serial, end_info, granule)
+            .unwrap();
+    }
+
+    writer.into_inner()
+}
+
+/// A handle to a running mock TTS server
+#[derive(Clone)]
+pub(crate) struct MockTtsServer {
+    /// The base URL to give to `GcpTts::new`
+    pub(crate) api_base: String,
+    /// The number of synthesis requests the server has received, including failed ones
+    num_requests: Arc<AtomicUsize>,
+    /// The number of upcoming requests that the server will fail with a 503
+    num_failures: Arc<AtomicUsize>,
+    /// The number of requests the server is currently handling
+    num_in_flight: Arc<AtomicUsize>,
+    /// The most requests the server has ever handled at once
+    max_in_flight: Arc<AtomicUsize>,
+}
+
+impl MockTtsServer {
+    /// Starts a mock TTS server on a random local port. The first `num_failures` requests it
+    /// receives will fail, which is useful for testing retries.
+    pub(crate) fn spawn(num_failures: usize) -> MockTtsServer {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = MockTtsServer {
+            api_base: format!("http://{addr}/v1beta1"),
+            num_requests: Arc::default(),
+            num_failures: Arc::new(AtomicUsize::new(num_failures)),
+            num_in_flight: Arc::default(),
+            max_in_flight: Arc::default(),
+        };
+
+        // The endpoint has a colon in it, which the router would treat as a path parameter. So
+        // just send everything to the handler and check the path there.
+        let app = Router::new()
+            .route("/v1beta1/voices", get(list_voices))
+            .fallback(post(synthesize))
+            .layer(Extension(server.clone()));
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        server
+    }
+
+    /// Returns the number of synthesis requests the server has received, including failed ones
+    pub(crate) fn num_requests(&self) -> usize {
+        self.num_requests.load(Ordering::SeqCst)
+    }
+
+    /// Returns the most synthesis requests the server has ever handled at once
+    pub(crate) fn max_in_flight(&self) -> usize {
+        self.max_in_flight.load(Ordering::SeqCst)
+    }
+}
+
+/// Handles a `voices` request. Returns `MOCK_VOICE_LIST`, as long as there's an API key
+async fn list_voices(uri: Uri) -> Result<Json<serde_json::Value>, StatusCode> {
+    if !uri.query().unwrap_or_default().starts_with("key=") {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(Json(serde_json::from_str(MOCK_VOICE_LIST).unwrap()))
+}
+
+/// Handles a `text:synthesize` request. Returns `len(text) / BYTES_PER_FRAME` frames of silence,
+/// and at least 1 frame. The audio is Ogg Opus if that's the requested encoding, and MP3
+/// otherwise. If timepointing is enabled, the marks in the SSML are evenly spaced throughout the
+/// audio.
+async fn synthesize(
+    uri: Uri,
+    Extension(server): Extension<MockTtsServer>,
+    Json(payload): Json<serde_json::Value>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    if !uri.path().ends_with("/text:synthesize") {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    server.num_requests.fetch_add(1, Ordering::SeqCst);
+
+    // Take a little while to respond, like the real thing. Keep track of how many requests
+    // overlap while we do
+    let in_flight = server.num_in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+    server.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    server.num_in_flight.fetch_sub(1, Ordering::SeqCst);
+
+    // Fail if we've been told to
+    let should_fail = server
+        .num_failures
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .is_ok();
+    if should_fail {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    // The real API needs a voice and an input
+    let input = &payload["input"];
+    let text = input["text"]
+        .as_str()
+        .or(input["ssml"].as_str())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let voice_name = payload["voice"]["name"]
+        .as_str()
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    // It also rejects a language code that isn't the voice's
+    if payload["voice"]["languageCode"] != voice_locale(voice_name) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let num_frames = core::cmp::max(1, text.len() / BYTES_PER_FRAME);
+    let (audio, duration) = if payload["audioConfig"]["audioEncoding"] == "OGG_OPUS" {
+        let num_samples = num_frames as u64 * OPUS_FRAME_SAMPLES - OPUS_PRE_SKIP as u64;
+        (silent_ogg_opus(num_frames), num_samples as f64 / 48000.0)
+    } else {
+        // Every MP3 frame is 1152 samples at 48kHz
+        let duration = (num_frames * 1152) as f64 / 48000.0;
+        (silent_mp3_frames(num_frames), duration)
+    };
+
+    // Collect the names of all the marks, if timepointing is enabled
+    let timepointing = payload["enableTimePointing"]
+        .as_array()
+        .map(|types| types.iter().any(|t| t == "SSML_MARK"))
+        .unwrap_or(false);
+    let mark_names: Vec<&str> = if timepointing {
+        text.split(r#"<mark name=""#)
+            .skip(1)
+            .filter_map(|s| s.split('"').next())
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    // Space the marks evenly
+    let timepoints = mark_names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            serde_json::json!({
+                "markName": name,
+                "timeSeconds": i as f64 * duration / mark_names.len() as f64,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Json(serde_json::json!({
+        "audioContent": base64::encode(audio),
+        "timepoints": timepoints,
+    })))
+}
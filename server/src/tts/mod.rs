@@ -0,0 +1,714 @@
+//! Defines the interface that text-to-speech engines implement, as well as the engine-agnostic
+//! logic for breaking an article into chunks and speaking them, and for marking where its sections
+//! begin
+
+pub(crate) mod cache;
+pub(crate) mod gcp;
+pub(crate) mod local;
+#[cfg(test)]
+pub(crate) mod mock;
+pub(crate) mod ssml;
+
+use crate::{
+    mp3,
+    tts::{cache::ChunkCache, ssml::Block},
+    usage::UsageLog,
+    util::{audio_bytes_duration, concat_audio},
+};
+
+use anyhow::{bail, Error as AnyError};
+use bytes::Bytes;
+use clap::ValueEnum;
+use common::{ArticleAlignment, ArticleSections, SectionTimepoint, SentenceTimepoint};
+use futures::{
+    future::{BoxFuture, FutureExt, TryFutureExt},
+    stream::{self, StreamExt, TryStreamExt},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio_retry::{
+    strategy::{jitter, ExponentialBackoff},
+    Retry,
+};
+use whatlang::Lang;
+
+use core::{future::Future, iter, num::NonZeroUsize, time::Duration};
+use std::sync::Arc;
+
+// The voice and audio options are part of the API, so they're defined in common
+pub(crate) use common::{AudioCodec, VoiceQuality, VoiceTier, VoiceType};
+
+/// The TTS engines this server knows how to use
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub(crate) enum TtsEngineKind {
+    /// Google Cloud Text-to-Speech. Requires an API key in `gcp_api.key`
+    Gcp,
+    /// A local espeak-ng install. Requires ffmpeg
+    EspeakNg,
+    /// A local Piper install. Requires ffmpeg
+    Piper,
+}
+
+/// A TTS engine, shared between all the request handlers
+pub(crate) type SharedTtsEngine = Arc<dyn TtsEngine>;
+
+/// The format of the audio that a TTS engine outputs
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct AudioFormat {
+    /// The codec of the audio
+    pub(crate) codec: AudioCodec,
+    /// The target bitrate, in kbps
+    pub(crate) bitrate_kbps: u32,
+}
+
+impl Default for AudioFormat {
+    /// 64kbps MP3, which is what Google Cloud has always given us
+    fn default() -> Self {
+        AudioFormat {
+            codec: AudioCodec::Mp3,
+            bitrate_kbps: 64,
+        }
+    }
+}
+
+/// The kinds of audio cue that can be played between the sections of an article
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum SectionCueKind {
+    /// A pause
+    Silence,
+    /// A pause with a short chime in the middle of it
+    Earcon,
+}
+
+/// The audio cue that's played before every section of an article but the first. Cues are spliced
+/// in frame by frame, so they're only played in MP3 audio.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct SectionCue {
+    /// What the cue sounds like
+    pub(crate) kind: SectionCueKind,
+    /// The total length of the silence in the cue
+    pub(crate) pause: Duration,
+}
+
+impl SectionCue {
+    /// Renders the cue as an MP3 stream in the same format as `like`. If the chime's format is
+    /// different, it's left out.
+    fn render_mp3(&self, like: &[u8]) -> Result<Vec<u8>, AnyError> {
+        if self.kind == SectionCueKind::Earcon {
+            if mp3::same_format(mp3::CHIME, like)? {
+                let half_pause = mp3::silence_like(like, self.pause / 2)?;
+                return Ok([half_pause.as_slice(), mp3::CHIME, &half_pause].concat());
+            }
+            tracing::warn!("The chime doesn't match the format of the TTS audio. Using silence");
+        }
+        mp3::silence_like(like, self.pause)
+    }
+}
+
+/// The engine-agnostic description of a reading voice
+#[derive(Clone)]
+pub(crate) struct VoiceInfo {
+    /// The unique voice identifier
+    pub(crate) id: String,
+    /// The language this voice speaks
+    pub(crate) lang: Lang,
+    /// The English description of this voice. E.g., "Portuguese (Brazil)"
+    pub(crate) english_desc: String,
+    /// The quality of this voice
+    pub(crate) quality: VoiceQuality,
+    /// The type of voice this is (high/low)
+    pub(crate) ty: VoiceType,
+    /// The pricing tier of this voice
+    pub(crate) tier: VoiceTier,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct TtsRequest {
+    /// The contents of the request
+    pub text: String,
+    /// Whether `text` is an SSML document rather than plain text
+    pub ssml: bool,
+    /// The ID of the voice to use
+    pub voice_name: String,
+}
+
+impl TtsRequest {
+    /// Returns the number of characters we're billed for when this request is sent. That's every
+    /// character of its text, except SSML `<mark>` tags
+    pub(crate) fn billed_len(&self) -> usize {
+        if self.ssml {
+            ssml::billed_len(&self.text)
+        } else {
+            self.text.chars().count()
+        }
+    }
+}
+
+/// A contiguous part of an article, and the voice to read it with
+#[derive(Clone, Debug)]
+pub(crate) struct VoicedSegment {
+    /// The text of the segment. This is a whole number of lines of the article
+    pub(crate) text: String,
+    /// The ID of the voice to use
+    pub(crate) voice_name: String,
+    /// The language of the text. This decides where the text can be broken into chunks
+    pub(crate) lang: Lang,
+}
+
+/// The point in a chunk's audio where an SSML `<mark>` was reached
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Timepoint {
+    /// The name of the mark
+    pub(crate) mark_name: String,
+    /// The offset from the start of the chunk's audio, in seconds
+    pub(crate) time: f64,
+}
+
+/// The result of speaking a single chunk of text
+pub(crate) struct SynthesizedChunk {
+    /// The audio of the chunk
+    pub(crate) audio: Bytes,
+    /// The timepoints of every mark in the chunk. This is empty if the engine doesn't support
+    /// timepointing.
+    pub(crate) timepoints: Vec<Timepoint>,
+}
+
+/// The result of speaking a whole article
+pub(crate) struct TtsOutput {
+    /// The audio of the article
+    pub(crate) audio: Bytes,
+    /// The alignment of the article's sentences to the audio. This is empty if the engine doesn't
+    /// support timepointing.
+    pub(crate) alignment: ArticleAlignment,
+    /// When each of the article's sections starts. A section that's preceded by a cue starts where
+    /// its cue does. This is empty if the article has no headings.
+    pub(crate) sections: ArticleSections,
+}
+
+/// How far along a `tts` call is
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct TtsProgress {
+    /// The number of chunks that have been synthesized
+    pub(crate) chunks_done: usize,
+    /// The number of chunks the article was broken into
+    pub(crate) chunks_total: usize,
+}
+
+/// A text-to-speech backend. Engines only need to know how to speak a single bounded-size chunk of
+/// text. Breaking up articles and stitching the results together is done by [`tts`].
+pub(crate) trait TtsEngine: Send + Sync {
+    /// The maximum number of bytes of text that can be given to a single `synthesize` call
+    fn max_request_size(&self) -> usize;
+
+    /// The format of the audio that `synthesize` returns
+    fn output_format(&self) -> AudioFormat;
+
+    /// The codec of the audio that `synthesize` returns
+    fn output_codec(&self) -> AudioCodec {
+        self.output_format().codec
+    }
+
+    /// Whether `synthesize` accepts SSML documents
+    fn supports_ssml(&self) -> bool;
+
+    /// Lists all the voices this engine can speak with, in decreasing order of preference
+    fn list_voices(&self) -> Vec<VoiceInfo>;
+
+    /// Speaks a text string of length at most `max_request_size()`. If the request is SSML and the
+    /// engine supports it, this also returns the timepoints of the SSML marks.
+    fn synthesize<'a>(
+        &'a self,
+        req: &'a TtsRequest,
+    ) -> BoxFuture<'a, Result<SynthesizedChunk, AnyError>>;
+
+    /// Returns the ID of a voice that speaks the given language and matches the given sound
+    /// quality. If the voice type is available, the voice will match that too.
+    fn pick_voice(
+        &self,
+        lang: Lang,
+        quality: VoiceQuality,
+        ty: VoiceType,
+    ) -> Result<String, AnyError> {
+        crate::lang::pick_tts_voice(&self.list_voices(), lang, quality, ty)
+    }
+}
+
+/// Performs the given async operation up to `num_retries` times before giving up
+pub(crate) async fn do_with_retry<F, Fut, T, E>(num_retries: usize, f: F) -> Result<T, E>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let retry_strategy = ExponentialBackoff::from_millis(10)
+        .map(jitter) // add jitter to delays
+        .take(num_retries);
+    Retry::spawn(retry_strategy, f).await
+}
+
+/// An article, broken up into requests that the engine can handle
+pub(crate) struct TtsPlan {
+    /// The requests, in order. Every request is paired with the index of its segment's first
+    /// sentence, since mark names are only unique within a segment.
+    requests: Vec<(TtsRequest, usize)>,
+    /// The sentences of the article, in order. SSML marks refer to these
+    sentences: Vec<String>,
+    /// The sections of the article, in order. Every section is the index of the request it starts
+    /// at, paired with its heading.
+    sections: Vec<(usize, String)>,
+}
+
+impl TtsPlan {
+    /// The number of characters we'd be billed for to carry out this plan. Requests whose audio
+    /// is in the cache aren't sent, so they aren't counted.
+    pub(crate) fn uncached_len(&self, cache: &ChunkCache, format: AudioFormat) -> usize {
+        self.requests
+            .iter()
+            .filter(|(req, _)| !cache.contains(req, format))
+            .map(|(req, _)| req.billed_len())
+            .sum()
+    }
+}
+
+/// Returns whether the block is the heading of a section
+fn starts_section(block: &Block) -> bool {
+    matches!(block, Block::Heading(h) if !h.is_empty())
+}
+
+/// Breaks the segments up into requests that the engine can handle. Chunks never span segments,
+/// since every request has a single voice, and every section starts a new chunk, so that a cue
+/// can be played before it. If the engine speaks SSML, use it to convey the article's structure,
+/// and to mark where every sentence begins.
+pub(crate) fn plan_requests(
+    engine: &dyn TtsEngine,
+    segments: &[VoicedSegment],
+) -> Result<TtsPlan, AnyError> {
+    let max_chunk_size = engine.max_request_size();
+    let mut requests = Vec::new();
+    let mut sentences = Vec::new();
+    let mut sections = Vec::new();
+    for segment in segments {
+        let segment_blocks = ssml::parse_blocks(&segment.text);
+        for blocks in segment_blocks.chunk_by(|_, b| !starts_section(b)) {
+            if let Some(Block::Heading(h)) = blocks.first().filter(|b| starts_section(b)) {
+                sections.push((requests.len(), ssml::written_text(h)));
+            }
+
+            let sentence_offset = sentences.len();
+            if engine.supports_ssml() {
+                let rendered = ssml::render_ssml_chunks(blocks, segment.lang, max_chunk_size)?;
+                requests.extend(rendered.chunks.into_iter().map(|slice| {
+                    let req = TtsRequest {
+                        text: slice,
+                        ssml: true,
+                        voice_name: segment.voice_name.clone(),
+                    };
+                    (req, sentence_offset)
+                }));
+                sentences.extend(rendered.sentences);
+            } else {
+                let plain = ssml::render_plain(blocks, segment.lang);
+                for slice in break_text(&plain, segment.lang, max_chunk_size)? {
+                    let req = TtsRequest {
+                        text: slice.to_string(),
+                        ssml: false,
+                        voice_name: segment.voice_name.clone(),
+                    };
+                    requests.push((req, sentence_offset));
+                }
+            }
+        }
+    }
+
+    Ok(TtsPlan {
+        requests,
+        sentences,
+        sections,
+    })
+}
+
+/// Speaks the planned requests using the given engine. Requests whose audio is in the cache aren't
+/// sent to the engine, and the ones that are sent get cached and recorded in the usage log. At most
+/// `max_concurrency` requests are made at once, and `progress` is updated as each chunk is done.
+/// If there's a `section_cue`, it's played before every section but the first. Returns an error if
+/// an error occurs in any of the engine calls.
+pub(crate) async fn tts(
+    engine: &dyn TtsEngine,
+    plan: &TtsPlan,
+    cache: &ChunkCache,
+    usage: &UsageLog,
+    max_concurrency: NonZeroUsize,
+    section_cue: Option<SectionCue>,
+    progress: &watch::Sender<TtsProgress>,
+) -> Result<TtsOutput, AnyError> {
+    let format = engine.output_format();
+    let voices = &engine.list_voices();
+    let TtsPlan {
+        requests: slice_reqs,
+        sentences,
+        sections: section_starts,
+    } = plan;
+
+    progress.send_replace(TtsProgress {
+        chunks_done: 0,
+        chunks_total: slice_reqs.len(),
+    });
+    let tts_tasks: Vec<BoxFuture<Result<SynthesizedChunk, AnyError>>> = slice_reqs
+        .iter()
+        .map(|(req, _)| {
+            async move {
+                if let Some(chunk) = cache.get(req, format) {
+                    return Ok(chunk);
+                }
+                let chunk = engine.synthesize(req).await?;
+
+                // We're billed for this chunk now, whether or not the rest of the article succeeds
+                let tier = voices
+                    .iter()
+                    .find(|v| v.id == req.voice_name)
+                    .map(|v| v.tier)
+                    .unwrap_or(VoiceTier::Local);
+                usage.record(tier, req.billed_len());
+
+                // A cache failure shouldn't fail the article. It just costs more next time
+                if let Err(e) = cache.put(req, format, &chunk) {
+                    tracing::warn!("Couldn't cache TTS chunk: {e}");
+                }
+                Ok(chunk)
+            }
+            .inspect_ok(|_| progress.send_modify(|p| p.chunks_done += 1))
+            .boxed()
+        })
+        .collect();
+
+    // Do the tasks in parallel, but only a few at a time, so that large articles don't blow
+    // through the engine's per-second quota. The results stay in order. If one task fails, the
+    // rest are cancelled immediately. This prevents us from wasting API calls.
+    let chunks: Vec<SynthesizedChunk> = stream::iter(tts_tasks)
+        .buffered(max_concurrency.get())
+        .try_collect()
+        .await?;
+
+    // Make the cue that goes between sections. It's made out of MP3 frames like the chunks' own,
+    // so there's no cue for other codecs
+    let needs_cue = section_starts.iter().any(|(start, _)| *start > 0);
+    let cue_audio = match (section_cue, chunks.first()) {
+        (Some(cue), Some(first)) if format.codec == AudioCodec::Mp3 && needs_cue => {
+            cue.render_mp3(&first.audio)?
+        }
+        _ => Vec::new(),
+    };
+    let cue_duration = if cue_audio.is_empty() {
+        0.0
+    } else {
+        audio_bytes_duration(&cue_audio, format.codec)?.as_secs_f64()
+    };
+
+    // Lay out the audio, with a cue before every section but the first. Merge the chunks'
+    // timepoints into one alignment. A timepoint is relative to the start of its chunk, so offset
+    // it by the total duration of everything before it. Mark names are indices into the
+    // segment's sentence list.
+    let has_timepoints = chunks.iter().any(|c| !c.timepoints.is_empty());
+    let needs_durations = has_timepoints || !section_starts.is_empty();
+    let mut alignment = ArticleAlignment::default();
+    let mut sections = ArticleSections::default();
+    let mut section_starts = section_starts.iter().peekable();
+    let mut audio_chunks: Vec<&[u8]> = Vec::new();
+    let mut offset = 0.0;
+    for (i, (chunk, (_, sentence_offset))) in chunks.iter().zip(slice_reqs).enumerate() {
+        if let Some((_, heading)) = section_starts.next_if(|(start, _)| *start == i) {
+            sections.0.push(SectionTimepoint {
+                time: offset,
+                heading: heading.clone(),
+            });
+            if i > 0 && !cue_audio.is_empty() {
+                audio_chunks.push(&cue_audio);
+                offset += cue_duration;
+            }
+        }
+
+        if has_timepoints {
+            for tp in &chunk.timepoints {
+                let sentence = tp
+                    .mark_name
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|i| sentences.get(sentence_offset + i));
+                if let Some(text) = sentence {
+                    alignment.0.push(SentenceTimepoint {
+                        time: offset + tp.time,
+                        text: text.clone(),
+                    });
+                }
+            }
+        }
+        audio_chunks.push(&chunk.audio);
+        if needs_durations {
+            offset += audio_bytes_duration(&chunk.audio, format.codec)?.as_secs_f64();
+        }
+    }
+
+    // Join the resulting audio blobs
+    let final_audio = Bytes::from(concat_audio(&audio_chunks, format.codec)?);
+
+    Ok(TtsOutput {
+        audio: final_audio,
+        alignment,
+        sections,
+    })
+}
+
+// Helper function that finds the next index i just past a delimiter in the text such that
+// txt[0, i] is below the chunk limit, as measured by `size`. If no such i is found, then the end
+// of the first delimiter is returned (and text[0, i] is too big). If no delimiter occurs at all,
+// txt.len() is returned.
+fn next_break(
+    text: &str,
+    delims: &[char],
+    max_chunk_size: usize,
+    size: &dyn Fn(&str) -> usize,
+) -> usize {
+    // Keep track of the last break that keeps us under the chunk size limit, and the size of the
+    // text before it
+    let mut last_candidate_break = None;
+    let mut prev_break = 0;
+    let mut prev_size = 0;
+
+    // Find the ends of all the delimiters
+    let delim_ends = text.match_indices(delims).map(|(i, d)| i + d.len());
+
+    // Make the last breakpoint the EOF, otherwise we'd just be counting span size between
+    // delims (leaving out the span between the final delim and EOF)
+    let eof = text.len();
+
+    for cur_break in delim_ends.chain(iter::once(eof)) {
+        let cur_size = prev_size + size(&text[prev_break..cur_break]);
+        if cur_size <= max_chunk_size {
+            last_candidate_break = Some(cur_break);
+            prev_break = cur_break;
+            prev_size = cur_size;
+        } else {
+            // The current break puts us over the chunk limit. Split at the last break if
+            // possible.
+            return last_candidate_break.unwrap_or(cur_break);
+        }
+    }
+
+    // If we made it to the end, then the text is now small enough to be a chunk in itself, and
+    // we're done.
+    return eof;
+}
+
+/// Attempts to break the given text into chunks of size at most `max_chunk_size`, making chunks as
+/// large as possible. The only allowed break points are just after the `delims` characters, so
+/// every delimiter stays at the end of the chunk before it, and the chunks put together are the
+/// whole text. This is best-effort, meaning that there may be chunks returned which exceed
+/// `max_chunk_size`.
+pub(crate) fn break_greedily_at_delim<'a>(
+    mut text: &'a str,
+    delims: &[char],
+    max_chunk_size: usize,
+    size: &dyn Fn(&str) -> usize,
+) -> Vec<&'a str> {
+    let mut chunks = Vec::new();
+
+    while !text.is_empty() {
+        // While the text is not fully chunked, find the next break and break it up.
+        let b = next_break(text, delims, max_chunk_size, size);
+        let (chunk, rest) = text.split_at(b);
+
+        // Save the chunk and truncate the text
+        chunks.push(chunk);
+        text = rest;
+    }
+
+    chunks
+}
+
+/// Attempts to break the given text into chunks of size at most `max_chunk_size`, making chunks as
+/// large as possible. The delimiter sets given are used in decreasing order of preference. If the
+/// text can be broken at just newlines, for example, that'd be preferred. But if there's a very
+/// large paragraph, then we have to break at periods. And if there's a very long sentence, we have
+/// to break on commas, etc.
+fn break_greedily_at_delims<'a>(
+    text: &'a str,
+    max_chunk_size: usize,
+    delim_sets: &[&[char]],
+    size: &dyn Fn(&str) -> usize,
+) -> Result<Vec<&'a str>, AnyError> {
+    // Break the text into the f irst delim first
+    let mut chunks = break_greedily_at_delim(text, delim_sets[0], max_chunk_size, size);
+
+    // If there are chunks greater than max_chunk_size, break them up. Use every delimiter if need
+    // be.
+    for &delims in &delim_sets[1..] {
+        chunks = chunks
+            .into_iter()
+            .flat_map(|chunk| {
+                if size(chunk) > max_chunk_size {
+                    break_greedily_at_delim(chunk, delims, max_chunk_size, size)
+                } else {
+                    vec![chunk]
+                }
+            })
+            .collect();
+    }
+
+    // Now check that everything was broken into sufficiently small pieces
+    for chunk in &chunks {
+        if size(chunk) > max_chunk_size {
+            bail!("Couldn't break text chunk {:?}", chunk);
+        }
+    }
+
+    Ok(chunks)
+}
+
+/// Returns the places text in the given language can be broken, in decreasing order of
+/// preference: newlines, then clause separators like colons, then sentence ends, then commas, and
+/// finally any whitespace, for text with no punctuation at all. Latin punctuation is in every set,
+/// since it shows up in text in every language.
+fn break_delims(lang: Lang) -> &'static [&'static [char]] {
+    const WHITESPACE: &[char] = &[' ', '\t', '\u{3000}'];
+    match lang {
+        Lang::Cmn | Lang::Jpn => &[
+            &['\n'],
+            &['：', '；', ':', ';'],
+            &['。', '！', '？', '.', '!', '?'],
+            &['，', '、', ','],
+            WHITESPACE,
+        ],
+        Lang::Hin | Lang::Mar | Lang::Nep => &[
+            &['\n'],
+            &[':', ';'],
+            &['।', '॥', '.', '!', '?'],
+            &[','],
+            WHITESPACE,
+        ],
+        Lang::Ara | Lang::Pes | Lang::Urd => &[
+            &['\n'],
+            &[':', '؛', ';'],
+            &['.', '!', '؟', '?', '۔'],
+            &['،', ','],
+            WHITESPACE,
+        ],
+        _ => &[&['\n'], &[':', ';'], &['.', '!', '?'], &[','], WHITESPACE],
+    }
+}
+
+/// Breaks text in the given language into bounded-size chunks by newlines, then (if necessary)
+/// colons, then sentence ends, then commas, then whitespace. Sizes are in bytes of UTF-8.
+fn break_text(text: &str, lang: Lang, max_chunk_size: usize) -> Result<Vec<&str>, AnyError> {
+    break_text_sized(text, lang, max_chunk_size, &str::len)
+}
+
+/// Like `break_text`, but sizes are measured by `size`. The size of two pieces of text put together
+/// must be the sum of their sizes.
+pub(crate) fn break_text_sized<'a>(
+    text: &'a str,
+    lang: Lang,
+    max_chunk_size: usize,
+    size: &dyn Fn(&str) -> usize,
+) -> Result<Vec<&'a str>, AnyError> {
+    break_greedily_at_delims(text, max_chunk_size, break_delims(lang), size)
+}
+
+#[test]
+fn text_breaking() {
+    let text = "\
+        Mr James Duffy lived in Chapelizod because he wished to live as far as possible from the \
+        city of which he was a citizen and because he found all the other suburbs of Dublin mean, \
+        modern and pretentious.
+        He lived in an old sombre house and from his windows he could look into the disused \
+        distillery or upwards along the shallow river on which Dublin is built. The lofty walls \
+        of his uncarpeted room were free from pictures. He had himself bought every article of \
+        furniture in the room: a black iron bedstead, an iron washstand, four cane chairs, a \
+        clothes-rack, a coal-scuttle, a fender and irons and a square table on which lay a double \
+        desk. A bookcase had been made in an alcove by means of shelves of white wood. The bed \
+        was clothed with white bedclothes and a black and scarlet rug covered the foot. A little \
+        hand-mirror hung above the washstand and during the day a white-shaded lamp stood as the \
+        sole ornament of the mantelpiece. The books on the white wooden shelves were arranged \
+        from below upwards according to bulk. A complete Wordsworth stood at one end of the \
+        lowest shelf and a copy of the Maynooth Catechism, sewn into the cloth cover of a \
+        notebook, stood at one end of the top shelf. Writing materials were always on the desk. \
+        In the desk lay a manuscript translation of Hauptmann’s Michael Kramer, the stage \
+        directions of which were written in purple ink, and a little sheaf of papers held \
+        together by a brass pin. In these sheets a sentence was inscribed from time to time and, \
+        in an ironical moment, the headline of an advertisement for Bile Beans had been pasted on \
+        to the first sheet. On lifting the lid of the desk a faint fragrance escaped—the \
+        fragrance of new cedarwood pencils or of a bottle of gum or of an overripe apple which \
+        might have been left there and forgotten.\
+    ";
+    let chunk_size = 220;
+    let chunks = break_text(text, Lang::Eng, chunk_size).unwrap();
+
+    // Make sure the chunks add up to the whole text. Every delimiter is kept, at the end of the
+    // chunk before it
+    assert_eq!(chunks.concat(), text);
+    assert!(chunks[..chunks.len() - 1]
+        .iter()
+        .all(|c| c.ends_with(['\n', ':', ';', '.', '!', '?', ',', ' '])));
+
+    // Make sure the chunks are nontrivial and don't exceed the chunk_size
+    for chunk in chunks {
+        assert!(chunk.len() > 1);
+        assert!(chunk.len() <= chunk_size);
+    }
+
+    //
+    // Test for a short text sample
+    //
+
+    let text = "\
+        Mr James Duffy lived in Chapelizod because he wished to live as far as possible from the \
+        city of which he was a citizen and because he found all the other suburbs of Dublin mean, \
+        modern and pretentious.\
+    ";
+    let chunks = break_text(text, Lang::Eng, chunk_size).unwrap();
+    // Make sure there's just one chunk and it's the length of the whole text
+    assert_eq!(chunks.len(), 1);
+    assert_eq!(chunks[0].len(), text.len());
+
+    //
+    // Test languages with their own punctuation. Their delimiters are multiple bytes long, and
+    // are kept whole
+    //
+
+    fn check(text: &str, lang: Lang, chunk_size: usize) -> Vec<&str> {
+        let chunks = break_text(text, lang, chunk_size).unwrap();
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|c| c.len() <= chunk_size));
+        assert_eq!(chunks.concat(), text);
+        chunks
+    }
+
+    // Chinese sentences end in "。", which is 3 bytes. The text is broken between sentences
+    let text = "这是一个很长的句子，里面有很多字。".repeat(20);
+    let chunks = check(&text, Lang::Cmn, 100);
+    assert!(chunks.iter().all(|c| c.ends_with("字。")));
+
+    // Hindi sentences end in "।"
+    let text = "यह एक लंबा वाक्य है जिसमें बहुत सारे शब्द हैं। ".repeat(10);
+    let chunks = check(text.trim_end(), Lang::Hin, 150);
+    assert!(chunks.iter().all(|c| c.ends_with('।')));
+
+    // Arabic clauses are separated by "،"
+    let text = "هذه جملة طويلة جدا، ".repeat(20);
+    check(text.trim_end(), Lang::Ara, 100);
+
+    // Text with no punctuation at all is broken at whitespace
+    let text = "word ".repeat(100);
+    let chunks = check(text.trim_end(), Lang::Eng, 64);
+    assert!(chunks
+        .iter()
+        .all(|c| c.starts_with("word") && c.trim_end().ends_with("word")));
+
+    // Sizes can be measured other than in bytes. Here, every "&" counts for five
+    let text = "Smith & Jones, ".repeat(10);
+    let size = |t: &str| t.len() + 4 * t.matches('&').count();
+    let chunks = break_text_sized(&text, Lang::Eng, 40, &size).unwrap();
+    assert_eq!(chunks.concat(), text);
+    assert_eq!(chunks.len(), 5);
+    assert!(chunks
+        .iter()
+        .all(|c| size(c) <= 40 && c.trim_end().ends_with(',')));
+}
@@ -0,0 +1,614 @@
+//! Renders article text into SSML, so that the structure of the article (headings, paragraphs,
+//! list items, block quotes) is audible. The structure is marked the same way as in Markdown:
+//! headings start with `# `, list items start with `- `, block quotes start with `> `, and every
+//! other line is a paragraph. A line can be made a paragraph, whatever it starts with, by
+//! starting it with a backslash. Inline `*emphasis*` and `**strong emphasis**` are also
+//! recognized. This is the format trafilatura outputs when `--formatting` is set.
+//!
+//! Every sentence is preceded by a `<mark>` whose name is the index of the sentence. Engines that
+//! support timepointing tell us when each mark is reached. That's how we align text to audio.
+//!
+//! The pronunciation lexicon can also leave hints in the text, saying how a word should be read.
+//! These become `<sub>` and `<phoneme>` elements. A hint is delimited by characters from Unicode's
+//! private use area, which never appear in article text, since the lexicon strips them first.
+
+use crate::{lang::full_stop, tts::break_text_sized};
+
+use anyhow::{bail, Error as AnyError};
+use whatlang::Lang;
+
+/// The pause after a heading
+const HEADING_BREAK: &str = r#"<break time="700ms"/>"#;
+
+/// Every SSML document is wrapped in these
+const SPEAK_OPEN: &str = "<speak>";
+const SPEAK_CLOSE: &str = "</speak>";
+
+/// Starts a pronunciation hint. A hint is `HINT_OPEN written HINT_SEP kind (HINT_SEP arg)*
+/// HINT_CLOSE`, where `written` is the text as it appears in the article
+pub(crate) const HINT_OPEN: char = '\u{E000}';
+pub(crate) const HINT_SEP: char = '\u{E001}';
+pub(crate) const HINT_CLOSE: char = '\u{E002}';
+
+/// A structural element of an article
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum Block<'a> {
+    Heading(&'a str),
+    Paragraph(&'a str),
+    ListItem(&'a str),
+    Quote(&'a str),
+}
+
+/// Splits article text into its structural elements. Blank lines are skipped.
+pub(crate) fn parse_blocks(text: &str) -> Vec<Block<'_>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(parse_block)
+        .collect()
+}
+
+/// Parses a single trimmed, nonempty line. A heading is one or more `#` followed by a space. A line
+/// starting with a backslash is a paragraph, whatever follows it.
+fn parse_block(line: &str) -> Block<'_> {
+    let heading = line.trim_start_matches('#');
+    if heading.len() < line.len() && heading.starts_with(' ') {
+        Block::Heading(heading.trim_start())
+    } else if let Some(item) = line
+        .strip_prefix("- ")
+        .or_else(|| line.strip_prefix("* "))
+        .or_else(|| line.strip_prefix("• "))
+    {
+        Block::ListItem(item.trim_start())
+    } else if let Some(quote) = line.strip_prefix("> ") {
+        Block::Quote(quote.trim_start())
+    } else if let Some(escaped) = line.strip_prefix('\\') {
+        Block::Paragraph(escaped)
+    } else {
+        Block::Paragraph(line)
+    }
+}
+
+/// Escapes the line, so that it's read as a paragraph even if it looks like another block
+pub(crate) fn escape_block(line: &str) -> String {
+    let trimmed = line.trim_start();
+    match parse_block(trimmed) {
+        Block::Paragraph(p) if p == trimmed => line.to_string(),
+        _ => format!("\\{trimmed}"),
+    }
+}
+
+/// Escapes every line of the text, so that it's all read as paragraphs. This is for text that
+/// isn't in the format above, like the raw text of a submitted article.
+pub(crate) fn escape_blocks(text: &str) -> String {
+    text.lines()
+        .map(escape_block)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// An article rendered as SSML
+pub(crate) struct RenderedSsml {
+    /// The SSML documents, in order
+    pub(crate) chunks: Vec<String>,
+    /// The plain text of every sentence, in order. The mark named `i` precedes `sentences[i]`.
+    pub(crate) sentences: Vec<String>,
+}
+
+/// Splits text into sentences. A sentence ends at a `.`, `!`, or `?` followed by whitespace.
+/// Pronunciation hints are never split.
+fn split_sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut in_hint = false;
+
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        match c {
+            HINT_OPEN => in_hint = true,
+            HINT_CLOSE => in_hint = false,
+            _ if in_hint => continue,
+            _ => (),
+        }
+        if let ('.' | '!' | '?', Some(&(next_idx, next_c))) = (c, chars.peek()) {
+            if next_c.is_whitespace() {
+                sentences.push(text[start..next_idx].trim());
+                start = next_idx;
+            }
+        }
+    }
+    sentences.push(text[start..].trim());
+
+    sentences.retain(|s| !s.is_empty());
+    sentences
+}
+
+/// Escapes the characters that can't appear in SSML text. Quotes only need escaping inside
+/// attributes, and we never put text there, so we leave them alone.
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Returns the number of characters in an SSML document that Google bills for. That's every
+/// character, markup included, except for `<mark>` tags.
+pub(crate) fn billed_len(ssml: &str) -> usize {
+    // Marks are all ASCII, so their lengths in bytes are their lengths in characters
+    let mark_len: usize = ssml
+        .split("<mark ")
+        .skip(1)
+        .filter_map(|rest| rest.find("/>"))
+        .map(|end| "<mark ".len() + end + "/>".len())
+        .sum();
+    ssml.chars().count() - mark_len
+}
+
+/// Escapes text so that it can go in a double-quoted attribute
+fn escape_attr(text: &str) -> String {
+    escape(text).replace('"', "&quot;")
+}
+
+/// Makes a hint saying that `written` is read as `alias`
+pub(crate) fn sub_hint(written: &str, alias: &str) -> String {
+    format!("{HINT_OPEN}{written}{HINT_SEP}sub{HINT_SEP}{alias}{HINT_CLOSE}")
+}
+
+/// Makes a hint saying that `written` is pronounced as `ph`, in the given phonetic alphabet
+pub(crate) fn phoneme_hint(written: &str, alphabet: &str, ph: &str) -> String {
+    format!("{HINT_OPEN}{written}{HINT_SEP}phoneme{HINT_SEP}{alphabet}{HINT_SEP}{ph}{HINT_CLOSE}")
+}
+
+/// A piece of text that is or isn't a pronunciation hint
+enum HintSpan<'a> {
+    Text(&'a str),
+    /// The written text, the kind of hint, and its arguments
+    Hint(&'a str, &'a str, Vec<&'a str>),
+}
+
+/// Splits text into plain text and pronunciation hints. A malformed hint is treated as text.
+fn split_hints(mut text: &str) -> Vec<HintSpan<'_>> {
+    let mut spans = Vec::new();
+
+    while let Some(start) = text.find(HINT_OPEN) {
+        let after = &text[start + HINT_OPEN.len_utf8()..];
+        let Some(end) = after.find(HINT_CLOSE) else {
+            break;
+        };
+        let mut parts = after[..end].split(HINT_SEP);
+        let (written, kind) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        spans.push(HintSpan::Text(&text[..start]));
+        spans.push(HintSpan::Hint(written, kind, parts.collect()));
+        text = &after[end + HINT_CLOSE.len_utf8()..];
+    }
+    spans.push(HintSpan::Text(text));
+
+    spans
+}
+
+/// Applies `f` to the parts of the text that aren't pronunciation hints, and leaves the hints as
+/// they are
+pub(crate) fn map_unhinted(text: &str, mut f: impl FnMut(&str) -> String) -> String {
+    split_hints(text)
+        .into_iter()
+        .map(|span| match span {
+            HintSpan::Text(t) => f(t),
+            HintSpan::Hint(written, kind, args) => {
+                let args = args
+                    .iter()
+                    .map(|a| format!("{HINT_SEP}{a}"))
+                    .collect::<String>();
+                format!("{HINT_OPEN}{written}{HINT_SEP}{kind}{args}{HINT_CLOSE}")
+            }
+        })
+        .collect()
+}
+
+/// Replaces the pronunciation hints with text. If `spoken` is set, a `<sub>` hint is replaced with
+/// its alias, since that's what should be said. Otherwise, every hint is replaced with its
+/// written text.
+fn resolve_hints(text: &str, spoken: bool) -> String {
+    split_hints(text)
+        .into_iter()
+        .map(|span| match span {
+            HintSpan::Text(t) => t,
+            HintSpan::Hint(_, "sub", args) if spoken && args.len() == 1 => args[0],
+            HintSpan::Hint(written, _, _) => written,
+        })
+        .collect()
+}
+
+/// Returns the text as it's written in the article, without emphasis markers or pronunciation hints
+pub(crate) fn written_text(text: &str) -> String {
+    resolve_hints(&strip_emphasis(text), false)
+}
+
+/// Escapes the text and converts its pronunciation hints to SSML
+fn render_hints(text: &str) -> String {
+    split_hints(text)
+        .into_iter()
+        .map(|span| match span {
+            HintSpan::Text(t) => escape(t),
+            HintSpan::Hint(written, "sub", args) if args.len() == 1 => format!(
+                r#"<sub alias="{}">{}</sub>"#,
+                escape_attr(args[0]),
+                escape(written)
+            ),
+            HintSpan::Hint(written, "phoneme", args) if args.len() == 2 => format!(
+                r#"<phoneme alphabet="{}" ph="{}">{}</phoneme>"#,
+                escape_attr(args[0]),
+                escape_attr(args[1]),
+                escape(written)
+            ),
+            HintSpan::Hint(written, _, _) => escape(written),
+        })
+        .collect()
+}
+
+/// Splits text into spans that are and aren't emphasized, i.e., surrounded by `*` or `**`. An
+/// asterisk followed by a space, or without a partner, is not emphasis.
+fn split_emphasis(mut text: &str) -> Vec<(&str, bool)> {
+    let mut spans = Vec::new();
+
+    while let Some(start) = text.find('*') {
+        let marker = if text[start..].starts_with("**") {
+            "**"
+        } else {
+            "*"
+        };
+        let after = &text[start + marker.len()..];
+
+        match after.find(marker) {
+            Some(end) if end > 0 && !after.starts_with(' ') => {
+                spans.push((&text[..start], false));
+                spans.push((&after[..end], true));
+                text = &after[end + marker.len()..];
+            }
+            _ => {
+                // Not emphasis. Keep the asterisks as they are
+                spans.push((&text[..start + marker.len()], false));
+                text = after;
+            }
+        }
+    }
+    spans.push((text, false));
+
+    spans.retain(|(s, _)| !s.is_empty());
+    spans
+}
+
+/// Removes the emphasis markers from the text
+fn strip_emphasis(text: &str) -> String {
+    split_emphasis(text).into_iter().map(|(s, _)| s).collect()
+}
+
+/// Escapes the text and converts its emphasis markers and pronunciation hints to SSML
+fn render_inline(text: &str) -> String {
+    split_emphasis(text)
+        .into_iter()
+        .map(|(s, emphasized)| {
+            if emphasized {
+                format!("<emphasis>{}</emphasis>", render_hints(s))
+            } else {
+                render_hints(s)
+            }
+        })
+        .collect()
+}
+
+/// Renders the blocks as plain text, one block per line. This is for engines that don't speak
+/// SSML. Headings get a full stop, in the given language, if they don't already end in
+/// punctuation, so there's at least some pause after them. Of the pronunciation hints, only
+/// aliases survive.
+pub(crate) fn render_plain(blocks: &[Block], lang: Lang) -> String {
+    let stop = full_stop(lang);
+    blocks
+        .iter()
+        .map(|block| match block {
+            Block::Heading(h) => {
+                let h = resolve_hints(&strip_emphasis(h), true);
+                if h.ends_with(['.', '!', '?', ':', stop]) {
+                    h
+                } else {
+                    format!("{h}{stop}")
+                }
+            }
+            Block::Paragraph(t) | Block::ListItem(t) | Block::Quote(t) => {
+                resolve_hints(&strip_emphasis(t), true)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Renders the blocks as SSML documents of at most `max_size` bytes each. Chunks are only ever
+/// broken between elements, so every chunk is a valid SSML document. If a single element is too
+/// big, its text is broken up, at the places text in `lang` can be broken, and each piece gets its
+/// own element.
+pub(crate) fn render_ssml_chunks(
+    blocks: &[Block],
+    lang: Lang,
+    max_size: usize,
+) -> Result<RenderedSsml, AnyError> {
+    // The space we have for elements in each document
+    let budget = match max_size.checked_sub(SPEAK_OPEN.len() + SPEAK_CLOSE.len()) {
+        Some(b) if b > 0 => b,
+        _ => bail!("SSML chunk size {max_size} is too small"),
+    };
+
+    // Render every block to a sequence of elements that are each under the budget
+    let mut elems = Vec::new();
+    let mut sentences = Vec::new();
+    for block in blocks {
+        let (open, text, close, suffix) = match block {
+            Block::Heading(h) => (
+                r#"<p><emphasis level="strong">"#,
+                h,
+                "</emphasis></p>",
+                HEADING_BREAK,
+            ),
+            Block::Paragraph(t) | Block::ListItem(t) | Block::Quote(t) => ("<p>", t, "</p>", ""),
+        };
+        let overhead = open.len() + close.len() + suffix.len();
+
+        // Record the block's sentences. Every sentence is preceded by its mark
+        let first_mark = sentences.len();
+        let block_sentences = split_sentences(text);
+        sentences.extend(block_sentences.iter().map(|s| written_text(s)));
+        let mark = |i: usize| format!(r#"<mark name="{}"/>"#, first_mark + i);
+
+        let inner = block_sentences
+            .iter()
+            .enumerate()
+            .map(|(i, s)| format!("{}{}", mark(i), render_inline(s)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if overhead + inner.len() <= budget {
+            elems.push(format!("{open}{inner}{close}{suffix}"));
+        } else {
+            // The element is too big. Break up the text of each sentence, and pack the pieces
+            // back into elements that fit. The emphasis markup and pronunciation hints go away
+            // here, since we can't break inside an element. The raw text is broken, measuring
+            // pieces by their escaped size, and each piece is escaped afterwards, so that no
+            // escape sequence is cut. Marks are added after breaking, since they have whitespace
+            // in them.
+            let piece_budget = match budget.checked_sub(overhead) {
+                Some(b) if b > 0 => b,
+                _ => bail!("SSML chunk size {max_size} is too small"),
+            };
+            let mut pieces: Vec<String> = Vec::new();
+            for (i, s) in block_sentences.iter().enumerate() {
+                let mut mark = Some(mark(i));
+                let text = resolve_hints(&strip_emphasis(s), true);
+                let text_budget = match piece_budget.checked_sub(mark.as_ref().unwrap().len()) {
+                    Some(b) if b > 0 => b,
+                    _ => bail!("SSML chunk size {max_size} is too small"),
+                };
+                let escaped_len = |t: &str| escape(t).len();
+                for t in break_text_sized(&text, lang, text_budget, &escaped_len)? {
+                    let t = escape(t.trim());
+                    if t.is_empty() {
+                        continue;
+                    }
+                    let t = format!("{}{t}", mark.take().unwrap_or_default());
+                    match pieces.last_mut() {
+                        Some(last) if last.len() + 1 + t.len() <= piece_budget => {
+                            last.push(' ');
+                            last.push_str(&t);
+                        }
+                        _ => pieces.push(t),
+                    }
+                }
+            }
+            let num_pieces = pieces.len();
+            for (i, piece) in pieces.into_iter().enumerate() {
+                let suffix = if i + 1 == num_pieces { suffix } else { "" };
+                elems.push(format!("{open}{piece}{close}{suffix}"));
+            }
+        }
+    }
+
+    // Now greedily pack the elements into documents
+    let mut chunks = Vec::new();
+    let mut cur_chunk = String::new();
+    for elem in elems {
+        if cur_chunk.len() + elem.len() > budget {
+            chunks.push(format!("{SPEAK_OPEN}{cur_chunk}{SPEAK_CLOSE}"));
+            cur_chunk.clear();
+        }
+        cur_chunk.push_str(&elem);
+    }
+    if !cur_chunk.is_empty() {
+        chunks.push(format!("{SPEAK_OPEN}{cur_chunk}{SPEAK_CLOSE}"));
+    }
+
+    Ok(RenderedSsml { chunks, sentences })
+}
+
+#[test]
+fn ssml_rendering() {
+    let text = "\
+        # Sea & Sky\n\
+        \n\
+        The *first* paragraph. It has <angle brackets>.\n\
+        - An item\n\
+        * Another **important** item\n\
+        A 5 * 3 multiplication is not emphasis\
+    ";
+    let blocks = parse_blocks(text);
+    assert_eq!(
+        blocks,
+        vec![
+            Block::Heading("Sea & Sky"),
+            Block::Paragraph("The *first* paragraph. It has <angle brackets>."),
+            Block::ListItem("An item"),
+            Block::ListItem("Another **important** item"),
+            Block::Paragraph("A 5 * 3 multiplication is not emphasis"),
+        ]
+    );
+    assert_eq!(
+        parse_blocks("> A block quote\n>Not one\n#1 priority\n## Heading"),
+        vec![
+            Block::Quote("A block quote"),
+            Block::Paragraph(">Not one"),
+            Block::Paragraph("#1 priority"),
+            Block::Heading("Heading"),
+        ]
+    );
+    // Escaped lines are paragraphs, and read as they were before escaping
+    let raw = "# Not a heading\n> Not a quote\n\\ A backslash\nA paragraph";
+    let escaped = escape_blocks(raw);
+    assert_eq!(
+        parse_blocks(&escaped),
+        vec![
+            Block::Paragraph("# Not a heading"),
+            Block::Paragraph("> Not a quote"),
+            Block::Paragraph("\\ A backslash"),
+            Block::Paragraph("A paragraph"),
+        ]
+    );
+
+    // Everything fits in one chunk. Every sentence gets a mark
+    let rendered = render_ssml_chunks(&blocks, Lang::Eng, 5000).unwrap();
+    assert_eq!(
+        rendered.chunks,
+        vec![concat!(
+            r#"<speak><p><emphasis level="strong"><mark name="0"/>Sea &amp; Sky</emphasis></p>"#,
+            r#"<break time="700ms"/><p><mark name="1"/>The <emphasis>first</emphasis> paragraph. "#,
+            r#"<mark name="2"/>It has &lt;angle brackets&gt;.</p>"#,
+            r#"<p><mark name="3"/>An item</p>"#,
+            r#"<p><mark name="4"/>Another <emphasis>important</emphasis> item</p>"#,
+            r#"<p><mark name="5"/>A 5 * 3 multiplication is not emphasis</p></speak>"#,
+        )]
+    );
+    assert_eq!(
+        rendered.sentences,
+        vec![
+            "Sea & Sky",
+            "The first paragraph.",
+            "It has <angle brackets>.",
+            "An item",
+            "Another important item",
+            "A 5 * 3 multiplication is not emphasis",
+        ]
+    );
+    // Every character is billed, except those of the marks
+    let marks_len: usize = (0..rendered.sentences.len())
+        .map(|i| format!(r#"<mark name="{i}"/>"#).len())
+        .sum();
+    assert_eq!(
+        billed_len(&rendered.chunks[0]),
+        rendered.chunks[0].len() - marks_len
+    );
+
+    // The plain rendering has no markup at all
+    assert_eq!(
+        render_plain(&blocks, Lang::Eng),
+        "Sea & Sky.\n\
+        The first paragraph. It has <angle brackets>.\n\
+        An item\n\
+        Another important item\n\
+        A 5 * 3 multiplication is not emphasis"
+    );
+
+    // Now use small chunks, and a paragraph that's too big for a single chunk. Every chunk must
+    // be under the limit and consist of whole elements
+    let long_paragraph = "The *first* sentence. The second sentence, which is long. ".repeat(4);
+    let blocks = parse_blocks(&long_paragraph)
+        .into_iter()
+        .chain(blocks)
+        .collect::<Vec<_>>();
+    let max_size = 150;
+    let rendered = render_ssml_chunks(&blocks, Lang::Eng, max_size).unwrap();
+    assert!(rendered.chunks.len() > 3);
+    for chunk in &rendered.chunks {
+        assert!(chunk.len() <= max_size);
+        assert!(chunk.starts_with("<speak><p>"));
+        assert!(chunk.ends_with("</p></speak>") || chunk.ends_with("/></speak>"));
+        assert_eq!(chunk.matches("<p>").count(), chunk.matches("</p>").count());
+        assert_eq!(
+            chunk.matches("<emphasis").count(),
+            chunk.matches("</emphasis>").count()
+        );
+    }
+
+    // Every sentence's mark appears exactly once, in order
+    let all_chunks = rendered.chunks.concat();
+    let mark_positions = (0..rendered.sentences.len())
+        .map(|i| {
+            let mark = format!(r#"<mark name="{i}"/>"#);
+            assert_eq!(all_chunks.matches(&mark).count(), 1);
+            all_chunks.find(&mark).unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert!(mark_positions.windows(2).all(|w| w[0] < w[1]));
+
+    // A long paragraph in a language without spaces is broken at its own punctuation
+    let long_paragraph = "这是一个很长的句子，里面有很多字。".repeat(10);
+    let blocks = parse_blocks(&long_paragraph);
+    let rendered = render_ssml_chunks(&blocks, Lang::Cmn, max_size).unwrap();
+    assert!(rendered.chunks.len() > 3);
+    assert!(rendered.chunks.iter().all(|c| c.len() <= max_size));
+    assert_eq!(rendered.chunks.concat().matches("<mark ").count(), 1);
+
+    // A long paragraph with characters that need escaping is broken before it's escaped, so no
+    // escape is cut, and no punctuation is lost. Every chunk is well-formed
+    let long_paragraph = "Smith & Jones <Partners>, ".repeat(30);
+    let rendered = render_ssml_chunks(&parse_blocks(&long_paragraph), Lang::Eng, 200).unwrap();
+    assert!(rendered.chunks.len() > 3);
+    let mut text = String::new();
+    for chunk in &rendered.chunks {
+        assert!(chunk.len() <= 200);
+        let mut reader = quick_xml::Reader::from_str(chunk);
+        loop {
+            match reader.read_event().unwrap() {
+                quick_xml::events::Event::Text(t) => text.push_str(&t.unescape().unwrap()),
+                quick_xml::events::Event::Eof => break,
+                _ => (),
+            }
+            text.push(' ');
+        }
+    }
+    assert_eq!(
+        text.split_whitespace().collect::<Vec<_>>(),
+        long_paragraph.split_whitespace().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn pronunciation_hints() {
+    let text = format!(
+        "Use a {} daily, {} works.\n# The \"{}\" step",
+        sub_hint("e.g.", "for example"),
+        phoneme_hint("Nguyen", "ipa", "wɪn"),
+        sub_hint("SQL", "sequel"),
+    );
+    let blocks = parse_blocks(&text);
+
+    // Hints become SSML elements. The period inside the hint doesn't end the sentence
+    let rendered = render_ssml_chunks(&blocks, Lang::Eng, 5000).unwrap();
+    assert_eq!(
+        rendered.chunks,
+        vec![concat!(
+            r#"<speak><p><mark name="0"/>Use a <sub alias="for example">e.g.</sub> daily, "#,
+            r#"<phoneme alphabet="ipa" ph="wɪn">Nguyen</phoneme> works.</p>"#,
+            r#"<p><emphasis level="strong"><mark name="1"/>The "<sub alias="sequel">SQL</sub>" "#,
+            r#"step</emphasis></p><break time="700ms"/></speak>"#,
+        )]
+    );
+    // The sentences are shown as written
+    assert_eq!(
+        rendered.sentences,
+        vec!["Use a e.g. daily, Nguyen works.", "The \"SQL\" step"]
+    );
+
+    // Plain text gets the aliases
+    assert_eq!(
+        render_plain(&blocks, Lang::Eng),
+        "Use a for example daily, Nguyen works.\nThe \"sequel\" step."
+    );
+
+    // Mapping the text around the hints leaves the hints alone
+    let mapped = map_unhinted(&text, |t| t.to_uppercase());
+    assert!(mapped.starts_with(&format!("USE A {}", sub_hint("e.g.", "for example"))));
+}
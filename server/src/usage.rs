@@ -0,0 +1,189 @@
+//! Keeps track of how much text the server has sent to the TTS engine, so we know roughly what
+//! we're being billed. Usage is counted in billed characters, which is every character of the
+//! request, SSML markup included, except `<mark>` tags. It's counted per voice tier per day, and
+//! saved to disk after every synthesized chunk, so it survives restarts.
+
+use common::{UsageReport, UsageTotals, VoiceTier};
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Error as AnyError};
+use axum::{extract::Extension, routing::get, Json, Router};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+/// The price of each voice tier, in US dollars per million characters
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TierPrices {
+    pub(crate) standard: f64,
+    pub(crate) wavenet: f64,
+    pub(crate) neural2: f64,
+}
+
+impl TierPrices {
+    /// Returns the price of the given number of characters in the given tier
+    fn cost(&self, tier: VoiceTier, num_chars: u64) -> f64 {
+        let per_million = match tier {
+            VoiceTier::Standard => self.standard,
+            VoiceTier::Wavenet => self.wavenet,
+            VoiceTier::Neural2 => self.neural2,
+            VoiceTier::Local => 0.0,
+        };
+        per_million * num_chars as f64 / 1_000_000.0
+    }
+}
+
+/// The number of characters synthesized in each voice tier
+type TierCounts = BTreeMap<VoiceTier, u64>;
+
+/// The contents of the usage file
+#[derive(Default, Serialize, Deserialize)]
+struct Ledger {
+    /// Maps days, of the form YYYY-MM-DD, to what was synthesized that day
+    days: BTreeMap<String, TierCounts>,
+}
+
+/// The server's TTS usage, backed by a JSON file
+#[derive(Clone)]
+pub(crate) struct UsageLog {
+    path: Arc<PathBuf>,
+    ledger: Arc<Mutex<Ledger>>,
+}
+
+impl UsageLog {
+    /// Opens the usage log at the given path. If the file doesn't exist, the log starts out empty
+    pub(crate) fn open(path: impl AsRef<Path>) -> Result<Self, AnyError> {
+        let path = path.as_ref();
+        let ledger = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| anyhow!("Could not parse usage file {:?}: {e}", path))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ledger::default(),
+            Err(e) => Err(anyhow!("Could not read usage file {:?}: {e}", path))?,
+        };
+
+        Ok(UsageLog {
+            path: Arc::new(path.to_path_buf()),
+            ledger: Arc::new(Mutex::new(ledger)),
+        })
+    }
+
+    /// Records that the given number of characters were synthesized today in the given tier
+    pub(crate) fn record(&self, tier: VoiceTier, num_chars: usize) {
+        let today = Utc::now().format("%Y-%m-%d").to_string();
+        self.record_on(&today, tier, num_chars);
+    }
+
+    /// Records that the given number of characters were synthesized on the given day in the given
+    /// tier. Failing to save the log is not an error, since the synthesis already happened
+    fn record_on(&self, day: &str, tier: VoiceTier, num_chars: usize) {
+        let mut ledger = self.ledger.lock().unwrap();
+        *ledger
+            .days
+            .entry(day.to_string())
+            .or_default()
+            .entry(tier)
+            .or_default() += num_chars as u64;
+
+        if let Err(e) = self.save(&ledger) {
+            tracing::error!("Error saving usage file: {e}");
+        }
+    }
+
+    /// Writes the ledger to the usage file. It's written to a temp file first, so that a crash
+    /// can't leave a partial file behind
+    fn save(&self, ledger: &Ledger) -> Result<(), AnyError> {
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(ledger)?)?;
+        fs::rename(&tmp_path, self.path.as_ref())?;
+        Ok(())
+    }
+
+    /// Returns the totals for every day and month, priced with the given prices
+    pub(crate) fn report(&self, prices: &TierPrices) -> UsageReport {
+        let ledger = self.ledger.lock().unwrap();
+
+        // Months are the sums of their days. A day's first 7 characters are its month
+        let mut months: BTreeMap<String, TierCounts> = BTreeMap::new();
+        for (day, counts) in &ledger.days {
+            let month_counts = months.entry(day[..7].to_string()).or_default();
+            for (&tier, &n) in counts {
+                *month_counts.entry(tier).or_default() += n;
+            }
+        }
+
+        let totals = |counts: &TierCounts| UsageTotals {
+            billed_chars: counts.clone(),
+            estimated_cost_usd: counts.iter().map(|(&t, &n)| prices.cost(t, n)).sum(),
+        };
+        UsageReport {
+            days: ledger
+                .days
+                .iter()
+                .map(|(day, counts)| (day.clone(), totals(counts)))
+                .collect(),
+            months: months
+                .iter()
+                .map(|(month, counts)| (month.clone(), totals(counts)))
+                .collect(),
+        }
+    }
+}
+
+// Sets the /api/usage route
+pub(crate) fn setup(router: Router, usage_log: UsageLog, prices: TierPrices) -> Router {
+    router.nest(
+        "/api",
+        Router::new()
+            .route("/usage", get(usage))
+            .layer(Extension(usage_log))
+            .layer(Extension(prices)),
+    )
+}
+
+/// Returns the TTS usage per day and per month, with estimated costs
+async fn usage(
+    Extension(usage_log): Extension<UsageLog>,
+    Extension(prices): Extension<TierPrices>,
+) -> Json<UsageReport> {
+    Json(usage_log.report(&prices))
+}
+
+#[test]
+fn usage_accounting() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("usage.json");
+    let prices = TierPrices {
+        standard: 4.0,
+        wavenet: 16.0,
+        neural2: 16.0,
+    };
+
+    // Record usage over two days in one month, and one day in the next
+    let log = UsageLog::open(&path).unwrap();
+    log.record_on("2026-09-30", VoiceTier::Wavenet, 500_000);
+    log.record_on("2026-09-30", VoiceTier::Standard, 250_000);
+    log.record_on("2026-09-30", VoiceTier::Wavenet, 500_000);
+    log.record_on("2026-09-01", VoiceTier::Local, 1_000_000);
+    log.record_on("2026-10-01", VoiceTier::Neural2, 100_000);
+
+    // The usage survives reopening the log
+    let report = UsageLog::open(&path).unwrap().report(&prices);
+    assert_eq!(report.days.len(), 3);
+    let day = &report.days["2026-09-30"];
+    assert_eq!(day.billed_chars[&VoiceTier::Wavenet], 1_000_000);
+    assert_eq!(day.billed_chars[&VoiceTier::Standard], 250_000);
+    assert!((day.estimated_cost_usd - 17.0).abs() < 1e-9);
+
+    // Local voices are free. Months sum up their days
+    assert_eq!(report.months.len(), 2);
+    let september = &report.months["2026-09"];
+    assert_eq!(september.billed_chars[&VoiceTier::Local], 1_000_000);
+    assert!((september.estimated_cost_usd - 17.0).abs() < 1e-9);
+    let october = &report.months["2026-10"];
+    assert!((october.estimated_cost_usd - 1.6).abs() < 1e-9);
+}
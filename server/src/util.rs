@@ -1,7 +1,15 @@
-use common::{ArticleMetadata, ArticleTextSubmission};
+use crate::{
+    mp3, opus,
+    tts::{AudioFormat, TtsRequest},
+};
+use common::{
+    ArticleAlignment, ArticleMetadata, ArticleSections, ArticleSeries, ArticleTextSubmission,
+    AudioCodec,
+};
 
 use std::{
-    fs::DirEntry,
+    fs::{self, DirEntry},
+    io::Cursor,
     path::Path,
     time::Duration,
     time::{SystemTime, UNIX_EPOCH},
@@ -11,12 +19,12 @@ use anyhow::{bail, Error as AnyError};
 use blake2::{Blake2s256, Digest};
 use byteorder::{BigEndian, ByteOrder};
 use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, NaiveTime, Timelike, Utc};
-use id3::{Tag, TagLike, Version};
+use id3::{frame::ExtendedText, Tag, TagLike, Version};
 use symphonia_bundle_mp3::{MpaDecoder, MpaReader};
 use symphonia_core::{
     codecs::{CodecParameters, Decoder, DecoderOptions, CODEC_TYPE_MP3},
     formats::{FormatOptions, FormatReader},
-    io::{MediaSourceStream, MediaSourceStreamOptions, ReadOnlySource},
+    io::{MediaSource, MediaSourceStream, MediaSourceStreamOptions, ReadOnlySource},
 };
 
 /// Filenames in `audio_blobs` are of the form `TITLE-HASH.mp3`. This is maximum number of bytes
@@ -36,6 +44,9 @@ const SANITIZATION_OPTIONS: sanitize_filename::Options<'static> = sanitize_filen
     replacement: "_",
 };
 
+/// The name of the tag that holds the language detector's confidence in the article's language
+const LANG_CONFIDENCE_KEY: &str = "LANGUAGE_CONFIDENCE";
+
 /// Used in `truncate_to_bytes` to specify the byte encoding of the string to be truncated
 pub(crate) enum StrEncoding {
     Utf8,
@@ -71,7 +82,9 @@ pub(crate) fn truncate_to_bytes(string: &str, max_len: usize, encoding: StrEncod
 }
 
 /// Computes the zbase32 encoded hash of the given article. The output length is ARTICLE_HASH_LEN.
-fn hash_article(ArticleTextSubmission { title, body }: &ArticleTextSubmission) -> String {
+/// The choice of voice is not part of the hash, so the same article can't be added twice with
+/// different narrators.
+fn hash_article(ArticleTextSubmission { title, body, .. }: &ArticleTextSubmission) -> String {
     // We will compute H(title_len || title || body)
     let mut h = Blake2s256::default();
 
@@ -89,6 +102,40 @@ fn hash_article(ArticleTextSubmission { title, body }: &ArticleTextSubmission) -
     zbase32::encode(&digest, ARTICLE_HASH_BITLEN)
 }
 
+/// Computes the zbase32 encoded hash of the given TTS request, and the format its audio is
+/// synthesized in. This is the key of the request's audio in the chunk cache.
+pub(crate) fn hash_tts_request(
+    TtsRequest {
+        text,
+        ssml,
+        voice_name,
+    }: &TtsRequest,
+    format: AudioFormat,
+) -> String {
+    // We will compute H(voice_len || voice || ssml || codec || bitrate || text). The codec is a
+    // single byte, so it needs no length
+    let mut h = Blake2s256::default();
+
+    let mut len_buf = [0u8; 8];
+    BigEndian::write_u64(&mut len_buf, voice_name.len().try_into().unwrap());
+    let mut bitrate_buf = [0u8; 4];
+    BigEndian::write_u32(&mut bitrate_buf, format.bitrate_kbps);
+    let codec_byte = match format.codec {
+        AudioCodec::Mp3 => 0u8,
+        AudioCodec::OggOpus => 1u8,
+    };
+
+    h.update(len_buf);
+    h.update(voice_name);
+    h.update([*ssml as u8, codec_byte]);
+    h.update(bitrate_buf);
+    h.update(text);
+
+    // Use the whole hash. Unlike article IDs, these never have to be read by a person
+    let digest = h.finalize();
+    zbase32::encode(&digest, 256)
+}
+
 /// Derives the unique ID of this article. It's of the form SHORTTITLE-HASH, where SHORTTITLE is
 /// the sanitized, truncated title of the article, and HASH is the zbase32 encoding of the
 /// Blake2s256 hash of the article.
@@ -101,15 +148,29 @@ pub fn derive_article_id(article: &ArticleTextSubmission) -> String {
     format!("{truncated_title}-{hash}")
 }
 
+/// Saves article metadata in the article's audio file. See `save_id3_metadata` and
+/// `save_vorbis_metadata` for how it's stored for each codec.
+pub fn save_metadata(meta: &ArticleMetadata, audio_blob_dir: &str) -> Result<(), AnyError> {
+    // The filename is ID.EXT
+    let savepath =
+        Path::new(&audio_blob_dir).join(&format!("{}.{}", meta.id, meta.codec.extension()));
+
+    match meta.codec {
+        AudioCodec::Mp3 => save_id3_metadata(meta, &savepath),
+        AudioCodec::OggOpus => save_vorbis_metadata(meta, &savepath),
+    }
+}
+
 /// Saves article metadata as ID3 tags in the MP3 file:
 ///
 ///     url -> Artist
 ///     title -> Title
 ///     date fetched  -> Recording Time
-pub fn save_metadata(meta: &ArticleMetadata, audio_blob_dir: &str) -> Result<(), AnyError> {
-    // The filename is ID.mp3
-    let savepath = Path::new(&audio_blob_dir).join(&format!("{}.mp3", meta.id));
-
+///     language -> Language
+///     language confidence -> user-defined LANGUAGE_CONFIDENCE
+///     series title -> Album
+///     place in series -> Track number, out of the series length
+fn save_id3_metadata(meta: &ArticleMetadata, savepath: &Path) -> Result<(), AnyError> {
     // Set the ID3 title
     let mut tag = Tag::new();
     tag.set_title(&meta.title);
@@ -138,11 +199,67 @@ pub fn save_metadata(meta: &ArticleMetadata, audio_blob_dir: &str) -> Result<(),
         tag.set_artist(url);
     }
 
+    // Set the language, and how sure we are of it if it was detected
+    if let Some(lang) = &meta.lang {
+        tag.set_text("TLAN", lang);
+    }
+    if let Some(confidence) = meta.lang_confidence {
+        tag.add_frame(ExtendedText {
+            description: LANG_CONFIDENCE_KEY.to_string(),
+            value: confidence.to_string(),
+        });
+    }
+
+    // Set the series as the album, and the article's place in it as the track
+    if let Some(series) = &meta.series {
+        tag.set_album(&series.title);
+        tag.set_track(series.index);
+        tag.set_total_tracks(series.len);
+    }
+
     // Now write
     tag.write_to_path(savepath, Version::Id3v24)
         .map_err(Into::into)
 }
 
+/// Saves article metadata as Vorbis comments in the Ogg Opus file. This mirrors the ID3 layout:
+///
+///     url -> ARTIST
+///     title -> TITLE
+///     date fetched  -> DATE (RFC 3339)
+///     language -> LANGUAGE
+///     language confidence -> LANGUAGE_CONFIDENCE
+///     series title -> ALBUM
+///     place in series -> TRACKNUMBER, out of TRACKTOTAL
+///
+/// The duration isn't saved, since it's cheap to read from the file itself.
+fn save_vorbis_metadata(meta: &ArticleMetadata, savepath: &Path) -> Result<(), AnyError> {
+    let mut comments = vec![("TITLE", meta.title.clone())];
+    if let Some(url) = &meta.source_url {
+        comments.push(("ARTIST", url.clone()));
+    }
+    if let Some(added) = meta.datetime_added {
+        comments.push(("DATE", epoch_secs_to_datetime(added).to_rfc3339()));
+    }
+    if let Some(lang) = &meta.lang {
+        comments.push(("LANGUAGE", lang.clone()));
+    }
+    if let Some(confidence) = meta.lang_confidence {
+        comments.push((LANG_CONFIDENCE_KEY, confidence.to_string()));
+    }
+    if let Some(series) = &meta.series {
+        comments.push(("ALBUM", series.title.clone()));
+        comments.push(("TRACKNUMBER", series.index.to_string()));
+        comments.push(("TRACKTOTAL", series.len.to_string()));
+    }
+
+    // Rewrite the file. Write to a temp file first, so that nobody reads a half-written file
+    let tagged = opus::write_comments(&fs::read(savepath)?, &comments)?;
+    let tmp_savepath = savepath.with_extension(format!("{}.tmp", AudioCodec::OggOpus.extension()));
+    fs::write(&tmp_savepath, tagged)?;
+    fs::rename(&tmp_savepath, savepath).map_err(Into::into)
+}
+
 /// Converts seconds since epoch to UTC datetime
 pub(crate) fn epoch_secs_to_datetime(secs: u64) -> DateTime<Utc> {
     let date = NaiveDateTime::from_timestamp(
@@ -153,11 +270,9 @@ pub(crate) fn epoch_secs_to_datetime(secs: u64) -> DateTime<Utc> {
     DateTime::<Utc>::from_utc(date, Utc)
 }
 
-/// Gets article metadata from ID3 tags in the MP3 file:
-///
-///     url <- Artist
-///     title <- Title
-///     date fetched  <- Recording Time (or else Unix last modified time)
+/// Gets article metadata from the article's audio file. The codec is determined by the file
+/// extension. See `save_metadata` for where each field is stored. If the file has no recording
+/// time, the Unix last modified time is used.
 pub fn get_metadata(entry: &DirEntry) -> Result<ArticleMetadata, AnyError> {
     let path = entry.path();
 
@@ -176,21 +291,61 @@ pub fn get_metadata(entry: &DirEntry) -> Result<ArticleMetadata, AnyError> {
         None => bail!("filename is not valid unicode"),
     };
 
-    // Pick default metadata in case no ID3 tag exists
+    // The codec is determined by the extension
+    let codec = match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => AudioCodec::from_extension(ext),
+        None => None,
+    };
+    let codec = match codec {
+        Some(c) => c,
+        None => bail!("{:?} is not an audio file", path),
+    };
+
+    // Pick default metadata in case no tags exist
     let mut meta = ArticleMetadata {
         title: id.clone(),
         id,
         duration: None,
         source_url: None,
         datetime_added: last_modified_timestamp,
+        lang: None,
+        lang_confidence: None,
+        codec,
+        series: None,
     };
 
+    match codec {
+        AudioCodec::Mp3 => read_id3_metadata(entry, &mut meta),
+        AudioCodec::OggOpus => read_vorbis_metadata(&path, &mut meta)?,
+    }
+
+    Ok(meta)
+}
+
+/// Fills in the article metadata from the ID3 tags in the MP3 file. If the file has no duration
+/// tag, the duration is computed and saved in the file.
+fn read_id3_metadata(entry: &DirEntry, meta: &mut ArticleMetadata) {
+    let path = entry.path();
+
     // Try to get the metadata from the ID3 tags
     if let Ok(tag) = Tag::read_from_path(&path) {
         // Try to get the ID3 title, source URL (URL is in the Artist field), and duration
         meta.title = tag.title().unwrap_or(&meta.title).to_string();
         meta.source_url = tag.artist().map(str::to_string);
         meta.duration = tag.duration().map(|t| Duration::from_millis(t as u64));
+        meta.lang = tag
+            .get("TLAN")
+            .and_then(|f| f.content().text())
+            .map(str::to_string);
+        meta.lang_confidence = tag
+            .extended_texts()
+            .find(|t| t.description == LANG_CONFIDENCE_KEY)
+            .and_then(|t| t.value.parse().ok());
+        meta.series = tag.album().map(|title| ArticleSeries {
+            title: title.to_string(),
+            index: tag.track().unwrap_or(1),
+            len: tag.total_tracks().unwrap_or(1),
+        });
 
         // Extract the time recorded and convert it back to a unix timestamp. It's a pain
         let datetime_added = tag.date_recorded().and_then(|recorded| {
@@ -216,7 +371,7 @@ pub fn get_metadata(entry: &DirEntry) -> Result<ArticleMetadata, AnyError> {
         meta.duration = get_mp3_duration(&path).ok();
         println!("Saving to {:?}", entry);
         save_metadata(
-            &meta,
+            meta,
             entry
                 .path()
                 .parent()
@@ -228,8 +383,45 @@ pub fn get_metadata(entry: &DirEntry) -> Result<ArticleMetadata, AnyError> {
 
         println!("Saved new duration metadata to {:?}", entry);
     }
+}
 
-    Ok(meta)
+/// Fills in the article metadata from the Vorbis comments in the Ogg Opus file, and computes the
+/// duration
+fn read_vorbis_metadata(path: &Path, meta: &mut ArticleMetadata) -> Result<(), AnyError> {
+    let bytes = fs::read(path)?;
+    meta.duration = opus::duration(&bytes).ok();
+
+    let mut series_index = None;
+    let mut series_len = None;
+    for (key, val) in opus::read_comments(&bytes)? {
+        match key.as_str() {
+            "TITLE" => meta.title = val,
+            "ALBUM" => {
+                meta.series = Some(ArticleSeries {
+                    title: val,
+                    index: 1,
+                    len: 1,
+                })
+            }
+            "TRACKNUMBER" => series_index = val.parse().ok(),
+            "TRACKTOTAL" => series_len = val.parse().ok(),
+            "ARTIST" => meta.source_url = Some(val),
+            "LANGUAGE" => meta.lang = Some(val),
+            LANG_CONFIDENCE_KEY => meta.lang_confidence = val.parse().ok(),
+            "DATE" => {
+                if let Ok(date) = DateTime::parse_from_rfc3339(&val) {
+                    meta.datetime_added = date.timestamp().try_into().ok().or(meta.datetime_added);
+                }
+            }
+            _ => (),
+        }
+    }
+    if let Some(series) = &mut meta.series {
+        series.index = series_index.unwrap_or(series.index);
+        series.len = series_len.unwrap_or(series.len);
+    }
+
+    Ok(())
 }
 
 /// Returns the true duration of an MP3 file. This is somewhat expensive, so it should only be
@@ -237,9 +429,48 @@ pub fn get_metadata(entry: &DirEntry) -> Result<ArticleMetadata, AnyError> {
 pub(crate) fn get_mp3_duration(path: &std::path::PathBuf) -> Result<Duration, anyhow::Error> {
     // Make the file into an input stream
     let f = std::fs::File::open(&path)?;
-    let src = ReadOnlySource::new(f);
+    mp3_duration(Box::new(ReadOnlySource::new(f)))
+}
+
+/// Returns the true duration of the MP3 data in the given buffer
+pub(crate) fn mp3_bytes_duration(bytes: &[u8]) -> Result<Duration, anyhow::Error> {
+    mp3_duration(Box::new(Cursor::new(bytes.to_vec())))
+}
+
+/// Returns the true duration of the audio file at the given path
+pub(crate) fn get_audio_duration(
+    path: &std::path::PathBuf,
+    codec: AudioCodec,
+) -> Result<Duration, anyhow::Error> {
+    match codec {
+        AudioCodec::Mp3 => get_mp3_duration(path),
+        AudioCodec::OggOpus => opus::duration(&fs::read(path)?),
+    }
+}
+
+/// Returns the true duration of the audio data in the given buffer
+pub(crate) fn audio_bytes_duration(
+    bytes: &[u8],
+    codec: AudioCodec,
+) -> Result<Duration, anyhow::Error> {
+    match codec {
+        AudioCodec::Mp3 => mp3_bytes_duration(bytes),
+        AudioCodec::OggOpus => opus::duration(bytes),
+    }
+}
+
+/// Joins the given audio chunks into a single audio file
+pub(crate) fn concat_audio(chunks: &[&[u8]], codec: AudioCodec) -> Result<Vec<u8>, AnyError> {
+    match codec {
+        AudioCodec::Mp3 => mp3::concat(chunks),
+        AudioCodec::OggOpus => opus::concat(chunks),
+    }
+}
+
+/// Returns the true duration of the MP3 data in the given source
+fn mp3_duration(src: Box<dyn MediaSource>) -> Result<Duration, anyhow::Error> {
     let media_src = MediaSourceStream::new(
-        Box::new(src),
+        src,
         MediaSourceStreamOptions {
             buffer_len: 1048576, // 1MB
         },
@@ -268,6 +499,30 @@ pub(crate) fn get_mp3_duration(path: &std::path::PathBuf) -> Result<Duration, an
     ))
 }
 
+/// Saves the article's text-to-audio alignment as JSON, next to the article's audio. The filename
+/// is ID.alignment.json
+pub fn save_alignment(
+    id: &str,
+    alignment: &ArticleAlignment,
+    audio_blob_dir: &str,
+) -> Result<(), AnyError> {
+    let savepath = Path::new(&audio_blob_dir).join(format!("{id}.alignment.json"));
+    let json = serde_json::to_vec(alignment)?;
+    std::fs::write(savepath, json).map_err(Into::into)
+}
+
+/// Saves the start times of the article's sections as JSON, next to the article's audio. The
+/// filename is ID.sections.json
+pub fn save_sections(
+    id: &str,
+    sections: &ArticleSections,
+    audio_blob_dir: &str,
+) -> Result<(), AnyError> {
+    let savepath = Path::new(&audio_blob_dir).join(format!("{id}.sections.json"));
+    let json = serde_json::to_vec(sections)?;
+    std::fs::write(savepath, json).map_err(Into::into)
+}
+
 #[test]
 fn test_title_truncation() {
     let title = "Money Stuff: AMC’s APEs Might Stick Around";